@@ -0,0 +1,579 @@
+//! Abilities units use on top of their basic attack.
+use std::collections::HashMap;
+
+use gdnative::{Color, Line2D, Vector2};
+use legion::prelude::*;
+use serde::Deserialize;
+
+use crate::archetype::Archetypes;
+use crate::buildings::Building;
+use crate::combat::{cooldown_ready, deal_damage, start_cooldown, Cooldowns, Hitpoints, MaxHitpoints};
+use crate::economy::Stockpiles;
+use crate::effects::{add_effect, Effects, StatusEffects, Stunned};
+use crate::gameworld::{Delta, Notices, Selected, WorldNode};
+use crate::input::{Actions, Aiming, MouseButton, MousePos};
+use crate::spawner::{create_circle, insert_unit};
+use crate::supply::Supply;
+use crate::units::{within_radius, Faction, UnitPos, UnitRect};
+use crate::veterancy::Attackers;
+
+/// Actions that use the first, second and third ability of the selected units
+pub const ABILITY_ACTIONS: &[&str] = &["ability_1", "ability_2", "ability_3"];
+
+// -----------------------------------------------------------------------------
+//     - Data -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Targeting {
+    /// Only affects the unit using it
+    Caster,
+    Unit,
+    Point,
+    /// Every unit within the radius of a point
+    Area(f32),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum Outcome {
+    /// Put a status effect on everyone hit
+    Effect(String),
+    /// Hitpoints lost by everyone hit
+    Damage(u32),
+    /// Spawn a unit of the archetype at the target
+    Summon(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Ability {
+    /// Minerals it takes to use
+    #[serde(default)]
+    pub cost: u32,
+    /// Seconds before it can be used again
+    pub cooldown: f32,
+    /// Seconds between using it and the outcome
+    #[serde(default)]
+    pub cast_time: f32,
+    /// How far from the caster the target can be
+    #[serde(default)]
+    pub range: f32,
+    pub targeting: Targeting,
+    pub outcomes: Vec<Outcome>,
+    /// Hits the caster's own faction rather than hostiles
+    #[serde(default)]
+    pub friendly: bool,
+}
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// Abilities a unit can use, by name
+#[derive(Debug, Clone)]
+pub struct AbilitySlots(pub Vec<String>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AbilityTarget {
+    Caster,
+    Unit(Entity),
+    Point(Vector2),
+}
+
+/// Using an ability, the outcome happens once `remaining` runs out
+#[derive(Debug, Clone)]
+pub struct Casting {
+    pub ability: String,
+    pub target: AbilityTarget,
+    pub remaining: f32,
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Every ability, by name
+#[derive(Debug, Default, Deserialize)]
+pub struct Abilities(HashMap<String, Ability>);
+
+impl Abilities {
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Ability> {
+        self.0.get(name)
+    }
+}
+
+/// Cursor showing where the ability being aimed will land
+pub struct AimCursor(Option<(String, Line2D)>);
+
+impl AimCursor {
+    pub fn new() -> Self {
+        Self(None)
+    }
+}
+
+unsafe impl Send for AimCursor {}
+unsafe impl Sync for AimCursor {}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+/// Use abilities of the selected units. Abilities that need a target wait
+/// for a left click, right click or `ui_cancel` puts them away again.
+pub fn use_abilities() -> Box<dyn Schedulable> {
+    SystemBuilder::new("use abilities")
+        .write_resource::<Aiming>()
+        .write_resource::<Actions>()
+        .write_resource::<MouseButton>()
+        .read_resource::<MousePos>()
+        .read_resource::<Abilities>()
+        .write_resource::<Stockpiles>()
+        .write_resource::<Notices>()
+        .write_component::<Cooldowns>()
+        .read_component::<Faction>()
+        .with_query(<(Read<AbilitySlots>, Read<UnitPos>, Read<Faction>)>::query()
+            .filter(tag::<Selected>() & !component::<Casting>()))
+        .with_query(<Read<UnitRect>>::query().filter(component::<Hitpoints>()))
+        .build(|cmd, world, resources, (selected, units)| {
+            let (aiming, actions, mouse_btn, mouse_pos, abilities, stockpiles, notices) = resources;
+
+            if let Some(index) = ABILITY_ACTIONS.iter().position(|action| actions.pressed(action)) {
+                ABILITY_ACTIONS.iter().for_each(|action| actions.consume(action));
+                let name = selected
+                    .iter(world)
+                    .find_map(|(slots, _, _)| slots.0.get(index).cloned());
+
+                match name.as_ref().and_then(|name| abilities.get(name).map(|ability| (name, ability))) {
+                    Some((name, ability)) if ability.targeting == Targeting::Caster => {
+                        let casters = selected
+                            .iter_entities(world)
+                            .filter(|(_, (slots, _, _))| slots.0.contains(name))
+                            .map(|(ent, _)| ent)
+                            .collect::<Vec<_>>();
+
+                        for caster in casters {
+                            if !start_casting(cmd, world, stockpiles, caster, name, ability, AbilityTarget::Caster) {
+                                notices.push(format!("Not enough minerals for {}", name));
+                            }
+                        }
+                    }
+                    Some((name, _)) => aiming.start(name),
+                    None => {}
+                }
+            }
+
+            let aimed = aiming
+                .ability
+                .clone()
+                .and_then(|name| abilities.get(&name).map(|ability| (name, ability)));
+
+            let (name, ability) = match aimed {
+                Some(a) => a,
+                None => return,
+            };
+
+            if actions.pressed("ui_cancel") || mouse_btn.button_pressed(2) {
+                aiming.cancel();
+                actions.consume("ui_cancel");
+                mouse_btn.consume();
+                return;
+            }
+
+            if !mouse_btn.button_pressed(1) {
+                return;
+            }
+            mouse_btn.consume();
+
+            let point = mouse_pos.global();
+            let target = match ability.targeting {
+                Targeting::Unit => {
+                    let clicked = units
+                        .iter_entities(world)
+                        .find(|(_, rect)| rect.0.contains(point.to_point()))
+                        .map(|(ent, _)| ent);
+
+                    match clicked {
+                        Some(unit) => AbilityTarget::Unit(unit),
+                        None => {
+                            notices.push(format!("Pick a unit to {}", name));
+                            return;
+                        }
+                    }
+                }
+                _ => AbilityTarget::Point(point),
+            };
+
+            let casters = selected
+                .iter_entities(world)
+                .filter(|(_, (slots, _, _))| slots.0.contains(&name))
+                .map(|(ent, (_, pos, faction))| (ent, pos.0, *faction))
+                .collect::<Vec<_>>();
+
+            for (caster, pos, faction) in casters {
+                if (point - pos).length() > ability.range {
+                    notices.push(format!("Out of range for {}", name));
+                    continue;
+                }
+
+                if let AbilityTarget::Unit(unit) = target {
+                    let friendly = world.get_component::<Faction>(unit).map(|f| *f == faction).unwrap_or(false);
+                    if friendly != ability.friendly {
+                        notices.push(format!("Can't {} that unit", name));
+                        continue;
+                    }
+                }
+
+                if !start_casting(cmd, world, stockpiles, caster, &name, ability, target) {
+                    notices.push(format!("Not enough minerals for {}", name));
+                }
+            }
+
+            aiming.cancel();
+        })
+}
+
+/// Pay for the ability and start the cooldown, the outcome comes once the
+/// cast time is up. False if the caster's faction can't afford it.
+fn start_casting(
+    cmd: &mut CommandBuffer,
+    world: &SubWorld,
+    stockpiles: &mut Stockpiles,
+    caster: Entity,
+    name: &str,
+    ability: &Ability,
+    target: AbilityTarget,
+) -> bool {
+    let faction = match world.get_component::<Faction>(caster) {
+        Some(faction) => *faction,
+        None => return true,
+    };
+
+    // Still cooling down, nothing to pay for
+    if !cooldown_ready(world, caster, name) {
+        return true;
+    }
+
+    if !stockpiles.spend(faction, ability.cost) {
+        return false;
+    }
+
+    start_cooldown(cmd, world, caster, name, ability.cooldown);
+    cmd.add_component(caster, Casting { ability: name.to_string(), target, remaining: ability.cast_time });
+    true
+}
+
+/// Finish casting and apply the outcome to whoever was hit. Stunned units
+/// wait it out. A summon without the supply for it gives back the minerals
+/// and the cooldown.
+pub fn cast_abilities() -> Box<dyn Schedulable> {
+    SystemBuilder::new("cast abilities")
+        .read_resource::<Delta>()
+        .read_resource::<Abilities>()
+        .read_resource::<StatusEffects>()
+        .read_resource::<Archetypes>()
+        .write_resource::<Supply>()
+        .write_resource::<Stockpiles>()
+        .write_resource::<Notices>()
+        .write_component::<Cooldowns>()
+        .write_component::<Hitpoints>()
+        .write_component::<Effects>()
+        .write_component::<Attackers>()
+        .read_component::<MaxHitpoints>()
+        .read_component::<Building>()
+        .read_component::<UnitPos>()
+        .read_component::<Faction>()
+        .with_query(<(Write<Casting>, Read<Faction>)>::query().filter(!component::<Stunned>()))
+        .with_query(<Read<UnitPos>>::query().filter(component::<Hitpoints>()))
+        .build(|cmd, world, resources, (casting, units)| {
+            let (delta, abilities, status_effects, archetypes, supply, stockpiles, notices) = resources;
+
+            let mut finished = Vec::new();
+            for (entity, (mut cast, faction)) in casting.iter_entities_mut(world) {
+                cast.remaining -= delta.0;
+                if cast.remaining <= 0. {
+                    finished.push((entity, cast.clone(), *faction));
+                }
+            }
+
+            for (caster, cast, faction) in finished {
+                cmd.remove_component::<Casting>(caster);
+                let ability = match abilities.get(&cast.ability) {
+                    Some(a) => a,
+                    None => continue,
+                };
+
+                let point = match cast.target {
+                    AbilityTarget::Caster => world.get_component::<UnitPos>(caster).map(|pos| pos.0),
+                    AbilityTarget::Unit(unit) => world.get_component::<UnitPos>(unit).map(|pos| pos.0),
+                    AbilityTarget::Point(point) => Some(point),
+                };
+
+                let point = match point {
+                    Some(p) => p,
+                    // The target died while casting
+                    None => continue,
+                };
+
+                let hit = match (cast.target, ability.targeting) {
+                    (AbilityTarget::Caster, _) => vec![caster],
+                    (AbilityTarget::Unit(unit), _) => vec![unit],
+                    (AbilityTarget::Point(_), Targeting::Area(radius)) => {
+                        let positions = units
+                            .iter_entities(world)
+                            .map(|(ent, pos)| (ent, pos.0))
+                            .collect::<Vec<_>>();
+
+                        within_radius(&positions, point, radius)
+                            .into_iter()
+                            .map(|(ent, _)| ent)
+                            .filter(|ent| {
+                                let friendly = world.get_component::<Faction>(*ent).map(|f| *f == faction);
+                                friendly == Some(ability.friendly)
+                            })
+                            .collect()
+                    }
+                    (AbilityTarget::Point(_), _) => Vec::new(),
+                };
+
+                let mut refunded = false;
+                for outcome in &ability.outcomes {
+                    match outcome {
+                        Outcome::Effect(name) => match status_effects.get(name) {
+                            Some(status) => {
+                                hit.iter().for_each(|ent| add_effect(cmd, world, *ent, name, status, Some(caster)))
+                            }
+                            None => continue,
+                        },
+                        Outcome::Damage(damage) => {
+                            for ent in &hit {
+                                deal_damage(cmd, world, *ent, *damage, Some(caster));
+                            }
+                        }
+                        Outcome::Summon(name) => {
+                            let archetype = match archetypes.get(name) {
+                                Some(a) => a,
+                                None => continue,
+                            };
+
+                            if supply.reserve(faction, archetype.supply) {
+                                insert_unit(cmd, archetype, point, faction);
+                                continue;
+                            }
+
+                            if refunded {
+                                continue;
+                            }
+                            refunded = true;
+
+                            stockpiles.add(faction, ability.cost);
+                            if let Some(mut cooldowns) = world.get_component_mut::<Cooldowns>(caster) {
+                                cooldowns.reset(&cast.ability);
+                            }
+                            if faction == Faction::PLAYER {
+                                notices.push(format!("Not enough supply for {}", cast.ability));
+                            }
+                        }
+                    }
+                }
+            }
+        })
+}
+
+/// Draw where the ability being aimed will land
+pub fn draw_aiming() -> Box<dyn Runnable> {
+    SystemBuilder::new("draw aiming")
+        .write_resource::<WorldNode>()
+        .write_resource::<AimCursor>()
+        .read_resource::<Aiming>()
+        .read_resource::<Abilities>()
+        .read_resource::<MousePos>()
+        .build_thread_local(|_, _, (world_node, cursor, aiming, abilities, mouse_pos), _| {
+            let ability = aiming
+                .ability
+                .as_ref()
+                .and_then(|name| abilities.get(name).map(|ability| (name, ability)));
+
+            // Remove the cursor once done aiming, or when aiming something else
+            let stale = match (&cursor.0, ability) {
+                (Some((shown, _)), Some((name, _))) => shown != name,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+
+            if stale {
+                if let Some((_, mut node)) = cursor.0.take() {
+                    unsafe { node.queue_free() };
+                }
+            }
+
+            let (name, ability) = match ability {
+                Some(a) => a,
+                None => return,
+            };
+
+            if cursor.0.is_none() {
+                let radius = match ability.targeting {
+                    Targeting::Area(radius) => radius,
+                    _ => 6.,
+                };
+                let node = create_circle(radius, Color::rgba(1., 0.8, 0.2, 0.8));
+                unsafe { world_node.add_child(node.to_node()) };
+                cursor.0 = Some((name.clone(), node));
+            }
+
+            if let Some((_, node)) = cursor.0.as_mut() {
+                unsafe { node.set_global_position(mouse_pos.global()) };
+            }
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use crate::data;
+    use crate::units::UnitType;
+    use super::*;
+
+    fn abilities() -> Abilities {
+        data::parse(r#"{
+            "grenade": (
+                cost: 10,
+                cooldown: 5.0,
+                cast_time: 1.0,
+                range: 100.0,
+                targeting: Area(20.0),
+                outcomes: [Damage(3)],
+            ),
+            "barrier": (
+                cooldown: 5.0,
+                targeting: Caster,
+                outcomes: [Effect("shield")],
+            ),
+            "reinforce": (
+                cost: 10,
+                cooldown: 30.0,
+                targeting: Caster,
+                outcomes: [Summon("soldier")],
+            ),
+        }"#).unwrap()
+    }
+
+    fn resources() -> Resources {
+        let mut resources = Resources::default();
+        resources.insert(Delta(0.5));
+        resources.insert(Aiming::none());
+        resources.insert(Actions::empty());
+        resources.insert(MouseButton::Empty);
+        resources.insert(MousePos::zero());
+        resources.insert(abilities());
+        resources.insert(Archetypes::new());
+        resources.insert(Stockpiles::new(15));
+        resources.insert(Supply::new(0));
+        resources.insert(Notices::new());
+        resources.insert(data::parse::<StatusEffects>(r#"{
+            "shield": (effect: Shield(5), duration: 10.0),
+        }"#).unwrap());
+        resources
+    }
+
+    // Area abilities wait for a click, take the cast time and hit hostiles
+    // around the point
+    pub fn test_area_ability() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = resources();
+
+        let caster = world.insert((Selected,), vec![(
+            UnitPos(Vector2::zero()),
+            Faction::PLAYER,
+            AbilitySlots(vec!["grenade".into()]),
+        )])[0];
+
+        let units = world.insert((), vec![
+            (UnitPos(Vector2::new(50., 0.)), Faction(1), Hitpoints(5)),
+            (UnitPos(Vector2::new(60., 0.)), Faction::PLAYER, Hitpoints(5)),
+            (UnitPos(Vector2::new(90., 0.)), Faction(1), Hitpoints(5)),
+        ]).to_vec();
+
+        let mut sched = Schedule::builder()
+            .add_system(use_abilities())
+            .add_system(cast_abilities())
+            .flush()
+            .build();
+
+        resources.get_mut::<Actions>().map(|mut actions| actions.press("ability_1"));
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(resources.get::<Aiming>().unwrap().ability.is_some());
+
+        resources.get_mut::<MousePos>().map(|mut pos| pos.set_global(Vector2::new(55., 0.)));
+        resources.insert(MouseButton::Mouse { pressed: true, button_index: 1, shift: false });
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Casting>(caster).is_some());
+        assert_gd!(resources.get::<Stockpiles>().unwrap().amount(Faction::PLAYER) == 5);
+
+        // Not out yet
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Hitpoints>(units[0]).unwrap().0 == 5);
+        sched.execute(&mut world, &mut resources);
+
+        assert_gd!(world.get_component::<Hitpoints>(units[0]).unwrap().0 == 2);
+        assert_gd!(world.get_component::<Hitpoints>(units[1]).unwrap().0 == 5);
+        assert_gd!(world.get_component::<Hitpoints>(units[2]).unwrap().0 == 5);
+        assert_gd!(world.get_component::<Casting>(caster).is_none());
+        assert_gd!(!world.get_component::<Cooldowns>(caster).unwrap().ready("grenade"))
+    }
+
+    // Abilities on the caster go off right away, and each one cools down
+    // on its own
+    pub fn test_caster_ability() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = resources();
+
+        let caster = world.insert((Selected,), vec![(
+            UnitPos(Vector2::zero()),
+            Faction::PLAYER,
+            AbilitySlots(vec!["grenade".into(), "barrier".into()]),
+        )])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(use_abilities())
+            .flush()
+            .add_system(cast_abilities())
+            .flush()
+            .build();
+
+        resources.get_mut::<Actions>().map(|mut actions| actions.press("ability_2"));
+        sched.execute(&mut world, &mut resources);
+
+        assert_gd!(world.get_component::<Effects>(caster).unwrap().has("shield"));
+        let cooldowns = world.get_component::<Cooldowns>(caster).unwrap();
+        assert_gd!(!cooldowns.ready("barrier"));
+        assert_gd!(cooldowns.ready("grenade"))
+    }
+
+    // Summoning without the supply for it gives back what the cast cost
+    pub fn test_summon_refund() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = resources();
+
+        let caster = world.insert((Selected,), vec![(
+            UnitPos(Vector2::zero()),
+            Faction::PLAYER,
+            AbilitySlots(vec!["reinforce".into()]),
+        )])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(use_abilities())
+            .flush()
+            .add_system(cast_abilities())
+            .flush()
+            .build();
+
+        resources.get_mut::<Actions>().map(|mut actions| actions.press("ability_1"));
+        sched.execute(&mut world, &mut resources);
+
+        let mut summoned = <Read<UnitType>>::query();
+        assert_gd!(summoned.iter(&world).count() == 0);
+        assert_gd!(resources.get::<Stockpiles>().unwrap().amount(Faction::PLAYER) == 15);
+        assert_gd!(world.get_component::<Cooldowns>(caster).unwrap().ready("reinforce"));
+        assert_gd!(resources.get_mut::<Notices>().unwrap().drain().len() == 1)
+    }
+}
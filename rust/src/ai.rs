@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+
+use gdnative::Vector2;
+use legion::prelude::*;
+use serde::Deserialize;
+
+use crate::archetype::Archetypes;
+use crate::behaviour::Behaviour;
+use crate::buildings::Building;
+use crate::combat::{AttackMoving, Hitpoints, MaxHitpoints, Target};
+use crate::economy::{Harvest, Harvester, Stockpiles};
+use crate::formation::{assign_slots, Formation};
+use crate::gameworld::Delta;
+use crate::healing::Repair;
+use crate::movement::Movement;
+use crate::orders::{Holding, Order, Orders};
+use crate::spawner::insert_unit;
+use crate::supply::Supply;
+use crate::units::{clear_current_order, Destination, Faction, UnitPos};
+
+/// How far below home units spawn, clear of the base's footprint
+const SPAWN_DISTANCE: f32 = 72.;
+
+// -----------------------------------------------------------------------------
+//     - Data -
+// -----------------------------------------------------------------------------
+/// How an AI commander plays, loaded from `res://data/ai.ron`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Difficulty {
+    /// Archetype the commander spawns
+    pub archetype: String,
+    /// Seconds between spawning units
+    pub spawn_interval: f32,
+    /// Total number of units the commander can spawn
+    pub budget: u32,
+    pub squad_size: usize,
+    /// Units below this fraction of their hitpoints head home
+    pub retreat_health: f32,
+}
+
+impl Default for Difficulty {
+    fn default() -> Self {
+        Self {
+            archetype: "soldier".into(),
+            spawn_interval: 5.,
+            budget: 16,
+            squad_size: 4,
+            retreat_health: 0.3,
+        }
+    }
+}
+
+pub type Difficulties = HashMap<String, Difficulty>;
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// Controls every unit of a faction
+pub struct AiCommander {
+    pub faction: Faction,
+    pub difficulty: Difficulty,
+    /// Where units spawn and retreat to
+    pub home: Vector2,
+    spawn_timer: f32,
+    spawned: u32,
+    next_squad: u32,
+}
+
+impl AiCommander {
+    pub fn new(faction: Faction, difficulty: Difficulty, home: Vector2) -> Self {
+        Self {
+            faction,
+            spawn_timer: difficulty.spawn_interval,
+            difficulty,
+            home,
+            spawned: 0,
+            next_squad: 0,
+        }
+    }
+}
+
+/// Units in the same squad of a faction move and attack together
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Squad(pub u32);
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+pub fn ai_spawn_units() -> Box<dyn Schedulable> {
+    SystemBuilder::new("ai spawn units")
+        .read_resource::<Delta>()
+        .read_resource::<Archetypes>()
+        .write_resource::<Stockpiles>()
+        .write_resource::<Supply>()
+        .with_query(<Write<AiCommander>>::query())
+        .build(|cmd, world, (delta, archetypes, stockpiles, supply), query| {
+            for mut commander in query.iter_mut(world) {
+                if commander.spawned >= commander.difficulty.budget {
+                    continue;
+                }
+
+                commander.spawn_timer -= delta.0;
+                if commander.spawn_timer > 0. {
+                    continue;
+                }
+
+                let archetype = match archetypes.get(&commander.difficulty.archetype) {
+                    Some(a) => a,
+                    None => continue,
+                };
+
+                // Keep trying until there's enough minerals and room under the cap
+                if supply.available(commander.faction) < archetype.supply {
+                    continue;
+                }
+                if !stockpiles.spend(commander.faction, archetype.cost) {
+                    continue;
+                }
+                supply.reserve(commander.faction, archetype.supply);
+                commander.spawn_timer = commander.difficulty.spawn_interval;
+
+                // Spread units out below home so they don't spawn on top of
+                // each other or inside the base
+                let column = (commander.spawned % 4) as f32 - 1.5;
+                let row = (commander.spawned / 4 % 4) as f32 - 1.5;
+                let pos = commander.home + Vector2::new(column * 16., SPAWN_DISTANCE + row * 16.);
+
+                insert_unit(cmd, archetype, pos, commander.faction);
+                commander.spawned += 1;
+            }
+        })
+}
+
+pub fn ai_form_squads() -> Box<dyn Schedulable> {
+    SystemBuilder::new("ai form squads")
+        .with_query(<Write<AiCommander>>::query())
+        // Units with a behaviour tree look after themselves, workers work and
+        // buildings stay put
+        .with_query(<(Read<Faction>, Read<Hitpoints>, Read<MaxHitpoints>)>::query().filter(
+            !component::<Squad>()
+                & !component::<Behaviour>()
+                & !component::<Harvester>()
+                & !component::<Building>(),
+        ))
+        .build(|cmd, world, _, (commanders, units)| {
+            let units = units
+                .iter_entities(world)
+                .map(|(ent, (faction, hp, max_hp))| (ent, *faction, hp.0 as f32 / max_hp.0 as f32))
+                .collect::<Vec<_>>();
+
+            for mut commander in commanders.iter_mut(world) {
+                let recruits = units
+                    .iter()
+                    .filter(|(_, faction, health)| {
+                        *faction == commander.faction && *health > commander.difficulty.retreat_health
+                    })
+                    .map(|(ent, _, _)| *ent)
+                    .collect::<Vec<_>>();
+
+                let squad_size = commander.difficulty.squad_size.max(1);
+                for members in recruits.chunks_exact(squad_size) {
+                    let squad = Squad(commander.next_squad);
+                    commander.next_squad += 1;
+                    for member in members {
+                        cmd.add_component(*member, squad);
+                    }
+                }
+            }
+        })
+}
+
+pub fn ai_attack() -> Box<dyn Schedulable> {
+    SystemBuilder::new("ai attack")
+        .write_component::<Orders>()
+        .read_component::<Destination>()
+        .read_component::<Target>()
+        .read_component::<AttackMoving>()
+        .read_component::<Movement>()
+        .with_query(<Read<AiCommander>>::query())
+        .with_query(<(Read<Squad>, Read<Faction>, Read<UnitPos>)>::query())
+        .with_query(<(Read<Faction>, Read<UnitPos>)>::query().filter(component::<Hitpoints>()))
+        .build(|_, world, _, (commanders, squads, hostiles)| {
+            let factions = commanders
+                .iter(world)
+                .map(|commander| commander.faction)
+                .collect::<Vec<_>>();
+
+            let mut members: HashMap<(u32, u32), Vec<(Entity, Vector2)>> = HashMap::new();
+            for (entity, (squad, faction, pos)) in squads.iter_entities(world) {
+                if factions.contains(&*faction) {
+                    members.entry((faction.0, squad.0)).or_default().push((entity, pos.0));
+                }
+            }
+
+            let hostiles = hostiles
+                .iter(world)
+                .map(|(faction, pos)| (*faction, pos.0))
+                .collect::<Vec<_>>();
+
+            for ((faction, _), members) in members {
+                let faction = Faction(faction);
+                let idle = members.iter().all(|(ent, _)| {
+                    world.get_component::<Destination>(*ent).is_none()
+                        && world.get_component::<Target>(*ent).is_none()
+                        && world.get_component::<AttackMoving>(*ent).is_none()
+                        && world.get_component::<Orders>(*ent).map(|o| o.0.is_empty()).unwrap_or(true)
+                });
+
+                if !idle {
+                    continue;
+                }
+
+                let centre = members
+                    .iter()
+                    .fold(Vector2::zero(), |acc, (_, pos)| acc + *pos)
+                    / members.len() as f32;
+
+                let target = hostiles
+                    .iter()
+                    .filter(|(other, _)| faction.hostile_to(other))
+                    .map(|(_, pos)| *pos)
+                    .min_by(|a, b| {
+                        (*a - centre)
+                            .length()
+                            .partial_cmp(&(*b - centre).length())
+                            .unwrap_or(std::cmp::Ordering::Equal)
+                    });
+
+                let target = match target {
+                    Some(t) => t,
+                    None => continue,
+                };
+
+                // Same as the player: attack move in formation
+                let speed = members
+                    .iter()
+                    .filter_map(|(ent, _)| world.get_component::<Movement>(*ent).map(|m| m.speed()))
+                    .fold(std::f32::MAX, f32::min);
+
+                for (entity, slot) in assign_slots(Formation::Box, &members, target) {
+                    let dest = Destination::in_group(slot, target, speed, members.len());
+                    if let Some(mut orders) = world.get_component_mut::<Orders>(entity) {
+                        orders.replace(Order::AttackMove(dest));
+                    }
+                }
+            }
+        })
+}
+
+pub fn ai_retreat() -> Box<dyn Schedulable> {
+    SystemBuilder::new("ai retreat")
+        .write_component::<Orders>()
+        .read_component::<Destination>()
+        .read_component::<Target>()
+        .read_component::<AttackMoving>()
+        .read_component::<Holding>()
+        .read_component::<Harvest>()
+        .read_component::<Repair>()
+        .with_query(<Read<AiCommander>>::query())
+        .with_query(<(Read<Faction>, Read<Hitpoints>, Read<MaxHitpoints>)>::query()
+            .filter(component::<Squad>()))
+        .build(|cmd, world, _, (commanders, units)| {
+            let commanders = commanders
+                .iter(world)
+                .map(|commander| (commander.faction, commander.home, commander.difficulty.retreat_health))
+                .collect::<Vec<_>>();
+
+            let retreating = units
+                .iter_entities(world)
+                .filter_map(|(ent, (faction, hp, max_hp))| {
+                    let health = hp.0 as f32 / max_hp.0 as f32;
+                    commanders
+                        .iter()
+                        .find(|(f, _, retreat_health)| *f == *faction && health <= *retreat_health)
+                        .map(|(_, home, _)| (ent, *home))
+                })
+                .collect::<Vec<_>>();
+
+            for (entity, home) in retreating {
+                clear_current_order(cmd, world, entity);
+                if let Some(mut orders) = world.get_component_mut::<Orders>(entity) {
+                    orders.replace(Order::Move(Destination::new(home)));
+                }
+                cmd.remove_component::<Squad>(entity);
+            }
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use std::collections::HashSet;
+
+    use crate::assert_gd;
+    use crate::buildings::{insert_building, Blueprint};
+    use crate::data;
+    use crate::simulation;
+    use crate::veterancy::Attackers;
+    use super::*;
+
+    // Only units join squads, never the buildings next to them
+    pub fn test_form_squads() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        let difficulty = Difficulty { squad_size: 2, ..Difficulty::default() };
+        let home = Vector2::zero();
+
+        world.insert((), vec![(AiCommander::new(Faction(1), difficulty, home),)]);
+        let units = world.insert((), vec![
+            (Faction(1), UnitPos(home + Vector2::new(-32., 32.)), Hitpoints(10), MaxHitpoints(10)),
+            (Faction(1), UnitPos(home + Vector2::new(0., 32.)), Hitpoints(10), MaxHitpoints(10)),
+            (Faction(1), UnitPos(home + Vector2::new(32., 32.)), Hitpoints(10), MaxHitpoints(10)),
+        ]).to_vec();
+
+        let terrain = simulation::flat_terrain();
+        let mut cmd = CommandBuffer::new(&world);
+        let base = insert_building(&mut cmd, &Blueprint::base(), &terrain, terrain.tile(home).unwrap(), Faction(1));
+        cmd.write(&mut world);
+
+        let mut sched = Schedule::builder().add_system(ai_form_squads()).build();
+        sched.execute(&mut world, &mut resources);
+
+        let squads = units.iter().filter(|ent| world.get_component::<Squad>(**ent).is_some()).count();
+        assert_gd!(squads == 2);
+        assert_gd!(world.get_component::<Squad>(base).is_none())
+    }
+
+    // Two AI commanders should find and fight each other
+    pub fn test_ai_vs_ai() -> bool {
+        let difficulties: Difficulties = data::parse(r#"{
+            "test": (
+                archetype: "soldier",
+                spawn_interval: 0.5,
+                budget: 4,
+                squad_size: 2,
+                retreat_health: 0.0,
+            ),
+        }"#).unwrap();
+        let difficulty = difficulties["test"].clone();
+
+        let mut world = Universe::new().create_world();
+        let mut resources = simulation::resources();
+        let mut sched = simulation::schedule();
+
+        world.insert((), vec![
+            (AiCommander::new(Faction(1), difficulty.clone(), Vector2::new(-200., 0.)),),
+            (AiCommander::new(Faction(2), difficulty, Vector2::new(200., 0.)),),
+        ]);
+
+        // A minute of game time, keeping track of which faction hurt which
+        let mut hurt = HashSet::new();
+        let mut query = <(Read<Faction>, Read<Attackers>)>::query();
+        for _ in 0..600 {
+            simulation::step(&mut world, &mut resources, &mut sched, 0.1);
+
+            for (victim, attackers) in query.iter(&world) {
+                for (attacker, _) in &attackers.0 {
+                    if let Some(faction) = world.get_component::<Faction>(*attacker) {
+                        hurt.insert((faction.0, victim.0));
+                    }
+                }
+            }
+        }
+
+        // Everyone got spawned, and both sides fought back
+        assert_gd!(<Read<AiCommander>>::query().iter(&world).all(|c| c.spawned == 4));
+        assert_gd!(hurt.contains(&(1, 2)));
+        assert_gd!(hurt.contains(&(2, 1)))
+    }
+}
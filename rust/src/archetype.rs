@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use crate::combat::Weapon;
+use crate::economy::Harvester;
+use crate::healing::{HealWeights, Healer, Repairer};
+use crate::movement::Movement;
+use crate::steering::Steering;
+use crate::targeting::TargetWeights;
+
+/// Stats shared by every unit of a kind
+#[derive(Debug, Clone)]
+pub struct Archetype {
+    pub name: String,
+    pub sprite: String,
+    pub hitpoints: u32,
+    /// Minerals it takes to spawn one
+    pub cost: u32,
+    /// Room it takes up under the faction's supply cap
+    pub supply: u32,
+    /// Seconds it takes a building to produce one
+    pub build_time: f32,
+    /// How far the unit can see through the fog of war
+    pub sight: f32,
+    pub width: f32,
+    pub height: f32,
+    pub movement: Movement,
+    /// Rotate the sprite towards the facing direction instead of flipping it
+    pub rotate_sprite: bool,
+    pub steering: Steering,
+    /// How the unit picks what to attack when left to itself
+    pub targeting: TargetWeights,
+    /// Behaviour tree the unit runs on its own, from `res://data/behaviours.ron`
+    pub behaviour: Option<String>,
+    /// Workers can harvest resource nodes
+    pub harvester: Option<Harvester>,
+    /// Abilities from `res://data/abilities.ron`, in the order of the
+    /// `ability_1`, `ability_2` and `ability_3` actions
+    pub abilities: Vec<String>,
+    /// Each one fires on its own, the longest range is how close hostiles
+    /// have to be before the unit engages them
+    pub weapons: Vec<Weapon>,
+    /// Only weapons that target air can hit it
+    pub flying: bool,
+    /// Support units heal damaged allies nearby
+    pub healer: Option<Healer>,
+    /// Workers can repair buildings
+    pub repairer: Option<Repairer>,
+}
+
+impl Archetype {
+    pub fn soldier() -> Self {
+        Self {
+            name: "soldier".into(),
+            sprite: "res://PlayerSprite.tscn".into(),
+            hitpoints: 10,
+            cost: 50,
+            supply: 1,
+            build_time: 4.,
+            sight: 200.,
+            width: 7.,
+            height: 29.,
+            movement: Movement::new(100., 400., 600., 12.),
+            rotate_sprite: false,
+            steering: Steering::default(),
+            targeting: TargetWeights::default(),
+            behaviour: None,
+            harvester: None,
+            abilities: vec!["grenade".into(), "snipe".into()],
+            weapons: vec![Weapon::rifle()],
+            flying: false,
+            healer: None,
+            repairer: None,
+        }
+    }
+
+    pub fn worker() -> Self {
+        Self {
+            name: "worker".into(),
+            hitpoints: 6,
+            cost: 50,
+            build_time: 3.,
+            weapons: vec![Weapon::tools()],
+            harvester: Some(Harvester { capacity: 5, rate: 2. }),
+            repairer: Some(Repairer { rate: 2., cost: 1 }),
+            abilities: Vec::new(),
+            ..Self::soldier()
+        }
+    }
+
+    /// Holds its ground, fights whatever comes close, patches up allies and
+    /// runs when hurt
+    pub fn sentry() -> Self {
+        Self {
+            name: "sentry".into(),
+            supply: 2,
+            behaviour: Some("guard".into()),
+            // Protect whoever is being shot at
+            targeting: TargetWeights { defend: 2., ..TargetWeights::default() },
+            abilities: vec!["barrier".into(), "reinforce".into()],
+            healer: Some(Healer::new(1.5, 80., HealWeights::default())),
+            ..Self::soldier()
+        }
+    }
+
+    /// Slow, short ranged and good against groups
+    pub fn grenadier() -> Self {
+        Self {
+            name: "grenadier".into(),
+            cost: 75,
+            supply: 2,
+            build_time: 6.,
+            movement: Movement::new(80., 400., 600., 12.),
+            abilities: Vec::new(),
+            weapons: vec![Weapon::launcher(), Weapon::pistol()],
+            ..Self::soldier()
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Every archetype units can be spawned from, by name
+pub struct Archetypes(HashMap<String, Archetype>);
+
+impl Archetypes {
+    pub fn new() -> Self {
+        let mut archetypes = Self(HashMap::new());
+        archetypes.insert(Archetype::soldier());
+        archetypes.insert(Archetype::sentry());
+        archetypes.insert(Archetype::worker());
+        archetypes.insert(Archetype::grenadier());
+        archetypes
+    }
+
+    pub fn insert(&mut self, archetype: Archetype) {
+        self.0.insert(archetype.name.clone(), archetype);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Archetype> {
+        self.0.get(name)
+    }
+}
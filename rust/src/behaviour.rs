@@ -0,0 +1,458 @@
+//! Behaviour trees for units that think for themselves.
+use std::collections::HashMap;
+
+use gdnative::Vector2;
+use legion::prelude::*;
+use serde::Deserialize;
+
+use crate::combat::{weapons_ready, AttackRange, Cooldowns, Hitpoints, MaxHitpoints, Target, Weapons};
+use crate::gameworld::Delta;
+use crate::steering::ARRIVAL_RADIUS;
+use crate::targeting::{choose_target, Candidate, TargetWeights};
+use crate::units::{Destination, Faction, UnitPos};
+
+// -----------------------------------------------------------------------------
+//     - Data -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Success,
+    Failure,
+    Running,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum Node {
+    // Composites
+
+    /// Run children in order until one doesn't succeed
+    Sequence(Vec<Node>),
+    /// Run children in order until one doesn't fail
+    Selector(Vec<Node>),
+    /// Run every child, succeed once `succeed` of them have succeeded
+    Parallel { succeed: usize, children: Vec<Node> },
+
+    // Decorators
+
+    /// Swap success and failure
+    Invert(Box<Node>),
+    /// Succeed even if the child fails
+    Succeed(Box<Node>),
+    /// Fail for the given number of seconds after the child succeeded
+    Throttle(f32, Box<Node>),
+
+    // Conditions
+
+    /// The blackboard target is still alive
+    HasTarget,
+    /// Below the given fraction of max hitpoints
+    IsHurt(f32),
+
+    // Actions
+
+    /// Put the best hostile in attack range on the blackboard as `target`
+    FindTarget,
+    /// Attack the blackboard target until it dies
+    AttackTarget,
+    /// Move to the position, or unit, stored under the key
+    MoveTo(String),
+    /// Run the given distance away from the nearest hostile
+    Flee(f32),
+    /// Wait until the unit can attack again
+    WaitCooldown,
+    /// Wait for the given number of seconds
+    Wait(f32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Entity(Entity),
+    Position(Vector2),
+}
+
+/// Memory of a single unit, shared between the nodes of its tree
+#[derive(Debug, Clone, Default)]
+pub struct Blackboard {
+    values: HashMap<String, Value>,
+    // Keyed by node address, trees are never modified once loaded
+    timers: HashMap<usize, f32>,
+}
+
+impl Blackboard {
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.values.get(key).copied()
+    }
+
+    pub fn set(&mut self, key: &str, value: Value) {
+        self.values.insert(key.to_string(), value);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.values.remove(key);
+    }
+
+    pub fn entity(&self, key: &str) -> Option<Entity> {
+        match self.get(key) {
+            Some(Value::Entity(entity)) => Some(entity),
+            _ => None,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// Runs the named tree every frame
+#[derive(Debug, Clone)]
+pub struct Behaviour {
+    pub tree: String,
+    pub blackboard: Blackboard,
+}
+
+impl Behaviour {
+    pub fn new(tree: &str) -> Self {
+        Self { tree: tree.to_string(), blackboard: Blackboard::default() }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Every behaviour tree, by name
+#[derive(Debug, Default, Deserialize)]
+pub struct BehaviourTrees(HashMap<String, Node>);
+
+impl BehaviourTrees {
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Node> {
+        self.0.get(name)
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Executor -
+// -----------------------------------------------------------------------------
+struct Context<'a> {
+    entity: Entity,
+    pos: Vector2,
+    faction: Faction,
+    range: f32,
+    health: f32,
+    delta: f32,
+    hostiles: &'a [Candidate],
+    world: &'a SubWorld,
+    cmd: &'a mut CommandBuffer,
+    blackboard: &'a mut Blackboard,
+}
+
+impl<'a> Context<'a> {
+    fn alive(&self, entity: Entity) -> bool {
+        self.world.get_component::<Hitpoints>(entity).map(|hp| hp.0 > 0).unwrap_or(false)
+    }
+
+    fn nearest_hostile(&self, range: f32) -> Option<(Entity, Vector2)> {
+        self.hostiles
+            .iter()
+            .filter(|c| self.faction.hostile_to(&c.faction))
+            .map(|c| (c.entity, c.pos, (c.pos - self.pos).length()))
+            .filter(|(_, _, distance)| *distance <= range)
+            .min_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(ent, pos, _)| (ent, pos))
+    }
+
+    fn move_to(&mut self, goal: Vector2) -> Status {
+        if (goal - self.pos).length() <= ARRIVAL_RADIUS * 2. {
+            return Status::Success;
+        }
+
+        // Only redirect when the goal has moved, e.g. when chasing a unit
+        let heading_there = self
+            .world
+            .get_component::<Destination>(self.entity)
+            .map(|dest| (dest.pos - goal).length() <= ARRIVAL_RADIUS)
+            .unwrap_or(false);
+
+        if !heading_there {
+            self.cmd.add_component(self.entity, Destination::new(goal));
+        }
+        Status::Running
+    }
+}
+
+fn tick(node: &Node, ctx: &mut Context) -> Status {
+    match node {
+        Node::Sequence(children) => {
+            for child in children {
+                match tick(child, ctx) {
+                    Status::Success => continue,
+                    status => return status,
+                }
+            }
+            Status::Success
+        }
+        Node::Selector(children) => {
+            for child in children {
+                match tick(child, ctx) {
+                    Status::Failure => continue,
+                    status => return status,
+                }
+            }
+            Status::Failure
+        }
+        Node::Parallel { succeed, children } => {
+            let mut successes = 0;
+            let mut failures = 0;
+            for child in children {
+                match tick(child, ctx) {
+                    Status::Success => successes += 1,
+                    Status::Failure => failures += 1,
+                    Status::Running => {}
+                }
+            }
+
+            if successes >= *succeed {
+                Status::Success
+            } else if children.len() - failures < *succeed {
+                Status::Failure
+            } else {
+                Status::Running
+            }
+        }
+        Node::Invert(child) => match tick(child, ctx) {
+            Status::Success => Status::Failure,
+            Status::Failure => Status::Success,
+            Status::Running => Status::Running,
+        },
+        Node::Succeed(child) => match tick(child, ctx) {
+            Status::Running => Status::Running,
+            _ => Status::Success,
+        },
+        Node::Throttle(secs, child) => {
+            let key = node as *const Node as usize;
+            if let Some(left) = ctx.blackboard.timers.get_mut(&key) {
+                *left -= ctx.delta;
+                if *left > 0. {
+                    return Status::Failure;
+                }
+                ctx.blackboard.timers.remove(&key);
+            }
+
+            let status = tick(child, ctx);
+            if status == Status::Success {
+                ctx.blackboard.timers.insert(key, *secs);
+            }
+            status
+        }
+        Node::HasTarget => match ctx.blackboard.entity("target") {
+            Some(target) if ctx.alive(target) => Status::Success,
+            _ => Status::Failure,
+        },
+        Node::IsHurt(fraction) => {
+            if ctx.health < *fraction {
+                Status::Success
+            } else {
+                Status::Failure
+            }
+        }
+        Node::FindTarget => {
+            let target = choose_target(ctx.cmd, ctx.world, ctx.entity, ctx.pos, ctx.faction, ctx.range, ctx.hostiles);
+            match target {
+                Some(target) => {
+                    ctx.blackboard.set("target", Value::Entity(target));
+                    Status::Success
+                }
+                None => Status::Failure,
+            }
+        }
+        Node::AttackTarget => {
+            let target = match ctx.blackboard.entity("target") {
+                Some(target) => target,
+                None => return Status::Failure,
+            };
+
+            if !ctx.alive(target) {
+                ctx.blackboard.remove("target");
+                return Status::Success;
+            }
+
+            let attacking = ctx
+                .world
+                .get_component::<Target>(ctx.entity)
+                .map(|t| t.0 == target)
+                .unwrap_or(false);
+
+            if !attacking {
+                ctx.cmd.add_component(ctx.entity, Target(target));
+            }
+            Status::Running
+        }
+        Node::MoveTo(key) => {
+            let goal = match ctx.blackboard.get(key) {
+                Some(Value::Position(pos)) => pos,
+                Some(Value::Entity(entity)) => match ctx.world.get_component::<UnitPos>(entity) {
+                    Some(pos) => pos.0,
+                    None => return Status::Failure,
+                },
+                _ => return Status::Failure,
+            };
+            ctx.move_to(goal)
+        }
+        Node::Flee(distance) => {
+            let goal = match ctx.blackboard.get("flee") {
+                Some(Value::Position(pos)) => pos,
+                _ => match ctx.nearest_hostile(std::f32::MAX) {
+                    Some((_, hostile_pos)) => {
+                        let away = (ctx.pos - hostile_pos).normalize();
+                        let goal = ctx.pos + away * *distance;
+                        ctx.blackboard.set("flee", Value::Position(goal));
+                        goal
+                    }
+                    None => return Status::Success,
+                },
+            };
+
+            let status = ctx.move_to(goal);
+            if status == Status::Success {
+                ctx.blackboard.remove("flee");
+            }
+            status
+        }
+        Node::WaitCooldown => {
+            if weapons_ready(ctx.world, ctx.entity) {
+                Status::Success
+            } else {
+                Status::Running
+            }
+        }
+        Node::Wait(secs) => {
+            let key = node as *const Node as usize;
+            let left = ctx.blackboard.timers.entry(key).or_insert(*secs);
+            *left -= ctx.delta;
+            if *left > 0. {
+                Status::Running
+            } else {
+                ctx.blackboard.timers.remove(&key);
+                Status::Success
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+pub fn run_behaviours() -> Box<dyn Schedulable> {
+    SystemBuilder::new("run behaviours")
+        .read_resource::<Delta>()
+        .read_resource::<BehaviourTrees>()
+        .write_component::<Behaviour>()
+        .read_component::<Hitpoints>()
+        .read_component::<Cooldowns>()
+        .read_component::<Weapons>()
+        .read_component::<Target>()
+        .read_component::<Destination>()
+        .read_component::<UnitPos>()
+        .read_component::<MaxHitpoints>()
+        .read_component::<AttackRange>()
+        .read_component::<Faction>()
+        .read_component::<TargetWeights>()
+        .with_query(<(Read<UnitPos>, Read<Faction>, Read<AttackRange>, Read<Hitpoints>, Read<MaxHitpoints>)>::query()
+            .filter(component::<Behaviour>()))
+        .with_query(<(Read<UnitPos>, Read<Faction>, Read<Hitpoints>)>::query())
+        .build(|cmd, world, (delta, trees), (units, hostiles)| {
+            let hostiles = hostiles
+                .iter_entities(world)
+                .map(|(ent, (pos, faction, hp))| Candidate::new(world, ent, pos.0, *faction, &hp))
+                .collect::<Vec<_>>();
+
+            let units = units
+                .iter_entities(world)
+                .map(|(ent, (pos, faction, range, hp, max_hp))| {
+                    (ent, pos.0, *faction, range.0, hp.0 as f32 / max_hp.0 as f32)
+                })
+                .collect::<Vec<_>>();
+
+            for (entity, pos, faction, range, health) in units {
+                let mut behaviour = match world.get_component_mut::<Behaviour>(entity) {
+                    Some(b) => b,
+                    None => continue,
+                };
+
+                let tree = match trees.get(&behaviour.tree) {
+                    Some(tree) => tree,
+                    None => continue,
+                };
+
+                let mut ctx = Context {
+                    entity,
+                    pos,
+                    faction,
+                    range,
+                    health,
+                    delta: delta.0,
+                    hostiles: &hostiles,
+                    world,
+                    cmd: &mut *cmd,
+                    blackboard: &mut behaviour.blackboard,
+                };
+
+                tick(tree, &mut ctx);
+            }
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use crate::data;
+    use super::*;
+
+    const TREES: &str = r#"{
+        "guard": Selector([
+            Sequence([IsHurt(0.5), Flee(100.0)]),
+            Sequence([FindTarget, AttackTarget]),
+        ]),
+    }"#;
+
+    fn run(world: &mut World, trees: BehaviourTrees) {
+        let mut resources = Resources::default();
+        resources.insert(Delta(0.1));
+        resources.insert(trees);
+
+        let mut sched = Schedule::builder()
+            .add_system(run_behaviours())
+            .build();
+        sched.execute(world, &mut resources);
+    }
+
+    // A healthy guard attacks, a hurt one runs away
+    pub fn test_behaviour_tree() -> bool {
+        let trees: BehaviourTrees = data::parse(TREES).unwrap();
+        let mut world = Universe::new().create_world();
+
+        let guards = world.insert(
+            (),
+            vec![
+                (UnitPos(Vector2::new(0., 0.)), Faction(1), AttackRange(50.), Hitpoints(10), MaxHitpoints(10), Behaviour::new("guard")),
+                (UnitPos(Vector2::new(0., 40.)), Faction(1), AttackRange(50.), Hitpoints(2), MaxHitpoints(10), Behaviour::new("guard")),
+            ],
+        ).to_vec();
+        let enemy = world.insert(
+            (),
+            vec![(UnitPos(Vector2::new(30., 0.)), Faction(0), Hitpoints(10))],
+        )[0];
+
+        run(&mut world, trees);
+
+        let attacking = world.get_component::<Target>(guards[0]).map(|t| t.0 == enemy).unwrap_or(false);
+        let fleeing_to = world.get_component::<Destination>(guards[1]).map(|d| d.pos);
+
+        assert_gd!(attacking);
+        assert_gd!(world.get_component::<Destination>(guards[0]).is_none());
+        // Away from the enemy
+        assert_gd!(fleeing_to.map(|pos| pos.x < 0.).unwrap_or(false))
+    }
+}
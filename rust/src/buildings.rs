@@ -0,0 +1,476 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use gdnative::{Rect2, Vector2};
+use legion::prelude::*;
+
+use crate::archetype::Archetypes;
+use crate::combat::{Hitpoints, MaxHitpoints};
+use crate::economy::{DropOff, Stockpiles};
+use crate::effects::Effects;
+use crate::gameworld::{Delta, Notices, Selected};
+use crate::input::{Actions, MouseButton, MousePos};
+use crate::navigation::{Cell, NavGrid};
+use crate::spawner::insert_unit;
+use crate::supply::Supply;
+use crate::tech::{Research, TechTree, Upgrades};
+use crate::terrain::Terrain;
+use crate::units::{Destination, Faction, UnitPos, UnitRect};
+use crate::veterancy::Attackers;
+use crate::Size2;
+
+/// Most units a building can have queued up
+pub const MAX_QUEUE: usize = 5;
+
+/// Actions that queue up the first, second and third unit a building produces
+pub const PRODUCE_ACTIONS: &[&str] = &["produce_1", "produce_2", "produce_3"];
+
+// -----------------------------------------------------------------------------
+//     - Data -
+// -----------------------------------------------------------------------------
+/// Stats shared by every building of a kind
+#[derive(Debug, Clone)]
+pub struct Blueprint {
+    pub name: String,
+    /// Size in tiles
+    pub width: i32,
+    pub height: i32,
+    pub hitpoints: u32,
+    pub cost: u32,
+    /// Archetypes the building can produce
+    pub produces: Vec<String>,
+    /// Techs that can be researched here, from `res://data/tech.ron`
+    pub researches: Vec<String>,
+    /// Workers can bring minerals here
+    pub drop_off: bool,
+    /// How much the building raises its faction's supply cap
+    pub supply: u32,
+}
+
+impl Blueprint {
+    pub fn base() -> Self {
+        Self {
+            name: "base".into(),
+            width: 3,
+            height: 3,
+            hitpoints: 40,
+            cost: 400,
+            produces: vec!["worker".into()],
+            researches: vec!["plating".into(), "boots".into()],
+            drop_off: true,
+            supply: 15,
+        }
+    }
+
+    pub fn barracks() -> Self {
+        Self {
+            name: "barracks".into(),
+            width: 3,
+            height: 2,
+            hitpoints: 30,
+            cost: 150,
+            produces: vec!["soldier".into(), "sentry".into(), "grenadier".into()],
+            researches: vec!["weapons".into(), "sentry_training".into(), "rapid_fire".into()],
+            drop_off: false,
+            supply: 0,
+        }
+    }
+
+    /// Does nothing but raise the supply cap
+    pub fn depot() -> Self {
+        Self {
+            name: "depot".into(),
+            width: 2,
+            height: 2,
+            hitpoints: 20,
+            cost: 100,
+            produces: Vec::new(),
+            researches: Vec::new(),
+            drop_off: false,
+            supply: 10,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// Name of the blueprint a building was built from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Building(pub String);
+
+/// Tiles a building stands on, units can't walk through them
+#[derive(Debug, Clone)]
+pub struct Footprint(pub Vec<Cell>);
+
+/// Units waiting to be produced, the first one is in progress
+#[derive(Debug, Clone)]
+pub struct Production {
+    pub queue: VecDeque<String>,
+    pub progress: f32,
+    /// Where new units head once produced
+    pub rally: Vector2,
+}
+
+impl Production {
+    pub fn new(rally: Vector2) -> Self {
+        Self { queue: VecDeque::new(), progress: 0., rally }
+    }
+
+    /// Remove the last unit in the queue
+    pub fn cancel(&mut self) -> Option<String> {
+        let cancelled = self.queue.pop_back();
+        if self.queue.is_empty() {
+            self.progress = 0.;
+        }
+        cancelled
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Every blueprint buildings can be built from, by name
+pub struct Blueprints(HashMap<String, Blueprint>);
+
+impl Blueprints {
+    pub fn new() -> Self {
+        let mut blueprints = Self(HashMap::new());
+        blueprints.insert(Blueprint::base());
+        blueprints.insert(Blueprint::barracks());
+        blueprints.insert(Blueprint::depot());
+        blueprints
+    }
+
+    pub fn insert(&mut self, blueprint: Blueprint) {
+        self.0.insert(blueprint.name.clone(), blueprint);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Blueprint> {
+        self.0.get(name)
+    }
+}
+
+/// Cells of the navigation grid currently blocked by buildings
+#[derive(Default)]
+pub struct BlockedCells(HashSet<Cell>);
+
+// -----------------------------------------------------------------------------
+//     - Spawning -
+// -----------------------------------------------------------------------------
+/// Tiles covered by a building whose top left tile is `tile`
+pub fn footprint(blueprint: &Blueprint, tile: Cell) -> Vec<Cell> {
+    let mut cells = Vec::new();
+    for y in tile.1..tile.1 + blueprint.height {
+        for x in tile.0..tile.0 + blueprint.width {
+            cells.push((x, y));
+        }
+    }
+    cells
+}
+
+/// Area covered by a building whose top left tile is `tile`
+pub fn footprint_rect(blueprint: &Blueprint, terrain: &Terrain, tile: Cell) -> Rect2 {
+    let size = terrain.tile_size();
+    Rect2::new(
+        terrain.tile_origin(tile).to_point(),
+        Size2::new(blueprint.width as f32 * size, blueprint.height as f32 * size),
+    )
+}
+
+/// Top left tile of a building centered on `pos`
+pub fn centered_tile(blueprint: &Blueprint, terrain: &Terrain, pos: Vector2) -> Option<Cell> {
+    terrain
+        .tile(pos)
+        .map(|tile| (tile.0 - blueprint.width / 2, tile.1 - blueprint.height / 2))
+}
+
+/// Add a building with its top left corner on `tile`
+pub fn insert_building(
+    cmd: &mut CommandBuffer,
+    blueprint: &Blueprint,
+    terrain: &Terrain,
+    tile: Cell,
+    faction: Faction,
+) -> Entity {
+    let rect = footprint_rect(blueprint, terrain, tile);
+    let pos = rect.center().to_vector();
+    let rally = Vector2::new(pos.x, rect.max_y() + terrain.tile_size() * 2.);
+
+    let entity = cmd.insert(
+        (),
+        vec![(
+            Building(blueprint.name.clone()),
+            UnitPos(pos),
+            UnitRect(rect),
+            Hitpoints(blueprint.hitpoints),
+            MaxHitpoints(blueprint.hitpoints),
+            Footprint(footprint(blueprint, tile)),
+            Production::new(rally),
+            Attackers::default(),
+            faction,
+        )],
+    )[0];
+
+    cmd.add_component(entity, Effects::default());
+
+    if blueprint.drop_off {
+        cmd.add_component(entity, DropOff);
+    }
+
+    if !blueprint.researches.is_empty() {
+        cmd.add_component(entity, Research::default());
+    }
+
+    entity
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+/// Queue up units at the selected buildings, paying up front.
+/// Cancelling refunds the last unit in the queue.
+pub fn queue_production() -> Box<dyn Schedulable> {
+    SystemBuilder::new("queue production")
+        .write_resource::<Actions>()
+        .read_resource::<Archetypes>()
+        .read_resource::<Blueprints>()
+        .read_resource::<TechTree>()
+        .read_resource::<Upgrades>()
+        .read_resource::<Supply>()
+        .write_resource::<Stockpiles>()
+        .write_resource::<Notices>()
+        .with_query(<(Read<Building>, Read<Faction>, Write<Production>)>::query().filter(tag::<Selected>()))
+        .build(|_, world, resources, query| {
+            let (actions, archetypes, blueprints, tech_tree, upgrades, supply, stockpiles, notices) = resources;
+
+            let produce = PRODUCE_ACTIONS.iter().position(|action| actions.pressed(action));
+            let cancel = actions.pressed("cancel_production");
+            if produce.is_none() && !cancel {
+                return;
+            }
+
+            for (building, faction, mut production) in query.iter_mut(world) {
+                if cancel {
+                    let refund = production
+                        .cancel()
+                        .and_then(|name| archetypes.get(&name))
+                        .map(|archetype| archetype.cost);
+                    if let Some(refund) = refund {
+                        stockpiles.add(*faction, refund);
+                    }
+                    continue;
+                }
+
+                let archetype = produce
+                    .and_then(|i| blueprints.get(&building.0)?.produces.get(i))
+                    .and_then(|name| archetypes.get(name));
+
+                let archetype = match archetype {
+                    Some(a) => a,
+                    None => continue,
+                };
+
+                if !tech_tree.unlocked(upgrades, *faction, &archetype.name) {
+                    notices.push(format!("Research is needed for a {}", archetype.name));
+                } else if supply.available(*faction) < archetype.supply {
+                    notices.push(format!("Not enough supply for a {}", archetype.name));
+                } else if production.queue.len() >= MAX_QUEUE {
+                    notices.push(format!("The {} queue is full", building.0));
+                } else if stockpiles.spend(*faction, archetype.cost) {
+                    production.queue.push_back(archetype.name.clone());
+                } else {
+                    notices.push(format!("Not enough minerals for a {}", archetype.name));
+                }
+            }
+
+            PRODUCE_ACTIONS.iter().for_each(|action| actions.consume(action));
+            actions.consume("cancel_production");
+        })
+}
+
+/// Right click sets the rally point of the selected buildings
+pub fn set_rally_point() -> Box<dyn Schedulable> {
+    SystemBuilder::new("set rally point")
+        .read_resource::<MouseButton>()
+        .read_resource::<MousePos>()
+        .with_query(<Write<Production>>::query().filter(tag::<Selected>()))
+        .build(|_, world, (mouse_btn, mouse_pos), query| {
+            if !mouse_btn.button_pressed(2) {
+                return;
+            }
+
+            for mut production in query.iter_mut(world) {
+                production.rally = mouse_pos.global();
+            }
+        })
+}
+
+pub fn produce_units() -> Box<dyn Schedulable> {
+    SystemBuilder::new("produce units")
+        .read_resource::<Delta>()
+        .read_resource::<Archetypes>()
+        .write_resource::<Supply>()
+        .with_query(<(Read<UnitRect>, Read<Faction>, Write<Production>)>::query())
+        .build(|cmd, world, (delta, archetypes, supply), query| {
+            for (rect, faction, mut production) in query.iter_mut(world) {
+                let archetype = match production.queue.front().and_then(|name| archetypes.get(name)) {
+                    Some(a) => a,
+                    None => continue,
+                };
+
+                production.progress = (production.progress + delta.0).min(archetype.build_time);
+                if production.progress < archetype.build_time {
+                    continue;
+                }
+
+                // Done, but waits there until there's room under the cap
+                if !supply.reserve(*faction, archetype.supply) {
+                    continue;
+                }
+
+                // Out the front door
+                let pos = Vector2::new(rect.0.center().x, rect.0.max_y() + archetype.height / 2.);
+                let entity = insert_unit(cmd, archetype, pos, *faction);
+                cmd.add_component(entity, Destination::new(production.rally));
+
+                production.queue.pop_front();
+                production.progress = 0.;
+            }
+        })
+}
+
+/// Keep the navigation grid in sync with the buildings standing on it
+pub fn block_footprints() -> Box<dyn Schedulable> {
+    SystemBuilder::new("block footprints")
+        .write_resource::<NavGrid>()
+        .read_resource::<Terrain>()
+        .write_resource::<BlockedCells>()
+        .with_query(<Read<Footprint>>::query())
+        .build(|_, world, (nav_grid, terrain, blocked), query| {
+            let cells = query
+                .iter(world)
+                .flat_map(|footprint| footprint.0.clone())
+                .collect::<HashSet<_>>();
+
+            if cells == blocked.0 {
+                return;
+            }
+
+            for cell in cells.difference(&blocked.0) {
+                nav_grid.block(*cell);
+            }
+
+            // The ground is back to whatever it was before the building
+            for cell in blocked.0.difference(&cells) {
+                let cost = terrain.cost_at(nav_grid.center(*cell));
+                nav_grid.set_cost(*cell, cost);
+            }
+
+            blocked.0 = cells;
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use crate::combat::{attack_targets, Target, Weapon, Weapons};
+    use crate::economy::STARTING_MINERALS;
+    use crate::effects::StatusEffects;
+    use crate::supply::STARTING_SUPPLY;
+    use crate::simulation::flat_terrain;
+    use super::*;
+
+    fn production_resources() -> Resources {
+        let mut resources = Resources::default();
+        resources.insert(Delta(1.));
+        resources.insert(Actions::empty());
+        resources.insert(Archetypes::new());
+        resources.insert(Blueprints::new());
+        resources.insert(TechTree::new());
+        resources.insert(Upgrades::new());
+        resources.insert(Stockpiles::new(STARTING_MINERALS));
+        resources.insert(Supply::new(STARTING_SUPPLY));
+        resources.insert(Notices::new());
+        resources
+    }
+
+    // Queued units cost minerals, come out over time and head for the rally point
+    pub fn test_production() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = production_resources();
+        let terrain = flat_terrain();
+
+        let mut cmd = CommandBuffer::new(&world);
+        let barracks = insert_building(&mut cmd, &Blueprint::barracks(), &terrain, (10, 10), Faction::PLAYER);
+        cmd.add_tag(barracks, Selected);
+        cmd.write(&mut world);
+
+        let rally = world.get_component::<Production>(barracks).unwrap().rally;
+        let cost = Archetypes::new().get("soldier").unwrap().cost;
+
+        let mut queue = Schedule::builder()
+            .add_system(queue_production())
+            .build();
+        let mut produce = Schedule::builder()
+            .add_system(produce_units())
+            .flush()
+            .build();
+
+        // Queue two soldiers, then cancel one
+        for &action in &["produce_1", "produce_1", "cancel_production"] {
+            resources.get_mut::<Actions>().map(|mut actions| actions.press(action));
+            queue.execute(&mut world, &mut resources);
+        }
+
+        let minerals = resources.get::<Stockpiles>().unwrap().amount(Faction::PLAYER);
+        assert_gd!(minerals == STARTING_MINERALS - cost);
+        assert_gd!(world.get_component::<Production>(barracks).unwrap().queue.len() == 1);
+
+        for _ in 0..10 {
+            produce.execute(&mut world, &mut resources);
+        }
+
+        let produced = <Read<Destination>>::query()
+            .iter(&world)
+            .filter(|dest| dest.pos == rally)
+            .count();
+
+        assert_gd!(produced == 1);
+        assert_gd!(world.get_component::<Production>(barracks).unwrap().queue.is_empty())
+    }
+
+    // Buildings block the navigation grid until they are destroyed
+    pub fn test_destroy_building() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        let terrain = flat_terrain();
+        resources.insert(terrain.nav_grid());
+        resources.insert(BlockedCells::default());
+
+        let mut blueprint = Blueprint::barracks();
+        blueprint.hitpoints = 1;
+
+        let mut cmd = CommandBuffer::new(&world);
+        let barracks = insert_building(&mut cmd, &blueprint, &terrain, (10, 10), Faction(1));
+        cmd.write(&mut world);
+        resources.insert(terrain);
+        resources.insert(StatusEffects::new());
+
+        let attacker = world.insert((), vec![(Target(barracks), Hitpoints(10), Weapons(vec![Weapon::rifle()]))])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(block_footprints())
+            .add_system(attack_targets())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(!resources.get::<NavGrid>().unwrap().passable((11, 11)));
+        assert_gd!(world.get_component::<Hitpoints>(barracks).is_none());
+        assert_gd!(world.get_component::<Target>(attacker).is_none());
+
+        // Rubble can be walked over
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(resources.get::<NavGrid>().unwrap().passable((11, 11)))
+    }
+}
@@ -1,27 +1,32 @@
-use gdnative::{Vector2, TextureRect};
+use std::collections::HashMap;
+
+use gdnative::{Polygon2D, Vector2, TextureRect};
 use legion::prelude::*;
 
-use crate::units::{UnitRect, UnitPos};
+use crate::units::{clear_current_order, within_radius, Destination, Faction, UnitRect, UnitPos};
+use crate::orders::{Holding, Order, Orders};
 use crate::input::{MousePos, MouseButton};
 use crate::gameworld::{Selected, WorldNode, Delta};
 use crate::spawner;
-
-const COOLDOWN: f32 = 1.;
-
-// -----------------------------------------------------------------------------
-//     - Tags -
-// -----------------------------------------------------------------------------
-#[derive(Debug, Clone, PartialEq)]
-struct Firing;
+use crate::buildings::Building;
+use crate::economy::{move_to, Harvest};
+use crate::effects::{add_effect, Effects, StatusEffects, Stunned};
+use crate::fog::FogOfWar;
+use crate::healing::Repair;
+use crate::targeting::{choose_target, Candidate, TargetWeights};
+use crate::veterancy::{record_death, Attackers};
 
 // -----------------------------------------------------------------------------
 //     - Components -
 // -----------------------------------------------------------------------------
-pub struct Target(Entity);
+pub struct Target(pub Entity);
 
 #[derive(Debug)]
 pub struct Hitpoints(pub u32);
 
+#[derive(Debug)]
+pub struct MaxHitpoints(pub u32);
+
 #[derive(Debug)]
 pub struct Bullet(pub TextureRect);
 
@@ -34,7 +39,313 @@ impl Drop for Bullet {
     }
 }
 
-struct Cooldown(f32);
+/// Components that run out over time, removed by `count_down` once they do
+pub trait Countdown: Send + Sync + 'static {
+    /// Advance by `delta` seconds, true once there's nothing left
+    fn tick(&mut self, delta: f32) -> bool;
+}
+
+/// Time left until each of the unit's weapons and abilities can be used again
+#[derive(Debug, Clone, Default)]
+pub struct Cooldowns(HashMap<String, f32>);
+
+impl Cooldowns {
+    pub fn ready(&self, name: &str) -> bool {
+        !self.0.contains_key(name)
+    }
+
+    pub fn remaining(&self, name: &str) -> f32 {
+        self.0.get(name).copied().unwrap_or(0.)
+    }
+
+    pub fn start(&mut self, name: &str, secs: f32) {
+        if secs > 0. {
+            self.0.insert(name.to_string(), secs);
+        }
+    }
+
+    pub fn reset(&mut self, name: &str) {
+        self.0.remove(name);
+    }
+}
+
+impl Countdown for Cooldowns {
+    fn tick(&mut self, delta: f32) -> bool {
+        self.0.values_mut().for_each(|secs| *secs -= delta);
+        self.0.retain(|_, secs| *secs > 0.);
+        self.0.is_empty()
+    }
+}
+
+/// Take hitpoints off `entity`, after shields, crediting `source`, and
+/// delete it once it's out. True if this killed it. Systems calling this
+/// need to write `Hitpoints`, `Effects` and `Attackers`, and read what
+/// `record_death` reads.
+pub fn deal_damage(
+    cmd: &mut CommandBuffer,
+    world: &SubWorld,
+    entity: Entity,
+    damage: u32,
+    source: Option<Entity>,
+) -> bool {
+    let remaining = {
+        let mut hp = match world.get_component_mut::<Hitpoints>(entity) {
+            Some(hp) => hp,
+            None => return false,
+        };
+
+        // Already dead, e.g. hit twice in the same frame
+        if hp.0 == 0 {
+            return false;
+        }
+
+        // Shields soak up what they can
+        let damage = world
+            .get_component_mut::<Effects>(entity)
+            .map(|mut effects| effects.absorb(damage))
+            .unwrap_or(damage);
+
+        let dealt = damage.min(hp.0);
+        if let (Some(source), Some(mut attackers)) = (source, world.get_component_mut::<Attackers>(entity)) {
+            attackers.record(source, dealt);
+        }
+
+        hp.0 -= dealt;
+        hp.0
+    };
+
+    if remaining == 0 {
+        record_death(cmd, world, entity, source);
+        return true;
+    }
+    false
+}
+
+/// Whether the unit can use `name` right now
+pub fn cooldown_ready(world: &SubWorld, entity: Entity, name: &str) -> bool {
+    world
+        .get_component::<Cooldowns>(entity)
+        .map(|cooldowns| cooldowns.ready(name))
+        .unwrap_or(true)
+}
+
+/// Systems calling this need to write `Cooldowns`
+pub fn start_cooldown(cmd: &mut CommandBuffer, world: &SubWorld, entity: Entity, name: &str, secs: f32) {
+    start_cooldowns(cmd, world, entity, &[(name, secs)]);
+}
+
+/// Start several at once. A unit without `Cooldowns` only gets the component
+/// when the command buffer is flushed, so starting them one at a time would
+/// keep only the last one.
+pub fn start_cooldowns(cmd: &mut CommandBuffer, world: &SubWorld, entity: Entity, started: &[(&str, f32)]) {
+    match world.get_component_mut::<Cooldowns>(entity) {
+        Some(mut cooldowns) => started.iter().for_each(|(name, secs)| cooldowns.start(name, *secs)),
+        None => {
+            let mut cooldowns = Cooldowns::default();
+            started.iter().for_each(|(name, secs)| cooldowns.start(name, *secs));
+            cmd.add_component(entity, cooldowns);
+        }
+    }
+}
+
+/// Whether any of the unit's weapons can fire right now. Units without
+/// weapons have nothing to wait for.
+pub fn weapons_ready(world: &SubWorld, entity: Entity) -> bool {
+    world
+        .get_component::<Weapons>(entity)
+        .map(|weapons| weapons.0.iter().any(|weapon| cooldown_ready(world, entity, &weapon.name)))
+        .unwrap_or(true)
+}
+
+/// Flies over everything, only weapons that target air can hit it
+#[derive(Debug, Clone, Copy)]
+pub struct Flying;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetKind {
+    Ground,
+    Air,
+    Building,
+}
+
+/// Requires read access to `Building` and `Flying`
+pub fn target_kind(world: &SubWorld, entity: Entity) -> TargetKind {
+    if world.get_component::<Building>(entity).is_some() {
+        TargetKind::Building
+    } else if world.get_component::<Flying>(entity).is_some() {
+        TargetKind::Air
+    } else {
+        TargetKind::Ground
+    }
+}
+
+/// What a weapon is able to shoot at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TargetFilter {
+    pub ground: bool,
+    pub air: bool,
+    pub buildings: bool,
+}
+
+impl TargetFilter {
+    pub const ALL: TargetFilter = TargetFilter { ground: true, air: true, buildings: true };
+    /// Anything that isn't flying
+    pub const SURFACE: TargetFilter = TargetFilter { ground: true, air: false, buildings: true };
+    pub const AIR: TargetFilter = TargetFilter { ground: false, air: true, buildings: false };
+
+    pub fn allows(&self, kind: TargetKind) -> bool {
+        match kind {
+            TargetKind::Ground => self.ground,
+            TargetKind::Air => self.air,
+            TargetKind::Building => self.buildings,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Weapon {
+    /// Also the weapon's key in `Cooldowns`
+    pub name: String,
+    pub damage: u32,
+    pub range: f32,
+    /// Seconds between shots
+    pub cooldown: f32,
+    /// Scene stretched from the attacker to whatever it hit
+    pub bullet: String,
+    pub targets: TargetFilter,
+    pub splash: Option<Splash>,
+    /// Status effect put on whatever survives a hit, by name
+    pub effect: Option<String>,
+}
+
+impl Weapon {
+    pub fn rifle() -> Self {
+        Self {
+            name: "rifle".into(),
+            damage: 1,
+            range: 120.,
+            cooldown: 1.,
+            bullet: "res://bullets/Ray2.tscn".into(),
+            targets: TargetFilter::ALL,
+            splash: None,
+            effect: None,
+        }
+    }
+
+    pub fn launcher() -> Self {
+        Self {
+            name: "launcher".into(),
+            damage: 3,
+            range: 90.,
+            cooldown: 2.5,
+            bullet: "res://bullets/Ray1.tscn".into(),
+            targets: TargetFilter::SURFACE,
+            splash: Some(Splash { radius: 30., falloff: 0.5, friendly_fire: false }),
+            effect: None,
+        }
+    }
+
+    pub fn pistol() -> Self {
+        Self {
+            name: "pistol".into(),
+            range: 60.,
+            cooldown: 0.8,
+            ..Self::rifle()
+        }
+    }
+
+    pub fn tools() -> Self {
+        Self {
+            name: "tools".into(),
+            range: 40.,
+            bullet: "res://bullets/Ray1.tscn".into(),
+            targets: TargetFilter::SURFACE,
+            ..Self::rifle()
+        }
+    }
+}
+
+/// Every weapon the unit carries, each firing on its own cooldown. Damage
+/// and cooldowns include upgrades, see `apply_upgrades`.
+#[derive(Debug, Clone, Default)]
+pub struct Weapons(pub Vec<Weapon>);
+
+impl Weapons {
+    /// Reach of the longest ranged weapon
+    pub fn range(&self) -> f32 {
+        self.0.iter().map(|weapon| weapon.range).fold(0., f32::max)
+    }
+
+    /// Damage per second with every weapon firing
+    pub fn dps(&self) -> f32 {
+        self.0
+            .iter()
+            .filter(|weapon| weapon.cooldown > 0.)
+            .map(|weapon| weapon.damage as f32 / weapon.cooldown)
+            .sum()
+    }
+}
+
+/// Attacks also hit everything within `radius` of the target, losing up to
+/// `falloff` of the damage towards the edge
+#[derive(Debug, Clone, Copy)]
+pub struct Splash {
+    pub radius: f32,
+    pub falloff: f32,
+    /// Hits the attacker's own faction as well
+    pub friendly_fire: bool,
+}
+
+impl Splash {
+    /// Damage taken `distance` away from the target, at least one
+    pub fn damage_at(&self, damage: u32, distance: f32) -> u32 {
+        let falloff = self.falloff * (distance / self.radius).min(1.);
+        ((damage as f32 * (1. - falloff)).round() as u32).max(1)
+    }
+}
+
+/// A weapon going off, drawn by `spawn_bullets`
+#[derive(Debug, Clone)]
+pub struct Shot {
+    pub from: Vector2,
+    pub to: Vector2,
+    pub bullet: String,
+}
+
+/// A splash going off, shown by `spawn_explosions`
+#[derive(Debug, Clone, Copy)]
+pub struct Explosion {
+    pub pos: Vector2,
+    pub radius: f32,
+}
+
+pub struct ExplosionNode(pub Polygon2D);
+
+unsafe impl Send for ExplosionNode {}
+unsafe impl Sync for ExplosionNode {}
+
+impl Drop for ExplosionNode {
+    fn drop(&mut self) {
+        unsafe { self.0.queue_free() };
+    }
+}
+
+/// How close a hostile has to be before an attack moving unit engages it
+pub struct AttackRange(pub f32);
+
+/// Travelling to `dest` while engaging any hostiles on the way.
+/// `engaged` is set while the unit has stopped to fight.
+#[derive(Debug, Clone, Copy)]
+pub struct AttackMoving {
+    pub dest: Destination,
+    pub engaged: bool,
+}
+
+impl AttackMoving {
+    pub fn new(dest: Destination) -> Self {
+        Self { dest, engaged: false }
+    }
+}
 
 // -----------------------------------------------------------------------------
 //     - Systems -
@@ -42,11 +353,21 @@ struct Cooldown(f32);
 pub fn target_unit() -> Box<dyn Schedulable> {
     SystemBuilder::new("target unit")
         .read_resource::<MousePos>()
+        .read_resource::<FogOfWar>()
         .write_resource::<MouseButton>()
-        .with_query(<Read<UnitRect>>::query().filter(tag::<Selected>()))
+        .write_component::<Orders>()
+        .read_component::<UnitPos>()
+        .read_component::<Faction>()
+        .read_component::<Destination>()
+        .read_component::<Target>()
+        .read_component::<AttackMoving>()
+        .read_component::<Holding>()
+        .read_component::<Harvest>()
+        .read_component::<Repair>()
+        .with_query(<Read<UnitRect>>::query().filter(tag::<Selected>() & !component::<Building>()))
         .with_query(<Read<UnitRect>>::query().filter(!tag::<Selected>()))
-        .build(|cmd, world, (mouse_pos, mouse_btn), (query, target_query)| {
-            if !mouse_btn.button_pressed(1) {
+        .build(|cmd, world, (mouse_pos, fog, mouse_btn), (query, target_query)| {
+            if !mouse_btn.button_pressed(2) {
                 return
             }
 
@@ -54,88 +375,306 @@ pub fn target_unit() -> Box<dyn Schedulable> {
                 .iter_entities(world)
                 .map(|(ent, _)| ent).collect::<Vec<_>>();
 
-            let attacker = match attackers.first() {
-                None => return,
-                Some(a) => a,
+            if attackers.is_empty() {
+                return
+            }
+
+            // Can't target what the player can't see
+            let hidden = |entity| {
+                match (world.get_component::<UnitPos>(entity), world.get_component::<Faction>(entity)) {
+                    (Some(pos), Some(faction)) => {
+                        Faction::PLAYER.hostile_to(&faction) && !fog.is_visible(Faction::PLAYER, pos.0)
+                    }
+                    _ => false,
+                }
             };
 
-            for (target_entity, rect) in target_query.iter_entities(world) {
-                if rect.0.contains(mouse_pos.global().to_point()) {
-                    // Have our target
-                    cmd.add_component(*attacker, Target(target_entity));
-                    mouse_btn.consume();
-                    return
+            let target_entity = target_query
+                .iter_entities(world)
+                .find(|(ent, rect)| rect.0.contains(mouse_pos.global().to_point()) && !hidden(*ent))
+                .map(|(ent, _)| ent);
+
+            // Have our target
+            if let Some(target_entity) = target_entity {
+                for &attacker in &attackers {
+                    if mouse_btn.shift() {
+                        if let Some(mut orders) = world.get_component_mut::<Orders>(attacker) {
+                            orders.push(Order::Attack(target_entity));
+                        }
+                    } else {
+                        clear_current_order(cmd, world, attacker);
+                        if let Some(mut orders) = world.get_component_mut::<Orders>(attacker) {
+                            orders.0.clear();
+                        }
+                        cmd.add_component(attacker, Target(target_entity));
+                    }
                 }
+                mouse_btn.consume();
             }
         })
 }
 
+pub fn attack_move() -> Box<dyn Schedulable> {
+    SystemBuilder::new("attack move")
+        .read_component::<Destination>()
+        .read_component::<MaxHitpoints>()
+        .read_component::<AttackRange>()
+        .read_component::<Weapons>()
+        .read_component::<Target>()
+        .read_component::<Faction>()
+        .read_component::<TargetWeights>()
+        .with_query(<(Read<UnitPos>, Read<Faction>, Read<AttackRange>, Read<AttackMoving>)>::query()
+            .filter(!component::<Target>()))
+        .with_query(<(Read<UnitPos>, Read<Faction>, Read<Hitpoints>)>::query())
+        .build(|cmd, world, _, (movers, hostiles)| {
+            let hostiles = hostiles
+                .iter_entities(world)
+                .map(|(ent, (pos, faction, hp))| Candidate::new(world, ent, pos.0, *faction, &hp))
+                .collect::<Vec<_>>();
+
+            for (entity, (pos, faction, range, attack_move)) in movers.iter_entities(world) {
+                let target = choose_target(cmd, world, entity, pos.0, *faction, range.0, &hostiles);
+                let travelling = world.get_component::<Destination>(entity).is_some();
+
+                match target {
+                    // Stop and fight
+                    Some(target) => {
+                        if travelling {
+                            cmd.remove_component::<Destination>(entity);
+                        }
+                        cmd.add_component(entity, Target(target));
+                        cmd.add_component(entity, AttackMoving { engaged: true, ..*attack_move });
+                    }
+                    None if travelling => {}
+                    // Done fighting, carry on
+                    None if attack_move.engaged => {
+                        cmd.add_component(entity, attack_move.dest);
+                        cmd.add_component(entity, AttackMoving::new(attack_move.dest));
+                    }
+                    // Arrived
+                    None => cmd.remove_component::<AttackMoving>(entity),
+                }
+            }
+        })
+}
+
+pub fn hold_position() -> Box<dyn Schedulable> {
+    SystemBuilder::new("hold position")
+        .read_component::<UnitPos>()
+        .read_component::<MaxHitpoints>()
+        .read_component::<AttackRange>()
+        .read_component::<Weapons>()
+        .read_component::<Target>()
+        .read_component::<Faction>()
+        .read_component::<TargetWeights>()
+        .with_query(<(Read<UnitPos>, Read<Faction>, Read<AttackRange>, Read<Target>)>::query()
+            .filter(component::<Holding>()))
+        .with_query(<(Read<UnitPos>, Read<Faction>, Read<AttackRange>)>::query()
+            .filter(component::<Holding>() & !component::<Target>()))
+        .with_query(<(Read<UnitPos>, Read<Faction>, Read<Hitpoints>)>::query())
+        .build(|cmd, world, _, (attacking, idle, hostiles)| {
+            // Holding units never chase, drop targets that leave the range
+            for (entity, (pos, _, range, target)) in attacking.iter_entities(world) {
+                let in_range = world
+                    .get_component::<UnitPos>(target.0)
+                    .map(|target_pos| (target_pos.0 - pos.0).length() <= range.0)
+                    .unwrap_or(false);
+
+                if !in_range {
+                    cmd.remove_component::<Target>(entity);
+                }
+            }
+
+            let hostiles = hostiles
+                .iter_entities(world)
+                .map(|(ent, (pos, faction, hp))| Candidate::new(world, ent, pos.0, *faction, &hp))
+                .collect::<Vec<_>>();
+
+            for (entity, (pos, faction, range)) in idle.iter_entities(world) {
+                if let Some(target) = choose_target(cmd, world, entity, pos.0, *faction, range.0, &hostiles) {
+                    cmd.add_component(entity, Target(target));
+                }
+            }
+        })
+}
+
+/// Fire every weapon that's off cooldown. Weapons shoot the unit's target
+/// when they're able to hit it, otherwise the closest hostile in range they
+/// can hit. Units close in on targets out of range unless they're holding.
 pub fn attack_targets() -> Box<dyn Schedulable> {
     SystemBuilder::new("attack targets")
+        .read_resource::<StatusEffects>()
         .write_component::<Hitpoints>()
-        .with_query(<Write<Target>>::query().filter(!component::<Cooldown>()))
-        .build(|cmd, world, _, query| {
-            for (entity, target) in query.iter_entities_mut(world) {
+        .write_component::<Effects>()
+        .write_component::<Cooldowns>()
+        .read_component::<UnitPos>()
+        .read_component::<Faction>()
+        .read_component::<Building>()
+        .read_component::<Flying>()
+        .read_component::<Destination>()
+        .read_component::<Holding>()
+        .write_component::<Attackers>()
+        .read_component::<MaxHitpoints>()
+        .with_query(<(Read<Target>, Read<Weapons>)>::query().filter(!component::<Stunned>()))
+        .with_query(<(Read<UnitPos>, Read<Faction>)>::query().filter(component::<Hitpoints>()))
+        .build(|cmd, world, status_effects, (query, units)| {
+            let units = units
+                .iter_entities(world)
+                .map(|(ent, (pos, faction))| (ent, pos.0, *faction))
+                .collect::<Vec<_>>();
+            let positions = units.iter().map(|(ent, pos, _)| (*ent, *pos)).collect::<Vec<_>>();
+            let alive = |entity| world.get_component::<Hitpoints>(entity).map(|hp| hp.0 > 0).unwrap_or(false);
+
+            for (entity, (target, weapons)) in query.iter_entities(world) {
                 let target_ent = target.0;
-                match world.get_component_mut::<Hitpoints>(target_ent) {
-                    None => { /* how can there be a unit without hitpoints? */ }
-                    Some(mut hp) => {
-                        cmd.add_tag(entity, Firing);
-                        cmd.add_component(entity, Cooldown(COOLDOWN));
-                        hp.0 -= 1;
-
-                        if hp.0 <= 0 {
-                            cmd.remove_component::<Target>(entity);
-                            cmd.delete(target_ent);
+
+                // The target is gone, or killed by someone else this frame
+                if !alive(target_ent) {
+                    cmd.remove_component::<Target>(entity);
+                    continue;
+                }
+
+                let pos = world.get_component::<UnitPos>(entity).map(|pos| pos.0);
+                let target_pos = world.get_component::<UnitPos>(target_ent).map(|pos| pos.0);
+                let faction = world.get_component::<Faction>(entity).map(|f| *f);
+                let kind = target_kind(world, target_ent);
+                let attack_speed = world
+                    .get_component::<Effects>(entity)
+                    .map(|effects| effects.attack_speed())
+                    .unwrap_or(1.);
+
+                let distance = match (pos, target_pos) {
+                    (Some(pos), Some(target_pos)) => (target_pos - pos).length(),
+                    _ => 0.,
+                };
+
+                // Longest reach of the weapons able to hit the target at all
+                let reach = weapons
+                    .0
+                    .iter()
+                    .filter(|weapon| weapon.targets.allows(kind))
+                    .map(|weapon| weapon.range)
+                    .max_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+                let mut started = Vec::new();
+                for weapon in &weapons.0 {
+                    if !cooldown_ready(world, entity, &weapon.name) {
+                        continue;
+                    }
+
+                    let victim = if weapon.targets.allows(kind) && distance <= weapon.range {
+                        Some((target_ent, target_pos))
+                    } else {
+                        pos.and_then(|pos| {
+                            units
+                                .iter()
+                                .filter(|(ent, _, other)| {
+                                    faction.map(|f| f.hostile_to(other)).unwrap_or(false)
+                                        && weapon.targets.allows(target_kind(world, *ent))
+                                        && alive(*ent)
+                                })
+                                .map(|(ent, other_pos, _)| (*ent, *other_pos, (*other_pos - pos).length()))
+                                .filter(|(_, _, distance)| *distance <= weapon.range)
+                                .min_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal))
+                                .map(|(ent, other_pos, _)| (ent, Some(other_pos)))
+                        })
+                    };
+
+                    let (victim, impact) = match victim {
+                        Some(victim) => victim,
+                        None => continue,
+                    };
+
+                    started.push((weapon.name.as_str(), weapon.cooldown / attack_speed.max(0.01)));
+
+                    if let (Some(from), Some(to)) = (pos, impact) {
+                        cmd.insert((), vec![(Shot { from, to, bullet: weapon.bullet.clone() },)]);
+                    }
+
+                    let killed = deal_damage(cmd, world, victim, weapon.damage, Some(entity));
+                    if killed && victim == target_ent {
+                        cmd.remove_component::<Target>(entity);
+                    }
+
+                    let effect = weapon.effect.as_ref().and_then(|name| status_effects.get(name).map(|status| (name, status)));
+                    if let (false, Some((name, status))) = (killed, effect) {
+                        add_effect(cmd, world, victim, name, status, Some(entity));
+                    }
+
+                    let (splash, impact) = match (weapon.splash, impact) {
+                        (Some(splash), Some(impact)) => (splash, impact),
+                        _ => continue,
+                    };
+
+                    for (other, distance) in within_radius(&positions, impact, splash.radius) {
+                        let friendly = faction.is_some() && world.get_component::<Faction>(other).map(|f| *f) == faction;
+                        if other == victim || other == entity || (friendly && !splash.friendly_fire) {
+                            continue;
+                        }
+
+                        deal_damage(cmd, world, other, splash.damage_at(weapon.damage, distance), Some(entity));
+                    }
+
+                    cmd.insert((), vec![(Explosion { pos: impact, radius: splash.radius },)]);
+                }
+
+                if !started.is_empty() {
+                    start_cooldowns(cmd, world, entity, &started);
+                }
+
+                match (reach, pos, target_pos) {
+                    // Nothing the unit carries can hit it
+                    (None, _, _) => cmd.remove_component::<Target>(entity),
+                    // Close in, stopping a bit inside the range
+                    (Some(reach), Some(pos), Some(target_pos)) if distance > reach => {
+                        if world.get_component::<Holding>(entity).is_none() {
+                            let dir = (pos - target_pos).normalize();
+                            move_to(cmd, world, entity, target_pos + dir * reach * 0.8);
                         }
                     }
+                    _ => {}
                 }
             }
         })
 }
 
-pub fn cooldown_units() -> Box<dyn Schedulable> {
-    SystemBuilder::new("cooldown")
+/// Tick down every `T` and remove the ones that ran out
+pub fn count_down<T: Countdown>(name: &'static str) -> Box<dyn Schedulable> {
+    SystemBuilder::new(name)
         .read_resource::<Delta>()
-        .with_query(<Write<Cooldown>>::query())
+        .with_query(<Write<T>>::query())
         .build(|cmd, world, delta, query| {
-
-            for (entity, mut cooldown) in query.iter_entities_mut(world) {
-                cooldown.0 -= delta.0;
-
-                if cooldown.0 <= 0. {
-                    cmd.remove_component::<Cooldown>(entity);
+            for (entity, mut countdown) in query.iter_entities_mut(world) {
+                if countdown.tick(delta.0) {
+                    cmd.remove_component::<T>(entity);
                 }
             }
         })
 }
 
+pub fn cooldown_units() -> Box<dyn Schedulable> {
+    count_down::<Cooldowns>("cooldown")
+}
+
 pub fn spawn_bullets() -> Box<dyn Runnable> {
     SystemBuilder::new("spawn bullets")
         .write_resource::<WorldNode>()
-        .read_component::<UnitPos>()
-        .with_query(<(Read<UnitPos>, Read<Target>)>::query().filter(tag::<Firing>()))
+        .with_query(<Read<Shot>>::query())
         .build_thread_local(|cmd, world, world_node, query| {
-            for (entity, (attacker_pos, target)) in query.iter_entities(world) {
-                let target_pos = match world.get_component::<UnitPos>(target.0) {
-                    None => continue,
-                    Some(pos) => pos
-                };
+            for (entity, shot) in query.iter_entities(world) {
+                cmd.delete(entity);
 
                 // Create bullet
-                let mut bullet_tex = spawner::create_bullet(2);
+                let mut bullet_tex = spawner::create_bullet(&shot.bullet);
 
                 // Add bullet to scene tree
                 unsafe { world_node.0.add_child(Some(bullet_tex.to_node()), false) };
 
                 // Position and scale bullet
-            
-                cmd.remove_tag::<Firing>(entity);
-
                 unsafe {
-                    bullet_tex.set_global_position(attacker_pos.0, false);
-                    let direction = (target_pos.0 - attacker_pos.0).normalize();
-                    let distance = (target_pos.0 - attacker_pos.0).length();
+                    bullet_tex.set_global_position(shot.from, false);
+                    let direction = (shot.to - shot.from).normalize();
+                    let distance = (shot.to - shot.from).length();
 
                     let scale = Vector2::new(distance, 1.);
                     let rot = direction.y.atan2(direction.x);
@@ -152,6 +691,60 @@ pub fn spawn_bullets() -> Box<dyn Runnable> {
         })
 }
 
+pub fn spawn_explosions() -> Box<dyn Runnable> {
+    SystemBuilder::new("spawn explosions")
+        .write_resource::<WorldNode>()
+        .with_query(<Read<Explosion>>::query())
+        .build_thread_local(|cmd, world, world_node, query| {
+            for (entity, explosion) in query.iter_entities(world) {
+                let mut node = spawner::create_explosion(explosion.radius);
+                unsafe {
+                    world_node.add_child(node.to_node());
+                    node.set_global_position(explosion.pos);
+                }
+
+                cmd.delete(entity);
+                cmd.insert((), vec![(ExplosionNode(node),)]);
+            }
+        })
+}
+
+pub fn fade_explosions() -> Box<dyn Runnable> {
+    SystemBuilder::new("fade explosions")
+        .read_resource::<Delta>()
+        .with_query(<Write<ExplosionNode>>::query())
+        .build_thread_local(|cmd, world, delta, query| {
+            for (entity, mut explosion) in query.iter_entities_mut(world) {
+                unsafe {
+                    let mut modulate = explosion.0.get_modulate();
+                    modulate.a -= delta.0 * 2.;
+                    explosion.0.set_modulate(modulate);
+
+                    if modulate.a <= 0. {
+                        cmd.delete(entity);
+                    }
+                }
+            }
+        })
+}
+
+/// Drop shots and explosions when there's nothing to draw them, e.g. when
+/// running headless
+pub fn discard_shots() -> Box<dyn Schedulable> {
+    SystemBuilder::new("discard shots")
+        .with_query(<Read<Shot>>::query())
+        .with_query(<Read<Explosion>>::query())
+        .build(|cmd, world, _, (shots, explosions)| {
+            for (entity, _) in shots.iter_entities(world) {
+                cmd.delete(entity);
+            }
+
+            for (entity, _) in explosions.iter_entities(world) {
+                cmd.delete(entity);
+            }
+        })
+}
+
 pub fn despawn_bullets() -> Box<dyn Runnable> {
     SystemBuilder::new("despawn bullets")
         .read_resource::<Delta>()
@@ -187,7 +780,8 @@ pub mod tests {
         let mut mouse_pos = MousePos::zero();
         mouse_pos.set_global(target_pos);
         resources.insert(mouse_pos);
-        resources.insert(MouseButton::Mouse { pressed: true, button_index: 1 });
+        resources.insert(MouseButton::Mouse { pressed: true, button_index: 2, shift: false });
+        resources.insert(FogOfWar::new(Vector2::zero(), 10., 20, 20));
 
         let entity = world.insert((Selected,), vec![(
                 UnitRect(Rect2::new(Vector2::zero().to_point(), Size2::new(10., 10.,))),
@@ -207,21 +801,58 @@ pub mod tests {
         assert_gd!(world.get_component::<Target>(entity).is_some())
     }
 
+    // Hostiles hidden by the fog of war can't be targeted
+    pub fn test_target_hidden_unit() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        let target_pos = Vector2::new(100., 100.);
+        let mut mouse_pos = MousePos::zero();
+        mouse_pos.set_global(target_pos);
+        resources.insert(mouse_pos);
+        resources.insert(MouseButton::Mouse { pressed: true, button_index: 2, shift: false });
+        resources.insert(FogOfWar::new(Vector2::zero(), 10., 20, 20));
+
+        let entity = world.insert((Selected,), vec![(
+                UnitRect(Rect2::new(Vector2::zero().to_point(), Size2::new(10., 10.,))),
+        ),])[0];
+
+        world.insert((), vec![(
+                UnitRect(Rect2::new(target_pos.to_point(), Size2::new(10., 10.,))),
+                UnitPos(target_pos),
+                Faction(1),
+        ),]);
+
+        let mut sched = Schedule::builder()
+            .add_system(target_unit())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Target>(entity).is_none());
+
+        // Once the player can see it
+        resources.get_mut::<FogOfWar>().map(|mut fog| fog.reveal(Faction::PLAYER, target_pos, 20.));
+        resources.insert(MouseButton::Mouse { pressed: true, button_index: 2, shift: false });
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Target>(entity).is_some())
+    }
+
     pub fn test_attack_target() -> bool {
         let mut world = Universe::new().create_world();
         let mut resources = Resources::default();
+        resources.insert(StatusEffects::new());
         let target_pos = Vector2::new(100., 100.);
         let mut mouse_pos = MousePos::zero();
         mouse_pos.set_global(target_pos);
         resources.insert(mouse_pos);
-        resources.insert(MouseButton::Mouse { pressed: true, button_index: 1 });
+        resources.insert(MouseButton::Mouse { pressed: true, button_index: 1, shift: false });
 
         let target_entity = world.insert((), vec![(
                 Hitpoints(10),
         ),])[0];
 
         let entity = world.insert((), vec![(
-                Target(target_entity), Hitpoints(10),
+                Target(target_entity), Hitpoints(10), Weapons(vec![Weapon::rifle()]),
         ),])[0];
 
         let mut sched = Schedule::builder()
@@ -234,5 +865,172 @@ pub mod tests {
         let hitpoints = world.get_component::<Hitpoints>(target_entity).unwrap();
         assert_gd!(hitpoints.0 == 9)
     }
-}
 
+    // Splash hurts hostiles around the target less the further out they are
+    pub fn test_splash_damage() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(StatusEffects::new());
+
+        let target = world.insert((), vec![(UnitPos(Vector2::new(100., 0.)), Faction(1), Hitpoints(10))])[0];
+        let units = world.insert((), vec![
+            (UnitPos(Vector2::new(110., 0.)), Faction(1), Hitpoints(10)),
+            (UnitPos(Vector2::new(200., 0.)), Faction(1), Hitpoints(10)),
+            (UnitPos(Vector2::new(100., 10.)), Faction(0), Hitpoints(10)),
+        ]).to_vec();
+
+        let attacker = world.insert((), vec![(
+            UnitPos(Vector2::zero()),
+            Faction(0),
+            Hitpoints(10),
+            Target(target),
+            Weapons(vec![Weapon {
+                damage: 4,
+                splash: Some(Splash { radius: 20., falloff: 0.5, friendly_fire: false }),
+                ..Weapon::rifle()
+            }]),
+        )])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(attack_targets())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+
+        let hp = |ent| world.get_component::<Hitpoints>(ent).unwrap().0;
+        assert_gd!(hp(target) == 6);
+        // Halfway out loses a quarter of the damage
+        assert_gd!(hp(units[0]) == 7);
+        assert_gd!(hp(units[1]) == 10);
+        assert_gd!(hp(units[2]) == 10);
+        assert_gd!(hp(attacker) == 10);
+
+        let mut explosions = <Read<Explosion>>::query();
+        assert_gd!(explosions.iter(&world).count() == 1)
+    }
+
+    // Each weapon fires on its own cooldown, at whatever it's able to hit
+    pub fn test_weapons() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(StatusEffects::new());
+        resources.insert(Delta(0.5));
+
+        let tank = world.insert((), vec![(UnitPos(Vector2::new(50., 0.)), Faction(1), Hitpoints(10))])[0];
+        let drone = world.insert((), vec![(UnitPos(Vector2::new(60., 0.)), Faction(1), Hitpoints(10), Flying)])[0];
+
+        let cannon = Weapon {
+            name: "cannon".into(),
+            damage: 3,
+            range: 100.,
+            cooldown: 2.,
+            bullet: "res://bullets/Ray1.tscn".into(),
+            targets: TargetFilter::SURFACE,
+            splash: None,
+            effect: None,
+        };
+        let flak = Weapon {
+            name: "flak".into(),
+            cooldown: 0.5,
+            targets: TargetFilter::AIR,
+            ..cannon.clone()
+        };
+
+        world.insert((), vec![(
+            UnitPos(Vector2::zero()),
+            Faction(0),
+            Target(tank),
+            Weapons(vec![cannon, flak]),
+        )]);
+
+        let mut sched = Schedule::builder()
+            .add_system(attack_targets())
+            .flush()
+            .add_system(cooldown_units())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+        let mut shots = <Read<Shot>>::query();
+        assert_gd!(shots.iter(&world).count() == 2);
+
+        sched.execute(&mut world, &mut resources);
+
+        // The cannon is still reloading, the flak picked its own target
+        let hp = |ent| world.get_component::<Hitpoints>(ent).unwrap().0;
+        assert_gd!(hp(tank) == 7);
+        assert_gd!(hp(drone) == 4)
+    }
+
+    // Attack moving units should stop to fight hostiles and then carry on
+    pub fn test_attack_move() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        let dest = Destination::new(Vector2::new(200., 0.));
+
+        let entity = world.insert((), vec![(
+                UnitPos(Vector2::zero()), Faction(0), AttackRange(50.),
+                AttackMoving::new(dest), dest,
+        ),])[0];
+
+        let hostile = world.insert((), vec![(
+                UnitPos(Vector2::new(40., 0.)), Faction(1), Hitpoints(10),
+        ),])[0];
+
+        // A friendly unit in range should be ignored
+        world.insert((), vec![(
+                UnitPos(Vector2::new(10., 0.)), Faction(0), Hitpoints(10),
+        ),]);
+
+        let mut sched = Schedule::builder()
+            .add_system(attack_move())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+
+        assert_gd!(world.get_component::<Target>(entity).map(|t| t.0) == Some(hostile));
+        assert_gd!(world.get_component::<Destination>(entity).is_none());
+
+        // The hostile is killed
+        world.delete(hostile);
+        let _ = world.remove_component::<Target>(entity);
+        sched.execute(&mut world, &mut resources);
+
+        assert_gd!(world.get_component::<Destination>(entity).map(|d| d.pos) == Some(dest.pos));
+        assert_gd!(world.get_component::<AttackMoving>(entity).map(|a| a.engaged) == Some(false))
+    }
+
+    // Holding units should only attack hostiles in range
+    pub fn test_hold_position() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+
+        let entity = world.insert((), vec![(
+                UnitPos(Vector2::zero()), Faction(0), AttackRange(50.), Holding,
+        ),])[0];
+
+        let hostile = world.insert((), vec![(
+                UnitPos(Vector2::new(80., 0.)), Faction(1), Hitpoints(10),
+        ),])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(hold_position())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Target>(entity).is_none());
+
+        // Hostile walks into range
+        world.get_component_mut::<UnitPos>(hostile).map(|mut pos| pos.0 = Vector2::new(40., 0.));
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Target>(entity).map(|t| t.0) == Some(hostile));
+
+        // And back out again
+        world.get_component_mut::<UnitPos>(hostile).map(|mut pos| pos.0 = Vector2::new(80., 0.));
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Target>(entity).is_none())
+    }
+}
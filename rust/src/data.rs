@@ -0,0 +1,30 @@
+use gdnative::{godot_error, File};
+use serde::de::DeserializeOwned;
+
+// File::READ
+const READ: i64 = 1;
+
+/// Load a RON file from the Godot project, e.g. `res://data/ai.ron`. Game
+/// data is loaded from `res://data/` once the game world is ready, anything
+/// missing or broken keeps the built-in defaults.
+pub fn load<T: DeserializeOwned>(path: &str) -> Option<T> {
+    let mut file = File::new();
+    if file.open(path.into(), READ).is_err() {
+        godot_error!("failed to open {}", path);
+        return None;
+    }
+
+    let text = file.get_as_text().to_string();
+    file.close();
+    parse(&text)
+}
+
+pub fn parse<T: DeserializeOwned>(text: &str) -> Option<T> {
+    match ron::de::from_str(text) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            godot_error!("failed to parse data: {}", e);
+            None
+        }
+    }
+}
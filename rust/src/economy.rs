@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+
+use gdnative::{Color, Polygon2D, Vector2};
+use legion::prelude::*;
+
+use crate::archetype::Archetypes;
+use crate::buildings::{centered_tile, insert_building, Blueprints, Building};
+use crate::combat::{AttackMoving, Target};
+use crate::gameworld::{Delta, Selected, WorldNode};
+use crate::healing::Repair;
+use crate::input::{MouseButton, MousePos};
+use crate::orders::{Holding, Orders};
+use crate::spawner::{create_marker, insert_unit};
+use crate::steering::ARRIVAL_RADIUS;
+use crate::terrain::Terrain;
+use crate::units::{clear_current_order, Destination, Faction, UnitPos, UnitRect};
+
+/// What every faction starts the game with
+pub const STARTING_MINERALS: u32 = 300;
+pub const MINERALS_PER_NODE: u32 = 500;
+
+/// How close a worker has to be to harvest a node
+const GATHER_RANGE: f32 = 24.;
+/// How close a worker has to be to drop off what it carries
+const DROP_OFF_RANGE: f32 = 40.;
+/// How far a worker looks for another node once its node runs out
+const SEARCH_RANGE: f32 = 200.;
+const NODE_SIZE: f32 = 16.;
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// Minerals that workers can harvest
+#[derive(Debug)]
+pub struct ResourceNode {
+    pub remaining: u32,
+}
+
+/// Where workers of the same faction bring what they harvested
+#[derive(Debug, Clone, Copy)]
+pub struct DropOff;
+
+/// Makes a unit a worker
+#[derive(Debug, Clone, Copy)]
+pub struct Harvester {
+    /// How much the worker carries per trip
+    pub capacity: u32,
+    /// Minerals harvested per second
+    pub rate: f32,
+}
+
+/// Harvesting `node` and bringing it back to the nearest drop-off
+#[derive(Debug, Clone, Copy)]
+pub struct Harvest {
+    pub node: Entity,
+    pub carrying: u32,
+    progress: f32,
+}
+
+impl Harvest {
+    pub fn new(node: Entity) -> Self {
+        Self { node, carrying: 0, progress: 0. }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Minerals per faction
+pub struct Stockpiles {
+    starting: u32,
+    amounts: HashMap<u32, u32>,
+}
+
+impl Stockpiles {
+    pub fn new(starting: u32) -> Self {
+        Self { starting, amounts: HashMap::new() }
+    }
+
+    pub fn amount(&self, faction: Faction) -> u32 {
+        self.amounts.get(&faction.0).copied().unwrap_or(self.starting)
+    }
+
+    pub fn add(&mut self, faction: Faction, amount: u32) {
+        let total = self.amount(faction) + amount;
+        self.amounts.insert(faction.0, total);
+    }
+
+    /// Take `cost` from the faction's stockpile, or nothing at all if there
+    /// isn't enough
+    pub fn spend(&mut self, faction: Faction, cost: u32) -> bool {
+        let amount = self.amount(faction);
+        if amount < cost {
+            return false;
+        }
+        self.amounts.insert(faction.0, amount - cost);
+        true
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Spawning -
+// -----------------------------------------------------------------------------
+pub fn insert_resource_node(cmd: &mut CommandBuffer, pos: Vector2, amount: u32) -> Entity {
+    cmd.insert(
+        (),
+        vec![(
+            UnitPos(pos),
+            UnitRect::new(pos, NODE_SIZE, NODE_SIZE),
+            ResourceNode { remaining: amount },
+        )],
+    )[0]
+}
+
+/// A base centered on `pos` with a mineral field next to it and a few
+/// workers already harvesting
+pub fn insert_base(
+    cmd: &mut CommandBuffer,
+    archetypes: &Archetypes,
+    blueprints: &Blueprints,
+    terrain: &Terrain,
+    pos: Vector2,
+    faction: Faction,
+) {
+    if let Some(base) = blueprints.get("base") {
+        if let Some(tile) = centered_tile(base, terrain, pos) {
+            insert_building(cmd, base, terrain, tile, faction);
+        }
+    }
+
+    let nodes = (0..4)
+        .map(|i| {
+            let offset = Vector2::new(-24. + i as f32 * 16., -64.);
+            insert_resource_node(cmd, pos + offset, MINERALS_PER_NODE)
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(worker) = archetypes.get("worker") {
+        for (i, node) in nodes.iter().enumerate() {
+            let offset = Vector2::new(-24. + i as f32 * 16., 40.);
+            let entity = insert_unit(cmd, worker, pos + offset, faction);
+            cmd.add_component(entity, Harvest::new(*node));
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+/// Right clicking a resource node sends the selected workers to harvest it
+pub fn gather_resources() -> Box<dyn Schedulable> {
+    SystemBuilder::new("gather resources")
+        .write_resource::<MouseButton>()
+        .read_resource::<MousePos>()
+        .write_component::<Orders>()
+        .read_component::<Destination>()
+        .read_component::<Target>()
+        .read_component::<AttackMoving>()
+        .read_component::<Holding>()
+        .read_component::<Harvest>()
+        .read_component::<Repair>()
+        .with_query(<Read<UnitRect>>::query().filter(component::<ResourceNode>()))
+        .with_query(<Read<Harvester>>::query().filter(tag::<Selected>()))
+        .build(|cmd, world, (mouse_btn, mouse_pos), (nodes, workers)| {
+            if !mouse_btn.button_pressed(2) {
+                return;
+            }
+
+            let node = nodes
+                .iter_entities(world)
+                .find(|(_, rect)| rect.0.contains(mouse_pos.global().to_point()))
+                .map(|(ent, _)| ent);
+
+            let node = match node {
+                Some(node) => node,
+                None => return,
+            };
+
+            let workers = workers
+                .iter_entities(world)
+                .map(|(ent, _)| ent)
+                .collect::<Vec<_>>();
+
+            if workers.is_empty() {
+                return;
+            }
+
+            for &worker in &workers {
+                clear_current_order(cmd, world, worker);
+                if let Some(mut orders) = world.get_component_mut::<Orders>(worker) {
+                    orders.0.clear();
+                }
+                cmd.add_component(worker, Harvest::new(node));
+            }
+
+            mouse_btn.consume();
+        })
+}
+
+/// Workers go back and forth between their node and the nearest drop-off
+pub fn harvest() -> Box<dyn Schedulable> {
+    SystemBuilder::new("harvest")
+        .read_resource::<Delta>()
+        .write_resource::<Stockpiles>()
+        .write_component::<Harvest>()
+        .write_component::<ResourceNode>()
+        .read_component::<Destination>()
+        .with_query(<(Read<UnitPos>, Read<Faction>, Read<Harvester>, Read<Harvest>)>::query())
+        .with_query(<Read<UnitPos>>::query().filter(component::<ResourceNode>()))
+        .with_query(<(Read<UnitPos>, Read<Faction>)>::query().filter(component::<DropOff>()))
+        .build(|cmd, world, (delta, stockpiles), (workers, nodes, drop_offs)| {
+            let workers = workers
+                .iter_entities(world)
+                .map(|(ent, (pos, faction, harvester, harvest))| (ent, pos.0, *faction, *harvester, *harvest))
+                .collect::<Vec<_>>();
+
+            let nodes = nodes
+                .iter_entities(world)
+                .map(|(ent, pos)| (ent, pos.0))
+                .collect::<Vec<_>>();
+
+            let drop_offs = drop_offs
+                .iter(world)
+                .map(|(pos, faction)| (pos.0, *faction))
+                .collect::<Vec<_>>();
+
+            for (entity, pos, faction, harvester, mut harvest) in workers {
+                let node_pos = nodes.iter().find(|(ent, _)| *ent == harvest.node).map(|(_, pos)| *pos);
+                let full = harvest.carrying >= harvester.capacity;
+
+                match node_pos {
+                    // Harvest
+                    Some(node_pos) if !full => {
+                        if (node_pos - pos).length() > GATHER_RANGE {
+                            move_to(cmd, world, entity, node_pos);
+                        } else if let Some(mut node) = world.get_component_mut::<ResourceNode>(harvest.node) {
+                            harvest.progress += delta.0 * harvester.rate;
+                            while harvest.progress >= 1. && node.remaining > 0 && harvest.carrying < harvester.capacity {
+                                harvest.progress -= 1.;
+                                harvest.carrying += 1;
+                                node.remaining -= 1;
+                            }
+
+                            if node.remaining == 0 {
+                                cmd.delete(harvest.node);
+                            }
+                        }
+                    }
+                    // Nothing left here, find another node close by
+                    None if harvest.carrying == 0 => {
+                        let next = nodes
+                            .iter()
+                            .filter(|(ent, _)| *ent != harvest.node)
+                            .map(|(ent, node_pos)| (*ent, (*node_pos - pos).length()))
+                            .filter(|(_, distance)| *distance <= SEARCH_RANGE)
+                            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+
+                        match next {
+                            Some((node, _)) => harvest = Harvest::new(node),
+                            None => {
+                                cmd.remove_component::<Harvest>(entity);
+                                continue;
+                            }
+                        }
+                    }
+                    // Bring it home
+                    _ => {
+                        let drop_off = drop_offs
+                            .iter()
+                            .filter(|(_, other)| *other == faction)
+                            .map(|(drop_off_pos, _)| *drop_off_pos)
+                            .min_by(|a, b| {
+                                (*a - pos)
+                                    .length()
+                                    .partial_cmp(&(*b - pos).length())
+                                    .unwrap_or(std::cmp::Ordering::Equal)
+                            });
+
+                        match drop_off {
+                            Some(drop_off) if (drop_off - pos).length() > DROP_OFF_RANGE => {
+                                move_to(cmd, world, entity, drop_off);
+                            }
+                            Some(_) => {
+                                stockpiles.add(faction, harvest.carrying);
+                                harvest.carrying = 0;
+                            }
+                            // Nowhere to go
+                            None => {}
+                        }
+                    }
+                }
+
+                if let Some(mut current) = world.get_component_mut::<Harvest>(entity) {
+                    *current = harvest;
+                }
+            }
+        })
+}
+
+/// Head for `pos` unless the unit is already on its way there
+pub fn move_to(cmd: &mut CommandBuffer, world: &SubWorld, entity: Entity, pos: Vector2) {
+    let heading_there = world
+        .get_component::<Destination>(entity)
+        .map(|dest| (dest.pos - pos).length() <= ARRIVAL_RADIUS)
+        .unwrap_or(false);
+
+    if !heading_there {
+        cmd.add_component(entity, Destination::new(pos));
+    }
+}
+
+/// Coloured rectangles for resource nodes and buildings
+pub fn create_markers() -> Box<dyn Runnable> {
+    SystemBuilder::new("create markers")
+        .write_resource::<WorldNode>()
+        .read_component::<Faction>()
+        .with_query(<Read<UnitRect>>::query()
+            .filter((component::<ResourceNode>() | component::<Building>()) & !component::<Marker>()))
+        .build_thread_local(|cmd, world, world_node, query| {
+            for (entity, rect) in query.iter_entities(world) {
+                let color = match world.get_component::<Faction>(entity) {
+                    None => Color::rgb(0.3, 0.7, 1.),
+                    Some(faction) if *faction == Faction::PLAYER => Color::rgb(0.5, 0.5, 0.5),
+                    Some(_) => Color::rgb(0.6, 0.3, 0.3),
+                };
+
+                let marker = create_marker(rect.0, color);
+                unsafe { world_node.add_child(marker.to_node()) };
+                cmd.add_component(entity, Marker(marker));
+            }
+        })
+}
+
+/// Removes the marker from the scene tree when the entity goes away
+pub struct Marker(pub Polygon2D);
+
+unsafe impl Send for Marker {}
+unsafe impl Sync for Marker {}
+
+impl Drop for Marker {
+    fn drop(&mut self) {
+        unsafe { self.0.queue_free() };
+    }
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use crate::archetype::Archetype;
+    use crate::buildings::Blueprint;
+    use crate::simulation;
+    use super::*;
+
+    // A worker should harvest a node and bring the minerals home
+    pub fn test_harvest() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = simulation::resources();
+        let mut sched = simulation::schedule();
+        resources.insert(Stockpiles::new(0));
+
+        let mut cmd = CommandBuffer::new(&world);
+        let node = insert_resource_node(&mut cmd, Vector2::new(150., 24.), 4);
+        let terrain = simulation::flat_terrain();
+        let base = Blueprint::base();
+        insert_building(&mut cmd, &base, &terrain, terrain.tile(Vector2::zero()).unwrap(), Faction::PLAYER);
+        let worker = insert_unit(&mut cmd, &Archetype::worker(), Vector2::new(80., 24.), Faction::PLAYER);
+        cmd.add_component(worker, Harvest::new(node));
+        cmd.write(&mut world);
+
+        for _ in 0..300 {
+            simulation::step(&mut world, &mut resources, &mut sched, 0.1);
+        }
+
+        // Everything was brought home and the worker is done
+        assert_gd!(resources.get::<Stockpiles>().unwrap().amount(Faction::PLAYER) == 4);
+        assert_gd!(world.get_component::<ResourceNode>(node).is_none());
+        assert_gd!(world.get_component::<Harvest>(worker).is_none())
+    }
+
+    pub fn test_stockpile() -> bool {
+        let mut stockpiles = Stockpiles::new(100);
+        assert_gd!(stockpiles.spend(Faction::PLAYER, 60));
+        assert_gd!(!stockpiles.spend(Faction::PLAYER, 60));
+        assert_gd!(stockpiles.amount(Faction::PLAYER) == 40);
+        stockpiles.add(Faction::PLAYER, 20);
+        assert_gd!(stockpiles.spend(Faction::PLAYER, 60));
+        assert_gd!(stockpiles.amount(Faction(1)) == 100)
+    }
+}
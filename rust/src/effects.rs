@@ -0,0 +1,445 @@
+//! Timed status effects, e.g. slowed down by a weapon or shielded by an ability.
+use std::collections::HashMap;
+
+use legion::prelude::*;
+use serde::Deserialize;
+
+use crate::combat::{Hitpoints, MaxHitpoints};
+use crate::gameworld::Delta;
+use crate::healing::{heal, HealEvents};
+use crate::movement::{ModifierSource, Movement};
+use crate::buildings::Building;
+use crate::units::{Faction, UnitPos};
+use crate::veterancy::{record_death, Attackers};
+
+// -----------------------------------------------------------------------------
+//     - Data -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub enum Effect {
+    /// Multiplies movement speed and attack speed
+    Slow { movement: f32, attack: f32 },
+    /// Can't move or attack
+    Stun,
+    /// Hitpoints lost per second
+    Poison(f32),
+    /// Hitpoints gained per second, up to max hitpoints
+    Heal(f32),
+    /// Damage soaked up before any hitpoints are lost
+    Shield(u32),
+}
+
+/// What happens when an effect is applied to a unit that already has it
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Stacking {
+    /// Start over with the full duration
+    Refresh,
+    /// Every application counts on its own, up to this many at once.
+    /// Past that the one closest to running out starts over.
+    Stack(usize),
+    /// Nothing, until it runs out
+    Ignore,
+}
+
+impl Default for Stacking {
+    fn default() -> Self {
+        Stacking::Refresh
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatusEffect {
+    pub effect: Effect,
+    /// Seconds
+    pub duration: f32,
+    #[serde(default)]
+    pub stacking: Stacking,
+}
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// An effect currently on a unit
+#[derive(Debug, Clone)]
+pub struct ActiveEffect {
+    pub name: String,
+    pub effect: Effect,
+    pub remaining: f32,
+    /// Whoever put it on, credited with poison damage and kills
+    pub source: Option<Entity>,
+    /// Hitpoints owed by poison or heal that don't add up to a whole one yet
+    pending: f32,
+}
+
+/// Every effect on a unit
+#[derive(Debug, Clone, Default)]
+pub struct Effects(pub Vec<ActiveEffect>);
+
+impl Effects {
+    pub fn add(&mut self, name: &str, status: &StatusEffect, source: Option<Entity>) {
+        let active = ActiveEffect {
+            name: name.to_string(),
+            effect: status.effect.clone(),
+            remaining: status.duration,
+            source,
+            pending: 0.,
+        };
+
+        let existing = self.0.iter().position(|e| e.name == name);
+        match status.stacking {
+            Stacking::Refresh => match existing {
+                Some(i) => self.0[i] = active,
+                None => self.0.push(active),
+            },
+            Stacking::Stack(max) => {
+                let stacks = self.0.iter().filter(|e| e.name == name).count();
+                if stacks < max {
+                    self.0.push(active);
+                } else if let Some(oldest) = self
+                    .0
+                    .iter_mut()
+                    .filter(|e| e.name == name)
+                    .min_by(|a, b| a.remaining.partial_cmp(&b.remaining).unwrap_or(std::cmp::Ordering::Equal))
+                {
+                    *oldest = active;
+                }
+            }
+            Stacking::Ignore => {
+                if existing.is_none() {
+                    self.0.push(active);
+                }
+            }
+        }
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.0.iter().any(|e| e.name == name)
+    }
+
+    pub fn stunned(&self) -> bool {
+        self.0.iter().any(|e| e.effect == Effect::Stun)
+    }
+
+    pub fn movement_factor(&self) -> f32 {
+        self.0.iter().fold(1., |factor, e| match e.effect {
+            Effect::Slow { movement, .. } => factor * movement,
+            _ => factor,
+        })
+    }
+
+    pub fn attack_speed(&self) -> f32 {
+        self.0.iter().fold(1., |factor, e| match e.effect {
+            Effect::Slow { attack, .. } => factor * attack,
+            _ => factor,
+        })
+    }
+
+    /// Use up shields on `damage`, returning whatever gets through.
+    /// Shields that break are removed.
+    pub fn absorb(&mut self, mut damage: u32) -> u32 {
+        for e in self.0.iter_mut() {
+            if let Effect::Shield(ref mut amount) = e.effect {
+                let absorbed = damage.min(*amount);
+                *amount -= absorbed;
+                damage -= absorbed;
+            }
+        }
+
+        self.0.retain(|e| e.effect != Effect::Shield(0));
+        damage
+    }
+
+    /// Advance by `delta` seconds and drop whatever ran out, true once
+    /// there's nothing left
+    pub fn tick(&mut self, delta: f32) -> bool {
+        self.0.iter_mut().for_each(|e| e.remaining -= delta);
+        self.0.retain(|e| e.remaining > 0.);
+        self.0.is_empty()
+    }
+}
+
+/// Can't move or attack while this is on
+#[derive(Debug, Clone, Copy)]
+pub struct Stunned;
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Every status effect, by name
+#[derive(Debug, Default, Deserialize)]
+pub struct StatusEffects(HashMap<String, StatusEffect>);
+
+impl StatusEffects {
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&StatusEffect> {
+        self.0.get(name)
+    }
+}
+
+/// Put the effect on a unit, on behalf of `source`. Systems calling this need
+/// to write `Effects`. Anything spawned without `Effects` gets one, but only
+/// the last effect added to it this frame sticks.
+pub fn add_effect(
+    cmd: &mut CommandBuffer,
+    world: &SubWorld,
+    entity: Entity,
+    name: &str,
+    status: &StatusEffect,
+    source: Option<Entity>,
+) {
+    match world.get_component_mut::<Effects>(entity) {
+        Some(mut effects) => effects.add(name, status, source),
+        None => {
+            let mut effects = Effects::default();
+            effects.add(name, status, source);
+            cmd.add_component(entity, effects);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+/// Poison and heal, and keep movement and stuns in line with the effects
+pub fn apply_effects() -> Box<dyn Schedulable> {
+    SystemBuilder::new("apply effects")
+        .read_resource::<Delta>()
+        .write_resource::<HealEvents>()
+        .read_component::<Stunned>()
+        .read_component::<UnitPos>()
+        .write_component::<Attackers>()
+        .read_component::<Faction>()
+        .read_component::<Building>()
+        .with_query(<(Write<Effects>, Write<Hitpoints>, Read<MaxHitpoints>)>::query())
+        .with_query(<(Read<Effects>, Write<Movement>)>::query())
+        .with_query(<Write<Movement>>::query().filter(!component::<Effects>()))
+        .with_query(<Read<Stunned>>::query().filter(!component::<Effects>()))
+        .build(|cmd, world, (delta, events), (hurt, slowed, recovered, unstunned)| {
+            for (entity, (mut effects, mut hp, max_hp)) in hurt.iter_entities_mut(world) {
+                let mut change = 0.;
+                let mut poisoners = Vec::new();
+                for e in effects.0.iter_mut() {
+                    let rate = match e.effect {
+                        Effect::Poison(rate) => -rate,
+                        Effect::Heal(rate) => rate,
+                        _ => continue,
+                    };
+
+                    e.pending += rate * delta.0;
+                    let whole = e.pending.trunc();
+                    e.pending -= whole;
+                    change += whole;
+
+                    if let (Some(source), true) = (e.source, whole < 0.) {
+                        poisoners.push((source, -whole as u32));
+                    }
+                }
+
+                if let Some(mut attackers) = world.get_component_mut::<Attackers>(entity) {
+                    poisoners.iter().for_each(|(source, damage)| attackers.record(*source, *damage));
+                }
+
+                if change < 0. {
+                    hp.0 = hp.0.saturating_sub(-change as u32);
+                    if hp.0 == 0 {
+                        // The last poison to hurt it gets the kill
+                        let killer = poisoners.last().map(|(source, _)| *source);
+                        record_death(cmd, world, entity, killer);
+                    }
+                } else if change > 0. {
+                    let healed = heal(&mut hp, &max_hp, change as u32);
+                    match world.get_component::<UnitPos>(entity) {
+                        Some(pos) if healed > 0 => events.push(pos.0, healed),
+                        _ => {}
+                    }
+                }
+            }
+
+            for (entity, (effects, mut movement)) in slowed.iter_entities_mut(world) {
+                movement.set_modifier(ModifierSource::Slow, effects.movement_factor());
+
+                let stunned = world.get_component::<Stunned>(entity).is_some();
+                if effects.stunned() && !stunned {
+                    cmd.add_component(entity, Stunned);
+                } else if !effects.stunned() && stunned {
+                    cmd.remove_component::<Stunned>(entity);
+                }
+            }
+
+            for mut movement in recovered.iter_mut(world) {
+                movement.clear_modifier(ModifierSource::Slow);
+            }
+
+            for (entity, _) in unstunned.iter_entities(world) {
+                cmd.remove_component::<Stunned>(entity);
+            }
+        })
+}
+
+/// Tick down every effect. Unlike `Cooldowns`, `Effects` is kept once it's
+/// empty.
+pub fn expire_effects() -> Box<dyn Schedulable> {
+    SystemBuilder::new("expire effects")
+        .read_resource::<Delta>()
+        .with_query(<Write<Effects>>::query())
+        .build(|_, world, delta, query| {
+            for mut effects in query.iter_mut(world) {
+                effects.tick(delta.0);
+            }
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use gdnative::Vector2;
+
+    use crate::assert_gd;
+    use crate::combat::{attack_targets, Target, Weapon, Weapons};
+    use crate::data;
+    use crate::veterancy::Death;
+    use super::*;
+
+    fn status_effects() -> StatusEffects {
+        data::parse(r#"{
+            "slow": (effect: Slow(movement: 0.5, attack: 0.5), duration: 2.0),
+            "stun": (effect: Stun, duration: 1.0, stacking: Ignore),
+            "poison": (effect: Poison(2.0), duration: 2.0, stacking: Stack(2)),
+            "shield": (effect: Shield(3), duration: 10.0),
+        }"#).unwrap()
+    }
+
+    // Stacking rules decide what reapplying an effect does
+    pub fn test_effect_stacking() -> bool {
+        let defs = status_effects();
+        let mut effects = Effects::default();
+
+        for _ in 0..3 {
+            effects.add("poison", defs.get("poison").unwrap(), None);
+            effects.add("slow", defs.get("slow").unwrap(), None);
+        }
+        assert_gd!(effects.0.iter().filter(|e| e.name == "poison").count() == 2);
+        assert_gd!(effects.0.iter().filter(|e| e.name == "slow").count() == 1);
+        assert_gd!(effects.movement_factor() == 0.5);
+
+        // Ignored while it's on, so it isn't extended
+        effects.add("stun", defs.get("stun").unwrap(), None);
+        effects.tick(0.5);
+        effects.add("stun", defs.get("stun").unwrap(), None);
+        assert_gd!(effects.stunned());
+        effects.tick(0.6);
+        assert_gd!(!effects.stunned());
+
+        // Shields soak up damage until they break
+        effects.add("shield", defs.get("shield").unwrap(), None);
+        assert_gd!(effects.absorb(2) == 0);
+        assert_gd!(effects.absorb(2) == 1);
+        assert_gd!(!effects.has("shield"));
+
+        // Everything runs out eventually
+        assert_gd!(effects.tick(10.))
+    }
+
+    // Poison ticks damage, stuns stop movement and it all wears off
+    pub fn test_apply_effects() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(Delta(0.5));
+        resources.insert(HealEvents::new());
+        let defs = status_effects();
+
+        let mut effects = Effects::default();
+        effects.add("poison", defs.get("poison").unwrap(), None);
+        effects.add("stun", defs.get("stun").unwrap(), None);
+        effects.add("slow", defs.get("slow").unwrap(), None);
+
+        let unit = world.insert((), vec![(
+            Hitpoints(10),
+            MaxHitpoints(10),
+            Movement::new(100., 400., 600., 12.),
+            effects,
+        )])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(apply_effects())
+            .add_system(expire_effects())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Hitpoints>(unit).unwrap().0 == 9);
+        assert_gd!(world.get_component::<Stunned>(unit).is_some());
+        assert_gd!(world.get_component::<Movement>(unit).unwrap().speed() == 50.);
+
+        for _ in 0..4 {
+            sched.execute(&mut world, &mut resources);
+        }
+
+        // Two seconds of poison, and back to normal
+        assert_gd!(world.get_component::<Hitpoints>(unit).unwrap().0 == 6);
+        assert_gd!(world.get_component::<Effects>(unit).unwrap().0.is_empty());
+        assert_gd!(world.get_component::<Stunned>(unit).is_none());
+        assert_gd!(world.get_component::<Movement>(unit).unwrap().speed() == 100.)
+    }
+
+    // Whoever poisoned a unit gets the damage and the kill
+    pub fn test_poison_kill() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(Delta(0.5));
+        resources.insert(HealEvents::new());
+        let defs = status_effects();
+
+        let poisoner = world.insert((), vec![(UnitPos(Vector2::zero()), Faction(0))])[0];
+        let mut effects = Effects::default();
+        effects.add("poison", defs.get("poison").unwrap(), Some(poisoner));
+        let victim = world.insert((), vec![
+            (Faction(1), Hitpoints(1), MaxHitpoints(10), Attackers::default(), effects),
+        ])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(apply_effects())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Hitpoints>(victim).is_none());
+
+        let deaths = <Read<Death>>::query().iter(&world).map(|d| (*d).clone()).collect::<Vec<_>>();
+        assert_gd!(deaths.len() == 1);
+        assert_gd!(deaths[0].killer == Some(poisoner));
+        assert_gd!(deaths[0].killer_faction == Some(Faction(0)));
+        assert_gd!(deaths[0].contributors == vec![(poisoner, 1)])
+    }
+
+    // Weapons put their effect on whatever they hit, and effects landing on
+    // the same unit in the same frame all stick
+    pub fn test_weapon_effects() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(status_effects());
+
+        let target = world.insert((), vec![
+            (UnitPos(Vector2::new(50., 0.)), Faction(1), Hitpoints(10), Effects::default()),
+        ])[0];
+
+        let weapons = |effect: &str| Weapons(vec![Weapon { effect: Some(effect.into()), ..Weapon::rifle() }]);
+        world.insert((), vec![
+            (UnitPos(Vector2::zero()), Faction(0), Target(target), weapons("slow")),
+            (UnitPos(Vector2::zero()), Faction(0), Target(target), weapons("poison")),
+        ]);
+
+        let mut sched = Schedule::builder()
+            .add_system(attack_targets())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+
+        let effects = world.get_component::<Effects>(target).unwrap();
+        assert_gd!(effects.has("slow"));
+        assert_gd!(effects.has("poison"))
+    }
+}
@@ -0,0 +1,215 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use gdnative::Vector2;
+
+use crate::navigation::{Cell, NavGrid, IMPASSABLE};
+
+const NEIGHBOURS: [(i32, i32); 8] = [
+    (1, 0),
+    (-1, 0),
+    (0, 1),
+    (0, -1),
+    (1, 1),
+    (1, -1),
+    (-1, 1),
+    (-1, -1),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+struct Visit {
+    cost: f32,
+    cell: Cell,
+}
+
+impl Eq for Visit {}
+
+// Reversed so the heap pops the cheapest cell first
+impl Ord for Visit {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Visit {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Direction towards the goal for every cell on the grid
+pub struct FlowField {
+    integration: Vec<f32>,
+    directions: Vec<Vector2>,
+}
+
+impl FlowField {
+    pub fn new(grid: &NavGrid, goal: Cell) -> Self {
+        let integration = integrate(grid, goal);
+        let mut directions = vec![Vector2::zero(); integration.len()];
+
+        for y in 0..grid.height() {
+            for x in 0..grid.width() {
+                let cell = (x, y);
+                let index = match grid.index(cell) {
+                    Some(i) => i,
+                    None => continue,
+                };
+
+                let mut best = integration[index];
+                for (dx, dy) in NEIGHBOURS.iter() {
+                    let next = (x + dx, y + dy);
+
+                    // Don't cut corners around blocked cells
+                    if *dx != 0 && *dy != 0
+                        && (!grid.passable((x + dx, y)) || !grid.passable((x, y + dy)))
+                    {
+                        continue;
+                    }
+
+                    if let Some(next_index) = grid.index(next) {
+                        if integration[next_index] < best {
+                            best = integration[next_index];
+                            directions[index] = Vector2::new(*dx as f32, *dy as f32).normalize();
+                        }
+                    }
+                }
+            }
+        }
+
+        Self {
+            integration,
+            directions,
+        }
+    }
+
+    /// Direction to travel in from `pos`, `None` outside the grid, at the
+    /// goal, or when the goal can't be reached.
+    pub fn direction(&self, grid: &NavGrid, pos: Vector2) -> Option<Vector2> {
+        let index = grid.cell(pos).and_then(|cell| grid.index(cell))?;
+        if self.integration[index] == IMPASSABLE {
+            return None;
+        }
+
+        let direction = self.directions[index];
+        if direction == Vector2::zero() {
+            None
+        } else {
+            Some(direction)
+        }
+    }
+}
+
+/// Cost of reaching the goal from every cell
+fn integrate(grid: &NavGrid, goal: Cell) -> Vec<f32> {
+    let mut integration = vec![IMPASSABLE; (grid.width() * grid.height()) as usize];
+    let mut open = BinaryHeap::new();
+
+    if let Some(index) = grid.index(goal) {
+        integration[index] = 0.;
+        open.push(Visit { cost: 0., cell: goal });
+    }
+
+    while let Some(Visit { cost, cell }) = open.pop() {
+        let index = grid.index(cell).unwrap();
+        if cost > integration[index] {
+            continue;
+        }
+
+        for (dx, dy) in NEIGHBOURS.iter() {
+            let next = (cell.0 + dx, cell.1 + dy);
+            if !grid.passable(next) {
+                continue;
+            }
+
+            if *dx != 0 && *dy != 0
+                && (!grid.passable((cell.0 + dx, cell.1)) || !grid.passable((cell.0, cell.1 + dy)))
+            {
+                continue;
+            }
+
+            let step = if *dx != 0 && *dy != 0 { std::f32::consts::SQRT_2 } else { 1. };
+            let next_cost = cost + grid.cost(next) * step;
+            let next_index = grid.index(next).unwrap();
+            if next_cost < integration[next_index] {
+                integration[next_index] = next_cost;
+                open.push(Visit { cost: next_cost, cell: next });
+            }
+        }
+    }
+
+    integration
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Flow fields cached by goal cell, dropped whenever the grid changes
+pub struct FlowFields {
+    fields: HashMap<Cell, FlowField>,
+    grid_version: u32,
+}
+
+impl FlowFields {
+    pub fn new() -> Self {
+        Self {
+            fields: HashMap::new(),
+            grid_version: 0,
+        }
+    }
+
+    pub fn get(&mut self, grid: &NavGrid, goal: Vector2) -> Option<&FlowField> {
+        if grid.version() != self.grid_version {
+            self.fields.clear();
+            self.grid_version = grid.version();
+        }
+
+        let goal = grid.cell(goal)?;
+        Some(
+            self.fields
+                .entry(goal)
+                .or_insert_with(|| FlowField::new(grid, goal)),
+        )
+    }
+
+    /// Sample the direction towards `goal` from `pos`
+    pub fn direction(&mut self, grid: &NavGrid, goal: Vector2, pos: Vector2) -> Option<Vector2> {
+        self.get(grid, goal)?.direction(grid, pos)
+    }
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use super::*;
+
+    // Units should be led around a wall, and fields rebuilt when it moves
+    pub fn test_flow_field() -> bool {
+        let mut grid = NavGrid::new(Vector2::zero(), 10., 10, 10);
+
+        // Wall between the start and the goal, with a gap at the bottom
+        for y in 0..9 {
+            grid.block((5, y));
+        }
+
+        let mut fields = FlowFields::new();
+        let start = grid.center((2, 0));
+        let goal = grid.center((8, 0));
+
+        let direction = fields.direction(&grid, goal, start).unwrap();
+        assert_gd!(direction.y > 0.);
+        assert_gd!(fields.fields.len() == 1);
+
+        // The same goal reuses the field
+        fields.direction(&grid, goal, grid.center((3, 3)));
+        assert_gd!(fields.fields.len() == 1);
+
+        // Removing the wall invalidates it
+        for y in 0..9 {
+            grid.unblock((5, y));
+        }
+        let direction = fields.direction(&grid, goal, start).unwrap();
+        assert_gd!(direction == Vector2::new(1., 0.));
+        assert_gd!(fields.fields.len() == 1)
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use gdnative::{ByteArray, Dictionary, Variant, Vector2};
+use legion::prelude::*;
+
+use crate::units::{Faction, Unit, UnitPos};
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// How far a unit can see
+#[derive(Debug, Clone, Copy)]
+pub struct Vision(pub f32);
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Visibility {
+    /// Never seen
+    Unseen = 0,
+    /// Seen before, but nobody is looking now
+    Explored = 1,
+    Visible = 2,
+}
+
+/// What each faction can see, one grid per faction
+pub struct FogOfWar {
+    origin: Vector2,
+    cell_size: f32,
+    width: i32,
+    height: i32,
+    factions: HashMap<u32, Vec<Visibility>>,
+}
+
+impl FogOfWar {
+    pub fn new(origin: Vector2, cell_size: f32, width: i32, height: i32) -> Self {
+        Self {
+            origin,
+            cell_size,
+            width,
+            height,
+            factions: HashMap::new(),
+        }
+    }
+
+    fn index(&self, pos: Vector2) -> Option<usize> {
+        let local = (pos - self.origin) / self.cell_size;
+        let (x, y) = (local.x.floor() as i32, local.y.floor() as i32);
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return None;
+        }
+        Some((y * self.width + x) as usize)
+    }
+
+    /// Anything off the grid is never seen
+    pub fn visibility(&self, faction: Faction, pos: Vector2) -> Visibility {
+        match (self.factions.get(&faction.0), self.index(pos)) {
+            (Some(cells), Some(i)) => cells[i],
+            _ => Visibility::Unseen,
+        }
+    }
+
+    pub fn is_visible(&self, faction: Faction, pos: Vector2) -> bool {
+        self.visibility(faction, pos) == Visibility::Visible
+    }
+
+    /// Everything currently visible becomes explored, call before revealing
+    /// what the units see this frame
+    pub fn fade(&mut self) {
+        for cells in self.factions.values_mut() {
+            for cell in cells.iter_mut() {
+                if *cell == Visibility::Visible {
+                    *cell = Visibility::Explored;
+                }
+            }
+        }
+    }
+
+    /// Make every cell whose center is within `radius` of `pos` visible
+    pub fn reveal(&mut self, faction: Faction, pos: Vector2, radius: f32) {
+        let (origin, cell_size, width, height) = (self.origin, self.cell_size, self.width, self.height);
+        let cells = self
+            .factions
+            .entry(faction.0)
+            .or_insert_with(|| vec![Visibility::Unseen; (width * height) as usize]);
+
+        let local = (pos - origin) / cell_size;
+        let cell_radius = (radius / cell_size).ceil() as i32;
+        let (cx, cy) = (local.x.floor() as i32, local.y.floor() as i32);
+
+        for y in (cy - cell_radius).max(0)..=(cy + cell_radius).min(height - 1) {
+            for x in (cx - cell_radius).max(0)..=(cx + cell_radius).min(width - 1) {
+                let center = origin + Vector2::new(x as f32 + 0.5, y as f32 + 0.5) * cell_size;
+                if (center - pos).length() <= radius {
+                    cells[(y * width + x) as usize] = Visibility::Visible;
+                }
+            }
+        }
+    }
+
+    /// The faction's grid for drawing a fog overlay:
+    /// `origin`, `cell_size`, `width`, `height` and `cells`, one byte per
+    /// cell row by row, 0 unseen, 1 explored and 2 visible.
+    pub fn to_dictionary(&self, faction: Faction) -> Dictionary {
+        let mut cells = ByteArray::new();
+        match self.factions.get(&faction.0) {
+            Some(grid) => grid.iter().for_each(|cell| cells.push(*cell as u8)),
+            None => (0..self.width * self.height).for_each(|_| cells.push(Visibility::Unseen as u8)),
+        }
+
+        let mut dict = Dictionary::new();
+        dict.set(&Variant::from_str("origin"), &Variant::from_vector2(&self.origin));
+        dict.set(&Variant::from_str("cell_size"), &Variant::from_f64(self.cell_size as f64));
+        dict.set(&Variant::from_str("width"), &Variant::from_i64(self.width as i64));
+        dict.set(&Variant::from_str("height"), &Variant::from_i64(self.height as i64));
+        dict.set(&Variant::from_str("cells"), &Variant::from_byte_array(&cells));
+        dict
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+pub fn update_visibility() -> Box<dyn Schedulable> {
+    SystemBuilder::new("update visibility")
+        .write_resource::<FogOfWar>()
+        .with_query(<(Read<UnitPos>, Read<Vision>, Read<Faction>)>::query())
+        .build(|_, world, fog, query| {
+            fog.fade();
+            for (pos, vision, faction) in query.iter(world) {
+                fog.reveal(*faction, pos.0, vision.0);
+            }
+        })
+}
+
+/// Hide hostile units the player can't see
+pub fn hide_units() -> Box<dyn Runnable> {
+    SystemBuilder::new("hide units")
+        .read_resource::<FogOfWar>()
+        .with_query(<(Write<Unit>, Read<UnitPos>, Read<Faction>)>::query())
+        .build_thread_local(|_, world, fog, query| {
+            for (mut unit, pos, faction) in query.iter_mut(world) {
+                if !Faction::PLAYER.hostile_to(&faction) {
+                    continue;
+                }
+
+                let visible = fog.is_visible(Faction::PLAYER, pos.0);
+                unsafe {
+                    if unit.0.is_visible() != visible {
+                        unit.0.set_visible(visible);
+                    }
+                }
+            }
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use super::*;
+
+    // Units reveal what's around them, and leave explored cells behind
+    pub fn test_fog_of_war() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(FogOfWar::new(Vector2::zero(), 10., 20, 20));
+
+        let scout = world.insert((), vec![
+            (UnitPos(Vector2::new(15., 15.)), Vision(20.), Faction::PLAYER),
+        ])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(update_visibility())
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+
+        {
+            let fog = resources.get::<FogOfWar>().unwrap();
+            assert_gd!(fog.is_visible(Faction::PLAYER, Vector2::new(25., 15.)));
+            assert_gd!(fog.visibility(Faction::PLAYER, Vector2::new(150., 150.)) == Visibility::Unseen);
+            // Other factions see nothing
+            assert_gd!(!fog.is_visible(Faction(1), Vector2::new(15., 15.)));
+        }
+
+        world.get_component_mut::<UnitPos>(scout).map(|mut pos| pos.0 = Vector2::new(150., 150.));
+        sched.execute(&mut world, &mut resources);
+
+        let fog = resources.get::<FogOfWar>().unwrap();
+        assert_gd!(fog.visibility(Faction::PLAYER, Vector2::new(15., 15.)) == Visibility::Explored);
+        assert_gd!(fog.is_visible(Faction::PLAYER, Vector2::new(150., 150.)))
+    }
+}
@@ -0,0 +1,169 @@
+use std::cmp::Ordering;
+
+use gdnative::Vector2;
+use legion::prelude::*;
+
+use crate::input::Actions;
+
+/// Distance between two neighbouring slots
+pub const SLOT_SPACING: f32 = 24.;
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Formation {
+    Line,
+    Box,
+    Wedge,
+}
+
+impl Formation {
+    /// Slot offsets for `count` units, facing along the positive x axis.
+    pub fn offsets(&self, count: usize) -> Vec<Vector2> {
+        match self {
+            Formation::Line => (0..count)
+                .map(|i| Vector2::new(0., lateral(i, count)))
+                .collect(),
+            Formation::Box => {
+                let columns = (count as f32).sqrt().ceil().max(1.) as usize;
+                let rows = (count + columns - 1) / columns;
+                let depth = (rows as f32 - 1.) * SLOT_SPACING / 2.;
+
+                (0..count)
+                    .map(|i| {
+                        let row = i / columns;
+                        let in_row = columns.min(count - row * columns);
+                        Vector2::new(
+                            depth - row as f32 * SLOT_SPACING,
+                            lateral(i % columns, in_row),
+                        )
+                    })
+                    .collect()
+            }
+            Formation::Wedge => (0..count)
+                .map(|i| {
+                    let row = ((i + 1) / 2) as f32;
+                    let side = if i % 2 == 0 { 1. } else { -1. };
+                    Vector2::new(-row * SLOT_SPACING, side * row * SLOT_SPACING)
+                })
+                .collect(),
+        }
+    }
+}
+
+fn lateral(index: usize, count: usize) -> f32 {
+    (index as f32 - (count as f32 - 1.) / 2.) * SLOT_SPACING
+}
+
+/// Give every unit a slot around `target`, with the formation facing the
+/// direction the group is travelling in.
+/// Units at the front pick first, each taking the free slot closest to where
+/// it currently is within the group, so the group keeps its shape.
+pub fn assign_slots(
+    formation: Formation,
+    units: &[(Entity, Vector2)],
+    target: Vector2,
+) -> Vec<(Entity, Vector2)> {
+    if units.is_empty() {
+        return Vec::new();
+    }
+
+    let centre = units
+        .iter()
+        .fold(Vector2::zero(), |acc, (_, pos)| acc + *pos)
+        / units.len() as f32;
+
+    let heading = match target - centre {
+        h if h.length() > 0. => h.normalize(),
+        _ => Vector2::new(1., 0.),
+    };
+    let side = Vector2::new(-heading.y, heading.x);
+
+    let mut slots = formation
+        .offsets(units.len())
+        .into_iter()
+        .map(|offset| heading * offset.x + side * offset.y)
+        .collect::<Vec<_>>();
+
+    let mut units = units.to_vec();
+    units.sort_by(|(_, a), (_, b)| {
+        let a = (*a - centre).dot(heading);
+        let b = (*b - centre).dot(heading);
+        b.partial_cmp(&a).unwrap_or(Ordering::Equal)
+    });
+
+    units
+        .into_iter()
+        .map(|(entity, pos)| {
+            let offset = pos - centre;
+            let index = slots
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| {
+                    let a = (**a - offset).length();
+                    let b = (**b - offset).length();
+                    a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+                })
+                .map(|(index, _)| index)
+                .unwrap_or(0);
+
+            (entity, target + slots.swap_remove(index))
+        })
+        .collect()
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+pub fn change_formation() -> Box<dyn Schedulable> {
+    SystemBuilder::new("change formation")
+        .write_resource::<Actions>()
+        .write_resource::<Formation>()
+        .build(|_, _, (actions, formation), _| {
+            let choices = [
+                ("formation_line", Formation::Line),
+                ("formation_box", Formation::Box),
+                ("formation_wedge", Formation::Wedge),
+            ];
+
+            for (action, choice) in choices.iter() {
+                if actions.pressed(action) {
+                    actions.consume(action);
+                    **formation = *choice;
+                }
+            }
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use crate::units::UnitPos;
+    use super::*;
+
+    // Units should keep their relative positions when moving in a line
+    pub fn test_assign_slots() -> bool {
+        let mut world = Universe::new().create_world();
+
+        // Three units side by side, ordered to move right
+        let positions = vec![
+            Vector2::new(0., -10.),
+            Vector2::new(0., 0.),
+            Vector2::new(0., 10.),
+        ];
+        let entities = world
+            .insert((), positions.iter().map(|pos| (UnitPos(*pos),)))
+            .to_vec();
+        let units = entities.iter().cloned().zip(positions).collect::<Vec<_>>();
+        let target = Vector2::new(100., 0.);
+
+        let slots = assign_slots(Formation::Line, &units, target);
+        let slot = |entity| slots.iter().find(|(e, _)| *e == entity).unwrap().1;
+
+        assert_gd!(slots.len() == 3);
+        assert_gd!(slot(entities[1]) == target);
+        assert_gd!(slot(entities[0]).y < target.y);
+        assert_gd!(slot(entities[2]).y > target.y)
+    }
+}
@@ -1,15 +1,59 @@
 use gdextras::input::InputEventExt;
 use gdnative::{
     godot_error, godot_wrap_method, godot_wrap_method_inner, godot_wrap_method_parameter_count,
-    methods, InputEvent, InputEventMouseButton, NativeClass, Node, Node2D,
+    init, methods, Dictionary, GodotString, InputEvent, InputEventMouseButton, NativeClass, Node, Node2D, NodePath, TileMap,
+    Variant, Vector2,
 };
 use lazy_static::lazy_static;
 use legion::prelude::*;
 use std::sync::Mutex;
 
-use crate::combat::{attack_targets, cooldown_units, despawn_bullets, spawn_bullets, target_unit};
-use crate::input::{MouseButton, MousePos};
-use crate::units::{move_units, select_unit, set_unit_destination, spawn_unit};
+use crate::combat::{
+    attack_move, attack_targets, cooldown_units, despawn_bullets, fade_explosions, hold_position,
+    spawn_bullets, spawn_explosions, target_unit,
+};
+use crate::effects::{apply_effects, expire_effects, StatusEffects};
+use crate::flowfield::FlowFields;
+use crate::fog::{hide_units, update_visibility, FogOfWar};
+use crate::healing::{
+    float_numbers, heal_allies, order_repairs, repair_buildings, show_heal_numbers, HealEvents,
+};
+use crate::abilities::{cast_abilities, draw_aiming, use_abilities, Abilities, AimCursor};
+use crate::ai::{ai_attack, ai_form_squads, ai_retreat, ai_spawn_units, AiCommander, Difficulties};
+use crate::archetype::Archetypes;
+use crate::behaviour::{run_behaviours, BehaviourTrees};
+use crate::buildings::{
+    block_footprints, produce_units, queue_production, set_rally_point, BlockedCells, Blueprints,
+    PRODUCE_ACTIONS,
+};
+use crate::data;
+use crate::economy::{
+    create_markers, gather_resources, harvest, insert_base, Stockpiles, STARTING_MINERALS,
+};
+use crate::match_state::{
+    check_win_conditions, results_to_dictionary, update_match, Match, MatchRules, MatchState,
+};
+use crate::placement::{draw_placement, place_building, Ghost, BUILD_ACTIONS};
+use crate::simulation::flat_terrain;
+use crate::supply::{count_supply, Supply, STARTING_SUPPLY};
+use crate::targeting::TargetScores;
+use crate::tech::{apply_upgrades, queue_research, research_techs, TechTree, Upgrades, RESEARCH_ACTIONS};
+use crate::terrain::Terrain;
+use crate::formation::{change_formation, Formation};
+use crate::input::{Actions, Aiming, MouseButton, MousePos, Placement, ACTIONS};
+use crate::orders::{
+    choose_command_mode, draw_waypoints, follow_orders, stop_units, CommandMode,
+};
+use crate::units::{
+    create_unit_nodes, deselect_units, move_units, select_unit, set_unit_destination, steer_units,
+    Faction,
+};
+use crate::veterancy::{award_experience, Ranks, Statistics};
+
+/// Where the player's base is
+const PLAYER_HOME: (f32, f32) = (-200., 0.);
+/// Where the AI opponent spawns its units
+const AI_HOME: (f32, f32) = (200., 0.);
 
 // -----------------------------------------------------------------------------
 //     - World  -
@@ -31,6 +75,8 @@ where
 struct Process {
     resources: Resources,
     schedule: Schedule,
+    /// Runs instead of `schedule` whenever the match isn't running
+    idle: Schedule,
 }
 
 impl Process {
@@ -39,32 +85,118 @@ impl Process {
         resources.insert(Delta(0.));
         resources.insert(MousePos::zero());
         resources.insert(MouseButton::Empty);
+        resources.insert(Actions::empty());
+        resources.insert(Formation::Box);
+        resources.insert(CommandMode::Move);
+        resources.insert(Archetypes::new());
+        resources.insert(Blueprints::new());
+        resources.insert(BehaviourTrees::new());
+        resources.insert(flat_terrain().fog_of_war());
+        // Buildings are placed on the same tiles the physics navigates
+        resources.insert(flat_terrain());
+        resources.insert(Stockpiles::new(STARTING_MINERALS));
+        resources.insert(Supply::new(STARTING_SUPPLY));
+        resources.insert(Notices::new());
+        resources.insert(HealEvents::new());
+        resources.insert(TechTree::new());
+        resources.insert(StatusEffects::new());
+        resources.insert(Abilities::new());
+        resources.insert(Aiming::none());
+        resources.insert(AimCursor::new());
+        resources.insert(Upgrades::new());
+        resources.insert(Ranks::new());
+        resources.insert(Statistics::new());
+        resources.insert(Placement::none());
+        resources.insert(Ghost::new());
+        resources.insert(Match::new(MatchRules::default()));
 
+        // `simulation::schedule` runs the same systems headless, keep them in step
         let schedule = Schedule::builder()
+            .add_system(update_match())
+            .add_system(count_supply())
+            .add_system(place_building())
+            .add_system(use_abilities())
             .add_system(select_unit())
+            .add_system(deselect_units())
+            .add_system(change_formation())
+            .add_system(choose_command_mode())
+            .add_system(stop_units())
+            .add_system(queue_production())
+            .add_system(queue_research())
+            .add_system(set_rally_point())
             .add_system(set_unit_destination())
+            .add_system(update_visibility())
+            .add_system(ai_spawn_units())
+            .add_system(ai_form_squads())
+            .add_system(ai_retreat())
+            .add_system(ai_attack())
+            .add_system(run_behaviours())
+            .add_system(order_repairs())
+            .add_system(gather_resources())
             .add_system(target_unit())
+            .add_system(attack_move())
+            .add_system(hold_position())
             .add_system(attack_targets())
             .flush()
+            .add_system(apply_upgrades())
+            .add_system(follow_orders())
+            .add_system(harvest())
+            .add_system(repair_buildings())
+            .add_system(produce_units())
+            .add_system(research_techs())
             .add_system(cooldown_units())
-            .add_thread_local(spawn_unit())
+            .add_system(award_experience())
+            .add_system(apply_effects())
+            .add_system(expire_effects())
+            .add_system(heal_allies())
+            .add_system(cast_abilities())
+            .add_system(check_win_conditions())
+            .add_thread_local(create_unit_nodes())
+            .add_thread_local(create_markers())
+            .add_thread_local(draw_placement())
+            .add_thread_local(draw_aiming())
+            .add_thread_local(hide_units())
             .add_thread_local(spawn_bullets())
             .add_thread_local(despawn_bullets())
+            .add_thread_local(spawn_explosions())
+            .add_thread_local(fade_explosions())
+            .add_thread_local(show_heal_numbers())
+            .add_thread_local(float_numbers())
+            .add_thread_local(draw_waypoints())
+            .build();
+
+        // Nothing moves, but units can still be looked at
+        let idle = Schedule::builder()
+            .add_system(update_match())
+            .add_system(select_unit())
+            .add_system(deselect_units())
+            .add_system(update_visibility())
+            .add_thread_local(create_unit_nodes())
+            .add_thread_local(create_markers())
+            .add_thread_local(hide_units())
+            .add_thread_local(draw_waypoints())
             .build();
 
         Self {
             resources,
             schedule,
+            idle,
         }
     }
 
+    fn running(&self) -> bool {
+        self.resources.get::<Match>().map(|game| game.running()).unwrap_or(false)
+    }
+
     fn execute(&mut self, delta: f64) {
         self.resources
             .get_mut::<Delta>()
             .map(|mut d| d.0 = delta as f32);
 
+        let schedule = if self.running() { &mut self.schedule } else { &mut self.idle };
+        let resources = &mut self.resources;
         with_world(|mut world| {
-            self.schedule.execute(&mut world, &mut self.resources);
+            schedule.execute(&mut world, resources);
         })
     }
 }
@@ -79,7 +211,19 @@ impl Physics {
         let mut resources = Resources::default();
         resources.insert(Delta(0.));
 
-        let schedule = Schedule::builder().add_thread_local(move_units()).build();
+        // Flat ground until a tile map says otherwise
+        let terrain = flat_terrain();
+        resources.insert(terrain.nav_grid());
+        resources.insert(terrain);
+        resources.insert(FlowFields::new());
+        resources.insert(BlockedCells::default());
+
+        let schedule = Schedule::builder()
+            .add_system(block_footprints())
+            .add_system(steer_units())
+            .flush()
+            .add_thread_local(move_units())
+            .build();
 
         Self {
             resources,
@@ -114,6 +258,23 @@ impl WorldNode {
 unsafe impl Send for WorldNode {}
 unsafe impl Sync for WorldNode {}
 
+/// Messages for the player, emitted as the `notice` signal
+pub struct Notices(Vec<String>);
+
+impl Notices {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn push(&mut self, message: String) {
+        self.0.push(message);
+    }
+
+    pub fn drain(&mut self) -> Vec<String> {
+        self.0.drain(..).collect()
+    }
+}
+
 // -----------------------------------------------------------------------------
 //     - Tags -
 // -----------------------------------------------------------------------------
@@ -126,6 +287,7 @@ pub struct Selected;
 
 #[derive(NativeClass)]
 #[inherit(Node2D)]
+#[register_with(Self::register_signals)]
 pub struct GameWorld {
     process: Process,
     physics: Physics,
@@ -140,16 +302,269 @@ impl GameWorld {
         }
     }
 
+    fn register_signals(builder: &init::ClassBuilder<Self>) {
+        builder.add_signal(init::Signal {
+            name: "notice",
+            args: &[init::SignalArgument {
+                name: "message",
+                default: Variant::from_str(""),
+                hint: init::PropertyHint::None,
+                usage: init::PropertyUsage::DEFAULT,
+            }],
+        });
+
+        builder.add_signal(init::Signal {
+            name: "match_ended",
+            args: &[init::SignalArgument {
+                name: "results",
+                default: Variant::from_dictionary(&Dictionary::new()),
+                hint: init::PropertyHint::None,
+                usage: init::PropertyUsage::DEFAULT,
+            }],
+        });
+    }
+
     #[export]
     pub fn _ready(&mut self, owner: Node2D) {
         self.process.resources.insert(WorldNode(owner));
+
+        let terrain = unsafe {
+            owner
+                .get_node(NodePath::from_str("TileMap"))
+                .and_then(|node| node.cast::<TileMap>())
+                .and_then(|tilemap| Terrain::from_tilemap(&tilemap))
+        };
+
+        if let Some(terrain) = terrain {
+            self.process.resources.insert(terrain.fog_of_war());
+            self.process.resources.insert(terrain.clone());
+            self.physics.resources.insert(terrain.nav_grid());
+            self.physics.resources.insert(terrain);
+            self.physics.resources.insert(FlowFields::new());
+        }
+
+        if let Some(trees) = data::load::<BehaviourTrees>("res://data/behaviours.ron") {
+            self.process.resources.insert(trees);
+        }
+
+        if let Some(tech_tree) = data::load::<TechTree>("res://data/tech.ron") {
+            self.process.resources.insert(tech_tree);
+        }
+
+        if let Some(effects) = data::load::<StatusEffects>("res://data/effects.ron") {
+            self.process.resources.insert(effects);
+        }
+
+        if let Some(abilities) = data::load::<Abilities>("res://data/abilities.ron") {
+            self.process.resources.insert(abilities);
+        }
+
+        if let Some(ranks) = data::load::<Ranks>("res://data/ranks.ron") {
+            self.process.resources.insert(ranks);
+        }
+
+        let rules = data::load::<MatchRules>("res://data/match.ron").unwrap_or_default();
+        self.process.resources.insert(Match::new(rules));
+
+        // AI opponent
+        let difficulty = data::load::<Difficulties>("res://data/ai.ron")
+            .and_then(|mut difficulties| difficulties.remove("normal"))
+            .unwrap_or_default();
+        let home = Vector2::new(AI_HOME.0, AI_HOME.1);
+        let archetypes = self.process.resources.get::<Archetypes>();
+        let blueprints = self.process.resources.get::<Blueprints>();
+        let terrain = self.process.resources.get::<Terrain>();
+        with_world(|world| {
+            world.insert((), vec![(AiCommander::new(Faction(1), difficulty.clone(), home),)]);
+
+            // Both sides start with a base and a few workers
+            if let (Some(archetypes), Some(blueprints), Some(terrain)) = (&archetypes, &blueprints, &terrain) {
+                let player_home = Vector2::new(PLAYER_HOME.0, PLAYER_HOME.1);
+                let mut cmd = CommandBuffer::new(world);
+                insert_base(&mut cmd, archetypes, blueprints, terrain, player_home, Faction::PLAYER);
+                insert_base(&mut cmd, archetypes, blueprints, terrain, home, Faction(1));
+                cmd.write(world);
+            }
+        });
     }
 
+    /// Stop the selected units, same as the `stop` action
     #[export]
-    pub fn _unhandled_input(&self, owner: Node2D, event: InputEvent) {
-        if event.action_pressed("ui_cancel") {
-            unsafe { owner.get_tree().map(|mut tree| tree.quit(0)) };
+    pub fn stop(&mut self, _owner: Node2D) {
+        self.press_action("stop");
+    }
+
+    /// Hold position with the selected units, same as the `hold_position` action
+    #[export]
+    pub fn hold_position(&mut self, _owner: Node2D) {
+        self.press_action("hold_position");
+    }
+
+    /// Why the selected units picked their last targets, for debugging
+    #[export]
+    pub fn target_scores(&mut self, _owner: Node2D) -> GodotString {
+        let mut lines = Vec::new();
+        with_world(|world| {
+            let mut query = <Read<TargetScores>>::query().filter(tag::<Selected>());
+            for (entity, scores) in query.iter_entities(&*world) {
+                for score in &scores.0 {
+                    lines.push(format!("{:?} -> {:?}", entity, score));
+                }
+            }
+        });
+        GodotString::from_str(&lines.join("\n"))
+    }
+
+    /// The player's fog of war, see `FogOfWar::to_dictionary`
+    #[export]
+    pub fn fog_of_war(&mut self, _owner: Node2D) -> Dictionary {
+        self.process
+            .resources
+            .get::<FogOfWar>()
+            .map(|fog| fog.to_dictionary(Faction::PLAYER))
+            .unwrap_or_else(Dictionary::new)
+    }
+
+    /// Queue up a unit at the selected buildings, same as the `produce_1`,
+    /// `produce_2` and `produce_3` actions
+    #[export]
+    pub fn produce(&mut self, _owner: Node2D, index: i64) {
+        if let Some(action) = PRODUCE_ACTIONS.get(index as usize) {
+            self.press_action(action);
+        }
+    }
+
+    /// Cancel the last unit queued at the selected buildings, same as the
+    /// `cancel_production` action
+    #[export]
+    pub fn cancel_production(&mut self, _owner: Node2D) {
+        self.press_action("cancel_production");
+    }
+
+    /// Start placing a building, same as the `build_base`, `build_barracks`
+    /// and `build_depot` actions
+    #[export]
+    pub fn build(&mut self, _owner: Node2D, blueprint: GodotString) {
+        let blueprint = blueprint.to_string();
+        if let Some((action, _)) = BUILD_ACTIONS.iter().find(|(_, name)| *name == blueprint) {
+            self.press_action(action);
+        }
+    }
+
+    /// Research at the selected buildings, same as the `research_1`,
+    /// `research_2` and `research_3` actions
+    #[export]
+    pub fn research(&mut self, _owner: Node2D, index: i64) {
+        if let Some(action) = RESEARCH_ACTIONS.get(index as usize) {
+            self.press_action(action);
         }
+    }
+
+    /// Whether the player has finished researching the tech
+    #[export]
+    pub fn researched(&mut self, _owner: Node2D, tech: GodotString) -> bool {
+        self.process
+            .resources
+            .get::<Upgrades>()
+            .map(|upgrades| upgrades.researched(Faction::PLAYER, &tech.to_string()))
+            .unwrap_or(false)
+    }
+
+    /// Minerals in the player's stockpile
+    #[export]
+    pub fn minerals(&mut self, _owner: Node2D) -> u32 {
+        self.process
+            .resources
+            .get::<Stockpiles>()
+            .map(|stockpiles| stockpiles.amount(Faction::PLAYER))
+            .unwrap_or(0)
+    }
+
+    /// Supply used by the player's units
+    #[export]
+    pub fn supply_used(&mut self, _owner: Node2D) -> u32 {
+        self.process
+            .resources
+            .get::<Supply>()
+            .map(|supply| supply.used(Faction::PLAYER))
+            .unwrap_or(0)
+    }
+
+    /// Most supply the player's units can use
+    #[export]
+    pub fn supply_cap(&mut self, _owner: Node2D) -> u32 {
+        self.process
+            .resources
+            .get::<Supply>()
+            .map(|supply| supply.cap(Faction::PLAYER))
+            .unwrap_or(0)
+    }
+
+    /// Kills and losses of a faction so far, see `FactionStats::to_dictionary`
+    #[export]
+    pub fn statistics(&mut self, _owner: Node2D, faction: i64) -> Dictionary {
+        self.process
+            .resources
+            .get::<Statistics>()
+            .map(|statistics| statistics.get(Faction(faction as u32)).to_dictionary())
+            .unwrap_or_else(Dictionary::new)
+    }
+
+    /// Leave the lobby and start the countdown
+    #[export]
+    pub fn start_match(&mut self, _owner: Node2D) {
+        self.process.resources.get_mut::<Match>().map(|mut game| game.start());
+    }
+
+    /// Pause or resume the match, same as the `pause_match` action
+    #[export]
+    pub fn pause_match(&mut self, _owner: Node2D) {
+        self.press_action("pause_match");
+    }
+
+    /// One of `lobby`, `countdown`, `running`, `paused` and `ended`
+    #[export]
+    pub fn match_state(&mut self, _owner: Node2D) -> GodotString {
+        self.process
+            .resources
+            .get::<Match>()
+            .map(|game| GodotString::from_str(game.state().name()))
+            .unwrap_or_else(GodotString::new)
+    }
+
+    /// Seconds left of the countdown, or the match has been running for
+    #[export]
+    pub fn match_time(&mut self, _owner: Node2D) -> f32 {
+        self.process
+            .resources
+            .get::<Match>()
+            .map(|game| match game.state() {
+                MatchState::Countdown(left) => left,
+                _ => game.elapsed(),
+            })
+            .unwrap_or(0.)
+    }
+
+    fn press_action(&self, action: &'static str) {
+        self.process
+            .resources
+            .get_mut::<Actions>()
+            .map(|mut actions| actions.press(action));
+    }
+
+    #[export]
+    pub fn _unhandled_input(&self, owner: Node2D, event: InputEvent) {
+        // Input actions
+        self.process
+            .resources
+            .get_mut::<Actions>()
+            .map(|mut actions| {
+                for &action in ACTIONS {
+                    if event.action_pressed(action) {
+                        actions.press(action);
+                    }
+                }
+            });
 
         // Mouse position
         self.process
@@ -170,12 +585,56 @@ impl GameWorld {
     }
 
     #[export]
-    pub fn _process(&mut self, _: Node2D, delta: f64) {
+    pub fn _process(&mut self, mut owner: Node2D, delta: f64) {
         self.process.execute(delta);
+
+        let notices = self
+            .process
+            .resources
+            .get_mut::<Notices>()
+            .map(|mut notices| notices.drain())
+            .unwrap_or_default();
+
+        for message in notices {
+            unsafe {
+                owner.emit_signal(GodotString::from_str("notice"), &[Variant::from_str(&message)]);
+            }
+        }
+
+        let results = self
+            .process
+            .resources
+            .get_mut::<Match>()
+            .and_then(|mut game| game.take_results());
+
+        if let (Some(results), Some(statistics)) = (results, self.process.resources.get::<Statistics>()) {
+            let results = results_to_dictionary(&results, &statistics);
+            unsafe {
+                owner.emit_signal(GodotString::from_str("match_ended"), &[Variant::from_dictionary(&results)]);
+            }
+        }
+
+        // Cancel quits the game unless a system used it (e.g. to deselect)
+        let quit = self
+            .process
+            .resources
+            .get_mut::<Actions>()
+            .map(|mut actions| {
+                let quit = actions.pressed("ui_cancel");
+                actions.clear();
+                quit
+            })
+            .unwrap_or(false);
+
+        if quit {
+            unsafe { owner.get_tree().map(|mut tree| tree.quit(0)) };
+        }
     }
 
     #[export]
     pub fn _physics_process(&mut self, _: Node2D, delta: f64) {
-        self.physics.execute(delta);
+        if self.process.running() {
+            self.physics.execute(delta);
+        }
     }
 }
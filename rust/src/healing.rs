@@ -0,0 +1,546 @@
+//! Support units healing allies and workers repairing buildings.
+//!
+//! Healers pick who to heal on their own, scored the same way `targeting`
+//! scores hostiles. Repairs are ordered by right clicking a damaged building
+//! with workers selected, and cost minerals for every hitpoint restored.
+//! Either way the amount restored is pushed to `HealEvents`, for floating
+//! numbers.
+use gdnative::{Color, Label, Vector2};
+use legion::prelude::*;
+use serde::Deserialize;
+
+use crate::buildings::Building;
+use crate::combat::{AttackMoving, Hitpoints, MaxHitpoints, Target};
+use crate::economy::{move_to, Harvest, Stockpiles};
+use crate::effects::Stunned;
+use crate::gameworld::{Delta, Notices, Selected, WorldNode};
+use crate::input::{MouseButton, MousePos};
+use crate::orders::{Holding, Orders};
+use crate::spawner::create_label;
+use crate::units::{clear_current_order, Destination, Faction, UnitPos, UnitRect};
+
+/// How close a worker has to be to the edge of a building to repair it
+const REPAIR_RANGE: f32 = 16.;
+/// Seconds a floating number stays up
+const FLOAT_TIME: f32 = 1.;
+/// Pixels per second a floating number rises
+const FLOAT_SPEED: f32 = 20.;
+
+// -----------------------------------------------------------------------------
+//     - Data -
+// -----------------------------------------------------------------------------
+/// A damaged ally that could be healed
+#[derive(Debug, Clone, Copy)]
+pub struct Patient {
+    pub entity: Entity,
+    pub pos: Vector2,
+    pub faction: Faction,
+    pub hitpoints: u32,
+    pub max_hitpoints: u32,
+}
+
+/// How much each factor counts when choosing who to heal
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct HealWeights {
+    /// Prefer close allies
+    pub distance: f32,
+    /// Prefer allies with the smallest fraction of their hitpoints left
+    pub health: f32,
+    /// Prefer allies missing the most hitpoints
+    pub missing: f32,
+}
+
+impl Default for HealWeights {
+    fn default() -> Self {
+        Self {
+            distance: 0.5,
+            health: 1.,
+            missing: 0.5,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// Heals the most deserving damaged ally in range
+#[derive(Debug, Clone, Copy)]
+pub struct Healer {
+    /// Hitpoints restored per second
+    pub rate: f32,
+    pub range: f32,
+    pub priority: HealWeights,
+    pending: f32,
+}
+
+impl Healer {
+    pub fn new(rate: f32, range: f32, priority: HealWeights) -> Self {
+        Self { rate, range, priority, pending: 0. }
+    }
+}
+
+/// Makes a unit able to repair buildings
+#[derive(Debug, Clone, Copy)]
+pub struct Repairer {
+    /// Hitpoints restored per second
+    pub rate: f32,
+    /// Minerals spent per hitpoint restored
+    pub cost: u32,
+}
+
+/// Repairing `building` until it's back at max hitpoints
+#[derive(Debug, Clone, Copy)]
+pub struct Repair {
+    pub building: Entity,
+    pending: f32,
+}
+
+impl Repair {
+    pub fn new(building: Entity) -> Self {
+        Self { building, pending: 0. }
+    }
+}
+
+pub struct FloatingNumber {
+    label: Label,
+    remaining: f32,
+}
+
+unsafe impl Send for FloatingNumber {}
+unsafe impl Sync for FloatingNumber {}
+
+impl Drop for FloatingNumber {
+    fn drop(&mut self) {
+        unsafe { self.label.queue_free() };
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy)]
+pub struct HealEvent {
+    pub pos: Vector2,
+    pub amount: u32,
+}
+
+/// Hitpoints restored this frame, shown by `show_heal_numbers`
+pub struct HealEvents(Vec<HealEvent>);
+
+impl HealEvents {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn push(&mut self, pos: Vector2, amount: u32) {
+        self.0.push(HealEvent { pos, amount });
+    }
+
+    pub fn drain(&mut self) -> Vec<HealEvent> {
+        self.0.drain(..).collect()
+    }
+}
+
+/// Restore up to `amount` hitpoints without going over the max.
+/// Returns how many were restored.
+pub fn heal(hp: &mut Hitpoints, max_hp: &MaxHitpoints, amount: u32) -> u32 {
+    let healed = amount.min(max_hp.0.saturating_sub(hp.0));
+    hp.0 += healed;
+    healed
+}
+
+// -----------------------------------------------------------------------------
+//     - Scoring -
+// -----------------------------------------------------------------------------
+/// The ally of `faction` within `range` most in need of healing
+pub fn choose_patient(
+    weights: &HealWeights,
+    pos: Vector2,
+    faction: Faction,
+    range: f32,
+    patients: &[Patient],
+) -> Option<Entity> {
+    let in_range = patients
+        .iter()
+        .filter(|p| p.faction == faction && p.hitpoints < p.max_hitpoints)
+        .map(|p| (p, (p.pos - pos).length()))
+        .filter(|(_, distance)| *distance <= range)
+        .collect::<Vec<_>>();
+
+    let max_missing = in_range
+        .iter()
+        .map(|(p, _)| (p.max_hitpoints - p.hitpoints) as f32)
+        .fold(0., f32::max);
+
+    let score = |p: &Patient, distance: f32| {
+        let distance = if range > 0. { 1. - distance / range } else { 1. };
+        let health = 1. - p.hitpoints as f32 / p.max_hitpoints as f32;
+        let missing = (p.max_hitpoints - p.hitpoints) as f32 / max_missing;
+
+        distance * weights.distance + health * weights.health + missing * weights.missing
+    };
+
+    in_range
+        .into_iter()
+        .map(|(p, distance)| (p.entity, score(p, distance)))
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(entity, _)| entity)
+}
+
+/// Distance from `pos` to the closest point of `rect`, zero inside it
+fn distance_to(rect: &UnitRect, pos: Vector2) -> f32 {
+    let (min, max) = (rect.0.min(), rect.0.max());
+    let closest = Vector2::new(pos.x.max(min.x).min(max.x), pos.y.max(min.y).min(max.y));
+    (closest - pos).length()
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+/// Healers top up the ally they score highest. Buildings need repairs instead.
+pub fn heal_allies() -> Box<dyn Schedulable> {
+    SystemBuilder::new("heal allies")
+        .read_resource::<Delta>()
+        .write_resource::<HealEvents>()
+        .write_component::<Hitpoints>()
+        .with_query(<(Read<UnitPos>, Read<Faction>, Write<Healer>)>::query().filter(!component::<Stunned>()))
+        .with_query(<(Read<UnitPos>, Read<Faction>, Read<MaxHitpoints>)>::query().filter(!component::<Building>()))
+        .build(|_, world, (delta, events), (healers, patients)| {
+            let patients = patients
+                .iter_entities(world)
+                .filter_map(|(entity, (pos, faction, max_hp))| {
+                    let hp = world.get_component::<Hitpoints>(entity)?;
+                    Some(Patient {
+                        entity,
+                        pos: pos.0,
+                        faction: *faction,
+                        hitpoints: hp.0,
+                        max_hitpoints: max_hp.0,
+                    })
+                })
+                .filter(|p| p.hitpoints > 0)
+                .collect::<Vec<_>>();
+
+            for (entity, (pos, faction, mut healer)) in healers.iter_entities_mut(world) {
+                let others = patients.iter().filter(|p| p.entity != entity).copied().collect::<Vec<_>>();
+                let patient = match choose_patient(&healer.priority, pos.0, *faction, healer.range, &others) {
+                    Some(patient) => patient,
+                    None => {
+                        healer.pending = 0.;
+                        continue;
+                    }
+                };
+
+                healer.pending += healer.rate * delta.0;
+                let whole = healer.pending.trunc();
+                healer.pending -= whole;
+                if whole < 1. {
+                    continue;
+                }
+
+                let max_hp = patients.iter().find(|p| p.entity == patient).map(|p| MaxHitpoints(p.max_hitpoints));
+                if let (Some(mut hp), Some(max_hp)) = (world.get_component_mut::<Hitpoints>(patient), max_hp) {
+                    let healed = heal(&mut hp, &max_hp, whole as u32);
+                    if healed > 0 {
+                        let pos = patients.iter().find(|p| p.entity == patient).map(|p| p.pos).unwrap_or(pos.0);
+                        events.push(pos, healed);
+                    }
+                }
+            }
+        })
+}
+
+/// Right clicking a damaged building of their own sends the selected
+/// workers to repair it
+pub fn order_repairs() -> Box<dyn Schedulable> {
+    SystemBuilder::new("order repairs")
+        .write_resource::<MouseButton>()
+        .read_resource::<MousePos>()
+        .write_component::<Orders>()
+        .read_component::<Destination>()
+        .read_component::<Target>()
+        .read_component::<AttackMoving>()
+        .read_component::<Holding>()
+        .read_component::<Harvest>()
+        .read_component::<Repair>()
+        .with_query(<(Read<UnitRect>, Read<Faction>, Read<Hitpoints>, Read<MaxHitpoints>)>::query()
+            .filter(component::<Building>()))
+        .with_query(<Read<Faction>>::query().filter(tag::<Selected>() & component::<Repairer>()))
+        .build(|cmd, world, (mouse_btn, mouse_pos), (buildings, workers)| {
+            if !mouse_btn.button_pressed(2) {
+                return;
+            }
+
+            let building = buildings
+                .iter_entities(world)
+                .find(|(_, (rect, _, hp, max_hp))| {
+                    rect.0.contains(mouse_pos.global().to_point()) && hp.0 < max_hp.0
+                })
+                .map(|(ent, (_, faction, _, _))| (ent, *faction));
+
+            let (building, owner) = match building {
+                Some(building) => building,
+                None => return,
+            };
+
+            let workers = workers
+                .iter_entities(world)
+                .filter(|(_, faction)| **faction == owner)
+                .map(|(ent, _)| ent)
+                .collect::<Vec<_>>();
+
+            if workers.is_empty() {
+                return;
+            }
+
+            for &worker in &workers {
+                clear_current_order(cmd, world, worker);
+                if let Some(mut orders) = world.get_component_mut::<Orders>(worker) {
+                    orders.0.clear();
+                }
+                cmd.add_component(worker, Repair::new(building));
+            }
+
+            mouse_btn.consume();
+        })
+}
+
+/// Workers walk up to the building they were sent to and repair it, paying
+/// as they go. Running out of minerals stops the repair.
+pub fn repair_buildings() -> Box<dyn Schedulable> {
+    SystemBuilder::new("repair buildings")
+        .read_resource::<Delta>()
+        .write_resource::<Stockpiles>()
+        .write_resource::<HealEvents>()
+        .write_resource::<Notices>()
+        .write_component::<Hitpoints>()
+        .read_component::<MaxHitpoints>()
+        .read_component::<UnitPos>()
+        .read_component::<UnitRect>()
+        .read_component::<Destination>()
+        .with_query(<(Read<UnitPos>, Read<Faction>, Read<Repairer>, Write<Repair>)>::query()
+            .filter(!component::<Stunned>()))
+        .build(|cmd, world, (delta, stockpiles, events, notices), workers| {
+            for (entity, (pos, faction, repairer, mut repair)) in workers.iter_entities_mut(world) {
+                let building = repair.building;
+                let damaged = match (
+                    world.get_component::<Hitpoints>(building),
+                    world.get_component::<MaxHitpoints>(building),
+                ) {
+                    (Some(hp), Some(max_hp)) => hp.0 > 0 && hp.0 < max_hp.0,
+                    _ => false,
+                };
+
+                // Destroyed or good as new
+                if !damaged {
+                    cmd.remove_component::<Repair>(entity);
+                    continue;
+                }
+
+                let (building_pos, in_range) = match (
+                    world.get_component::<UnitPos>(building),
+                    world.get_component::<UnitRect>(building),
+                ) {
+                    (Some(building_pos), Some(rect)) => (building_pos.0, distance_to(&rect, pos.0) <= REPAIR_RANGE),
+                    _ => continue,
+                };
+
+                if !in_range {
+                    move_to(cmd, world, entity, building_pos);
+                    continue;
+                }
+
+                repair.pending += repairer.rate * delta.0;
+                let mut repaired = 0;
+                let mut broke = false;
+                if let (Some(mut hp), Some(max_hp)) = (
+                    world.get_component_mut::<Hitpoints>(building),
+                    world.get_component::<MaxHitpoints>(building),
+                ) {
+                    while repair.pending >= 1. && hp.0 < max_hp.0 {
+                        if !stockpiles.spend(*faction, repairer.cost) {
+                            broke = true;
+                            break;
+                        }
+
+                        repair.pending -= 1.;
+                        repaired += heal(&mut hp, &max_hp, 1);
+                    }
+                }
+
+                if repaired > 0 {
+                    events.push(building_pos, repaired);
+                }
+
+                if broke {
+                    if *faction == Faction::PLAYER {
+                        notices.push("Not enough minerals to repair".to_string());
+                    }
+                    cmd.remove_component::<Repair>(entity);
+                }
+            }
+        })
+}
+
+/// A rising number for every heal since the last frame
+pub fn show_heal_numbers() -> Box<dyn Runnable> {
+    SystemBuilder::new("show heal numbers")
+        .write_resource::<WorldNode>()
+        .write_resource::<HealEvents>()
+        .build_thread_local(|cmd, _, (world_node, events), _| {
+            for event in events.drain() {
+                let mut label = create_label(&format!("+{}", event.amount), Color::rgb(0.3, 1., 0.3));
+                unsafe {
+                    world_node.add_child(label.to_node());
+                    label.set_global_position(event.pos, false);
+                }
+
+                cmd.insert((), vec![(FloatingNumber { label, remaining: FLOAT_TIME },)]);
+            }
+        })
+}
+
+pub fn float_numbers() -> Box<dyn Runnable> {
+    SystemBuilder::new("float numbers")
+        .read_resource::<Delta>()
+        .with_query(<Write<FloatingNumber>>::query())
+        .build_thread_local(|cmd, world, delta, query| {
+            for (entity, mut number) in query.iter_entities_mut(world) {
+                number.remaining -= delta.0;
+                if number.remaining <= 0. {
+                    cmd.delete(entity);
+                    continue;
+                }
+
+                unsafe {
+                    let pos = number.label.get_global_position();
+                    number.label.set_global_position(pos - Vector2::new(0., FLOAT_SPEED * delta.0), false);
+
+                    let mut modulate = number.label.get_modulate();
+                    modulate.a = number.remaining / FLOAT_TIME;
+                    number.label.set_modulate(modulate);
+                }
+            }
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use crate::archetype::Archetype;
+    use crate::buildings::{insert_building, Blueprint};
+    use crate::simulation;
+    use crate::spawner::insert_unit;
+    use super::*;
+
+    // A healer at the origin with wounded allies that are close, have the
+    // least health left or miss the most hitpoints, and a wounded enemy
+    fn heal_once(priority: HealWeights) -> (World, Vec<Entity>) {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(Delta(1.));
+        resources.insert(HealEvents::new());
+
+        world.insert((), vec![(UnitPos(Vector2::zero()), Faction::PLAYER, Healer::new(1., 100., priority))]);
+        let ents = world.insert((), vec![
+            (UnitPos(Vector2::new(10., 0.)), Faction::PLAYER, Hitpoints(9), MaxHitpoints(10)),
+            (UnitPos(Vector2::new(60., 0.)), Faction::PLAYER, Hitpoints(1), MaxHitpoints(10)),
+            (UnitPos(Vector2::new(50., 0.)), Faction::PLAYER, Hitpoints(10), MaxHitpoints(40)),
+            (UnitPos(Vector2::new(5., 0.)), Faction(1), Hitpoints(1), MaxHitpoints(10)),
+        ]).to_vec();
+
+        let mut sched = Schedule::builder().add_system(heal_allies()).build();
+        sched.execute(&mut world, &mut resources);
+
+        (world, ents)
+    }
+
+    // The heuristic decides who gets healed, and no one goes past max hitpoints
+    pub fn test_heal_allies() -> bool {
+        let nearest = HealWeights { distance: 1., health: 0., missing: 0. };
+        let weakest = HealWeights { distance: 0., health: 1., missing: 0. };
+        let most_missing = HealWeights { distance: 0., health: 0., missing: 1. };
+
+        for (priority, healed) in [(nearest, 0), (weakest, 1), (most_missing, 2)].iter() {
+            let (world, ents) = heal_once(*priority);
+            let hitpoints = ents
+                .iter()
+                .map(|ent| world.get_component::<Hitpoints>(*ent).unwrap().0)
+                .collect::<Vec<_>>();
+
+            let mut expected = vec![9, 1, 10, 1];
+            expected[*healed] += 1;
+            assert_gd!(hitpoints == expected);
+        }
+
+        // A healer with no one else around
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(Delta(1.));
+        resources.insert(HealEvents::new());
+
+        world.insert((), vec![(
+            UnitPos(Vector2::zero()),
+            Faction::PLAYER,
+            Healer::new(3., 50., HealWeights::default()),
+        )]);
+        let hurt = world.insert((), vec![
+            (UnitPos(Vector2::new(20., 0.)), Faction::PLAYER, Hitpoints(5), MaxHitpoints(10)),
+            (UnitPos(Vector2::new(20., 0.)), Faction(1), Hitpoints(1), MaxHitpoints(10)),
+        ]).to_vec();
+
+        let mut sched = Schedule::builder()
+            .add_system(heal_allies())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Hitpoints>(hurt[0]).unwrap().0 == 8);
+
+        for _ in 0..2 {
+            sched.execute(&mut world, &mut resources);
+        }
+
+        assert_gd!(world.get_component::<Hitpoints>(hurt[0]).unwrap().0 == 10);
+        assert_gd!(world.get_component::<Hitpoints>(hurt[1]).unwrap().0 == 1);
+
+        let amounts = resources.get_mut::<HealEvents>().unwrap().drain()
+            .into_iter()
+            .map(|event| event.amount)
+            .collect::<Vec<_>>();
+        assert_gd!(amounts == vec![3, 2])
+    }
+
+    // Workers walk over and repair a building, paying for every hitpoint
+    pub fn test_repair_building() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = simulation::resources();
+        let mut sched = simulation::schedule();
+        resources.insert(Stockpiles::new(5));
+
+        let terrain = simulation::flat_terrain();
+        let mut cmd = CommandBuffer::new(&world);
+        let barracks = insert_building(
+            &mut cmd,
+            &Blueprint::barracks(),
+            &terrain,
+            terrain.tile(Vector2::zero()).unwrap(),
+            Faction::PLAYER,
+        );
+        let worker = insert_unit(&mut cmd, &Archetype::worker(), Vector2::new(0., 100.), Faction::PLAYER);
+        cmd.add_component(worker, Repair::new(barracks));
+        cmd.write(&mut world);
+
+        let max_hp = world.get_component::<MaxHitpoints>(barracks).unwrap().0;
+        world.get_component_mut::<Hitpoints>(barracks).unwrap().0 = max_hp - 8;
+
+        for _ in 0..200 {
+            simulation::step(&mut world, &mut resources, &mut sched, 0.1);
+        }
+
+        // Ran out of minerals part of the way
+        assert_gd!(world.get_component::<Hitpoints>(barracks).unwrap().0 == max_hp - 3);
+        assert_gd!(resources.get::<Stockpiles>().unwrap().amount(Faction::PLAYER) == 0);
+        assert_gd!(world.get_component::<Repair>(worker).is_none())
+    }
+}
@@ -1,8 +1,10 @@
 use gdnative::{InputEventMouseButton, Vector2};
 
+use crate::navigation::Cell;
+
 pub enum MouseButton {
     Empty,
-    Mouse { pressed: bool, button_index: i64 },
+    Mouse { pressed: bool, button_index: i64, shift: bool },
 }
 
 impl MouseButton {
@@ -14,17 +16,25 @@ impl MouseButton {
         Self::Mouse {
             pressed: ev.is_pressed(),
             button_index: ev.get_button_index(),
+            shift: ev.get_shift(),
         }
     }
 
     pub fn button_pressed(&self, index: i64) -> bool {
         match self {
             Self::Empty => false,
-            Self::Mouse { pressed, button_index } => {
+            Self::Mouse { pressed, button_index, .. } => {
                 *pressed && *button_index == index
             }
         }
     }
+
+    pub fn shift(&self) -> bool {
+        match self {
+            Self::Empty => false,
+            Self::Mouse { shift, .. } => *shift,
+        }
+    }
 }
 
 pub struct MousePos {
@@ -46,3 +56,102 @@ impl MousePos {
         }
     }
 }
+
+/// Input map actions the game world listens for
+pub const ACTIONS: &[&str] = &[
+    "ui_cancel",
+    "formation_line",
+    "formation_box",
+    "formation_wedge",
+    "attack_move",
+    "patrol",
+    "stop",
+    "hold_position",
+    "produce_1",
+    "produce_2",
+    "produce_3",
+    "cancel_production",
+    "research_1",
+    "research_2",
+    "research_3",
+    "build_base",
+    "build_barracks",
+    "build_depot",
+    "confirm_placement",
+    "cancel_placement",
+    "ability_1",
+    "ability_2",
+    "ability_3",
+    "pause_match",
+];
+
+/// The building the player is about to place, if any
+pub struct Placement {
+    pub blueprint: Option<String>,
+    /// Top left tile of the footprint under the mouse
+    pub tile: Cell,
+    /// Nothing in the way of the footprint
+    pub valid: bool,
+}
+
+impl Placement {
+    pub fn none() -> Self {
+        Self { blueprint: None, tile: (0, 0), valid: false }
+    }
+
+    pub fn start(&mut self, blueprint: &str) {
+        self.blueprint = Some(blueprint.to_string());
+        self.valid = false;
+    }
+
+    pub fn cancel(&mut self) {
+        *self = Self::none();
+    }
+}
+
+/// Ability waiting for the player to pick a target, if any
+pub struct Aiming {
+    pub ability: Option<String>,
+}
+
+impl Aiming {
+    pub fn none() -> Self {
+        Self { ability: None }
+    }
+
+    pub fn start(&mut self, ability: &str) {
+        self.ability = Some(ability.to_string());
+    }
+
+    pub fn cancel(&mut self) {
+        self.ability = None;
+    }
+}
+
+pub struct Actions {
+    pressed: Vec<&'static str>,
+}
+
+impl Actions {
+    pub fn empty() -> Self {
+        Self { pressed: Vec::new() }
+    }
+
+    pub fn press(&mut self, action: &'static str) {
+        if !self.pressed(action) {
+            self.pressed.push(action);
+        }
+    }
+
+    pub fn pressed(&self, action: &str) -> bool {
+        self.pressed.iter().any(|a| *a == action)
+    }
+
+    pub fn consume(&mut self, action: &str) {
+        self.pressed.retain(|a| *a != action);
+    }
+
+    pub fn clear(&mut self) {
+        self.pressed.clear();
+    }
+}
@@ -6,6 +6,30 @@ mod units;
 mod spawner;
 mod input;
 mod combat;
+mod orders;
+mod formation;
+mod steering;
+mod archetype;
+mod navigation;
+mod flowfield;
+mod movement;
+mod terrain;
+mod data;
+mod ai;
+mod behaviour;
+mod targeting;
+mod fog;
+mod economy;
+mod buildings;
+mod placement;
+mod tech;
+mod supply;
+mod effects;
+mod abilities;
+mod healing;
+mod veterancy;
+mod match_state;
+mod simulation;
 
 pub type Size2 = Size2D<f32, euclid::UnknownUnit>;
 
@@ -52,9 +76,52 @@ pub extern fn run_tests() -> sys::godot_variant {
     let mut status = true;
 
     eprintln!("Running tests");
-    status &= run_test!(units::tests::test_move_units);
+    status &= run_test!(units::tests::test_select_units);
+    status &= run_test!(units::tests::test_select_units_multiple_commands);
+    status &= run_test!(units::tests::test_deselect_units);
     status &= run_test!(combat::tests::test_target_unit);
+    status &= run_test!(combat::tests::test_target_hidden_unit);
     status &= run_test!(combat::tests::test_attack_target);
+    status &= run_test!(combat::tests::test_splash_damage);
+    status &= run_test!(combat::tests::test_weapons);
+    status &= run_test!(combat::tests::test_attack_move);
+    status &= run_test!(combat::tests::test_hold_position);
+    status &= run_test!(orders::tests::test_follow_orders);
+    status &= run_test!(orders::tests::test_patrol_route);
+    status &= run_test!(orders::tests::test_stop_units);
+    status &= run_test!(formation::tests::test_assign_slots);
+    status &= run_test!(steering::tests::test_steering);
+    status &= run_test!(flowfield::tests::test_flow_field);
+    status &= run_test!(movement::tests::test_movement);
+    status &= run_test!(terrain::tests::test_terrain);
+    status &= run_test!(ai::tests::test_form_squads);
+    status &= run_test!(ai::tests::test_ai_vs_ai);
+    status &= run_test!(behaviour::tests::test_behaviour_tree);
+    status &= run_test!(targeting::tests::test_score_targets);
+    status &= run_test!(fog::tests::test_fog_of_war);
+    status &= run_test!(economy::tests::test_harvest);
+    status &= run_test!(economy::tests::test_stockpile);
+    status &= run_test!(buildings::tests::test_production);
+    status &= run_test!(buildings::tests::test_destroy_building);
+    status &= run_test!(placement::tests::test_can_place);
+    status &= run_test!(placement::tests::test_place_building);
+    status &= run_test!(tech::tests::test_stat_modifiers);
+    status &= run_test!(tech::tests::test_research);
+    status &= run_test!(tech::tests::test_research_once);
+    status &= run_test!(supply::tests::test_supply_cap);
+    status &= run_test!(effects::tests::test_effect_stacking);
+    status &= run_test!(effects::tests::test_apply_effects);
+    status &= run_test!(effects::tests::test_poison_kill);
+    status &= run_test!(effects::tests::test_weapon_effects);
+    status &= run_test!(abilities::tests::test_area_ability);
+    status &= run_test!(abilities::tests::test_caster_ability);
+    status &= run_test!(abilities::tests::test_summon_refund);
+    status &= run_test!(healing::tests::test_heal_allies);
+    status &= run_test!(healing::tests::test_repair_building);
+    status &= run_test!(veterancy::tests::test_split_experience);
+    status &= run_test!(veterancy::tests::test_veterancy);
+    status &= run_test!(match_state::tests::test_match_flow);
+    status &= run_test!(match_state::tests::test_win_conditions);
 
     gdnative::Variant::from_bool(status).forget()
 }
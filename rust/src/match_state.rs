@@ -0,0 +1,436 @@
+//! Lobby, countdown, the match itself and who won it.
+use gdnative::{Dictionary, Variant, Vector2};
+use legion::prelude::*;
+use serde::Deserialize;
+
+use crate::buildings::Building;
+use crate::combat::Hitpoints;
+use crate::gameworld::Delta;
+use crate::input::Actions;
+use crate::units::{Faction, UnitPos};
+use crate::veterancy::Statistics;
+
+// -----------------------------------------------------------------------------
+//     - Data -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Deserialize)]
+pub enum WinCondition {
+    /// Last faction with units or buildings left wins
+    Annihilation,
+    /// The faction wins if it still has units or buildings after this many
+    /// seconds
+    Survive { faction: u32, seconds: f32 },
+    /// First faction to keep the zone to itself for this many seconds in a
+    /// row wins. Only units count, buildings don't hold a zone.
+    HoldZone { center: (f32, f32), radius: f32, seconds: f32 },
+}
+
+impl WinCondition {
+    /// The winners, once the condition is met. Nobody wins if everyone is
+    /// wiped out at once.
+    ///
+    /// `forces` are every unit and building left with its faction and
+    /// position, and whether it's a building. `held` is who has had the zone
+    /// to themselves, and for how long.
+    fn check(
+        &self,
+        forces: &[(Faction, Vector2, bool)],
+        factions: &[Faction],
+        elapsed: f32,
+        delta: f32,
+        held: &mut Option<(Faction, f32)>,
+    ) -> Option<Vec<Faction>> {
+        let standing = |faction: &Faction| forces.iter().any(|(f, _, _)| f == faction);
+
+        match self {
+            WinCondition::Annihilation => {
+                let left = factions.iter().copied().filter(standing).collect::<Vec<_>>();
+                if factions.len() > 1 && left.len() <= 1 {
+                    Some(left)
+                } else {
+                    None
+                }
+            }
+            WinCondition::Survive { faction, seconds } => {
+                let faction = Faction(*faction);
+                if elapsed >= *seconds && standing(&faction) {
+                    Some(vec![faction])
+                } else {
+                    None
+                }
+            }
+            WinCondition::HoldZone { center, radius, seconds } => {
+                let center = Vector2::new(center.0, center.1);
+                let mut inside = forces
+                    .iter()
+                    .filter(|(_, pos, building)| !building && (*pos - center).length() <= *radius)
+                    .map(|(faction, _, _)| *faction);
+
+                // Empty or contested, and whoever had it has to start over
+                let holder = match inside.next() {
+                    Some(first) if inside.all(|faction| faction == first) => first,
+                    _ => {
+                        *held = None;
+                        return None;
+                    }
+                };
+
+                let time = match held {
+                    Some((faction, time)) if *faction == holder => *time + delta,
+                    _ => delta,
+                };
+                *held = Some((holder, time));
+
+                if time >= *seconds {
+                    Some(vec![holder])
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// How a match is set up and won, loaded from `res://data/match.ron`
+#[derive(Debug, Clone, Deserialize)]
+pub struct MatchRules {
+    /// Wait in the lobby until the match is started, rather than going
+    /// straight to the countdown
+    #[serde(default)]
+    pub lobby: bool,
+    /// Seconds
+    pub countdown: f32,
+    pub conditions: Vec<WinCondition>,
+}
+
+impl Default for MatchRules {
+    fn default() -> Self {
+        Self {
+            lobby: false,
+            countdown: 3.,
+            conditions: vec![WinCondition::Annihilation],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchState {
+    Lobby,
+    /// Seconds until the match starts
+    Countdown(f32),
+    Running,
+    Paused,
+    Ended,
+}
+
+impl MatchState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MatchState::Lobby => "lobby",
+            MatchState::Countdown(_) => "countdown",
+            MatchState::Running => "running",
+            MatchState::Paused => "paused",
+            MatchState::Ended => "ended",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchResult {
+    Victory,
+    Defeat,
+}
+
+impl MatchResult {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MatchResult::Victory => "victory",
+            MatchResult::Defeat => "defeat",
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Decides which of the game world's schedules runs, so nothing moves before
+/// the countdown is over, while paused or once the match has ended
+pub struct Match {
+    rules: MatchRules,
+    state: MatchState,
+    /// Seconds the match has been running, pauses not included
+    elapsed: f32,
+    /// Every faction that has taken part
+    factions: Vec<Faction>,
+    /// Who has had each condition's zone to themselves, and for how long
+    held: Vec<Option<(Faction, f32)>>,
+    results: Vec<(Faction, MatchResult)>,
+    /// The match has ended and the results haven't been taken yet
+    ended: bool,
+}
+
+impl Match {
+    pub fn new(rules: MatchRules) -> Self {
+        let state = if rules.lobby {
+            MatchState::Lobby
+        } else {
+            MatchState::Countdown(rules.countdown)
+        };
+
+        Self {
+            held: vec![None; rules.conditions.len()],
+            rules,
+            state,
+            elapsed: 0.,
+            factions: Vec::new(),
+            results: Vec::new(),
+            ended: false,
+        }
+    }
+
+    pub fn state(&self) -> MatchState {
+        self.state
+    }
+
+    pub fn running(&self) -> bool {
+        self.state == MatchState::Running
+    }
+
+    pub fn elapsed(&self) -> f32 {
+        self.elapsed
+    }
+
+    /// Leave the lobby and start the countdown
+    pub fn start(&mut self) {
+        if self.state == MatchState::Lobby {
+            self.state = MatchState::Countdown(self.rules.countdown);
+        }
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.state = match self.state {
+            MatchState::Running => MatchState::Paused,
+            MatchState::Paused => MatchState::Running,
+            state => state,
+        };
+    }
+
+    /// Count down to the start, or keep the match clock going
+    pub fn tick(&mut self, delta: f32) {
+        match self.state {
+            MatchState::Countdown(left) if left <= delta => self.state = MatchState::Running,
+            MatchState::Countdown(left) => self.state = MatchState::Countdown(left - delta),
+            MatchState::Running => self.elapsed += delta,
+            _ => {}
+        }
+    }
+
+    /// End the match if any win condition is met
+    pub fn judge(&mut self, forces: &[(Faction, Vector2, bool)], delta: f32) {
+        if !self.running() {
+            return;
+        }
+
+        for (faction, _, _) in forces {
+            if !self.factions.contains(faction) {
+                self.factions.push(*faction);
+            }
+        }
+
+        let mut winners = None;
+        for (condition, held) in self.rules.conditions.iter().zip(self.held.iter_mut()) {
+            winners = condition.check(forces, &self.factions, self.elapsed, delta, held);
+            if winners.is_some() {
+                break;
+            }
+        }
+
+        if let Some(winners) = winners {
+            self.end(&winners);
+        }
+    }
+
+    pub fn end(&mut self, winners: &[Faction]) {
+        self.results = self
+            .factions
+            .iter()
+            .map(|faction| {
+                let result = if winners.contains(faction) { MatchResult::Victory } else { MatchResult::Defeat };
+                (*faction, result)
+            })
+            .collect();
+        self.state = MatchState::Ended;
+        self.ended = true;
+    }
+
+    pub fn results(&self) -> &[(Faction, MatchResult)] {
+        &self.results
+    }
+
+    /// The results, once, when the match has just ended
+    pub fn take_results(&mut self) -> Option<Vec<(Faction, MatchResult)>> {
+        if !self.ended {
+            return None;
+        }
+        self.ended = false;
+        Some(self.results.clone())
+    }
+}
+
+/// Result, kills and losses by faction id, see `FactionStats::to_dictionary`
+pub fn results_to_dictionary(results: &[(Faction, MatchResult)], statistics: &Statistics) -> Dictionary {
+    let mut dict = Dictionary::new();
+    for (faction, result) in results {
+        let mut entry = statistics.get(*faction).to_dictionary();
+        entry.set(&Variant::from_str("result"), &Variant::from_str(result.name()));
+        dict.set(&Variant::from_u64(faction.0 as u64), &Variant::from_dictionary(&entry));
+    }
+    dict
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+/// Count down to the start, run the match clock and pause on the
+/// `pause_match` action. Runs whatever the state.
+pub fn update_match() -> Box<dyn Schedulable> {
+    SystemBuilder::new("update match")
+        .read_resource::<Delta>()
+        .write_resource::<Actions>()
+        .write_resource::<Match>()
+        .build(|_, _, (delta, actions, game), _| {
+            if actions.pressed("pause_match") {
+                actions.consume("pause_match");
+                game.toggle_pause();
+            }
+
+            game.tick(delta.0);
+        })
+}
+
+pub fn check_win_conditions() -> Box<dyn Schedulable> {
+    SystemBuilder::new("check win conditions")
+        .read_resource::<Delta>()
+        .write_resource::<Match>()
+        .read_component::<Building>()
+        .with_query(<(Read<Faction>, Read<UnitPos>)>::query().filter(component::<Hitpoints>()))
+        .build(|_, world, (delta, game), query| {
+            let forces = query
+                .iter_entities(world)
+                .map(|(entity, (faction, pos))| {
+                    (*faction, pos.0, world.get_component::<Building>(entity).is_some())
+                })
+                .collect::<Vec<_>>();
+
+            game.judge(&forces, delta.0);
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use crate::data;
+    use super::*;
+
+    // Lobby, countdown, running and paused, with the clock only running
+    // while the match does
+    pub fn test_match_flow() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(Delta(1.));
+        resources.insert(Actions::empty());
+        resources.insert(Match::new(MatchRules { lobby: true, ..MatchRules::default() }));
+
+        let mut sched = Schedule::builder().add_system(update_match()).build();
+
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(resources.get::<Match>().unwrap().state() == MatchState::Lobby);
+
+        resources.get_mut::<Match>().unwrap().start();
+        for _ in 0..3 {
+            sched.execute(&mut world, &mut resources);
+        }
+        assert_gd!(resources.get::<Match>().unwrap().running());
+
+        sched.execute(&mut world, &mut resources);
+        resources.get_mut::<Actions>().unwrap().press("pause_match");
+        sched.execute(&mut world, &mut resources);
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(resources.get::<Match>().unwrap().state() == MatchState::Paused);
+        assert_gd!(resources.get::<Match>().unwrap().elapsed() == 1.);
+
+        resources.get_mut::<Actions>().unwrap().press("pause_match");
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(resources.get::<Match>().unwrap().running());
+        assert_gd!(resources.get::<Match>().unwrap().elapsed() == 2.)
+    }
+
+    // Wiping out the enemy wins, as does holding the zone or surviving long
+    // enough
+    pub fn test_win_conditions() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(Delta(1.));
+
+        let rules = data::parse::<MatchRules>(r#"(
+            countdown: 0.0,
+            conditions: [
+                Annihilation,
+                HoldZone(center: (0.0, 0.0), radius: 50.0, seconds: 2.0),
+            ],
+        )"#).unwrap();
+        let mut game = Match::new(rules.clone());
+        game.tick(0.);
+        resources.insert(game);
+
+        let ents = world.insert((), vec![
+            (Faction(0), UnitPos(Vector2::new(0., 0.)), Hitpoints(10)),
+            (Faction(1), UnitPos(Vector2::new(200., 0.)), Hitpoints(10)),
+        ]).to_vec();
+
+        let mut sched = Schedule::builder().add_system(check_win_conditions()).build();
+
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(resources.get::<Match>().unwrap().running());
+
+        world.delete(ents[1]);
+        sched.execute(&mut world, &mut resources);
+        let mut game = resources.get_mut::<Match>().unwrap();
+        assert_gd!(game.state() == MatchState::Ended);
+        assert_gd!(game.results().contains(&(Faction(0), MatchResult::Victory)));
+        assert_gd!(game.results().contains(&(Faction(1), MatchResult::Defeat)));
+        assert_gd!(game.take_results().is_some());
+        assert_gd!(game.take_results().is_none());
+
+        // Contesting the zone starts the clock over
+        let mut game = Match::new(rules);
+        game.tick(0.);
+        let alone = [(Faction(0), Vector2::zero(), false), (Faction(1), Vector2::new(200., 0.), false)];
+        let contested = [(Faction(0), Vector2::zero(), false), (Faction(1), Vector2::new(10., 0.), false)];
+
+        game.judge(&alone, 1.);
+        game.judge(&contested, 1.);
+        game.judge(&alone, 1.);
+        assert_gd!(game.running());
+        game.judge(&alone, 1.);
+        assert_gd!(game.state() == MatchState::Ended);
+        assert_gd!(game.results().contains(&(Faction(0), MatchResult::Victory)));
+
+        // Still standing when the time is up wins
+        let rules = data::parse::<MatchRules>(r#"(
+            countdown: 0.0,
+            conditions: [Survive(faction: 1, seconds: 10.0)],
+        )"#).unwrap();
+        let mut game = Match::new(rules);
+        game.tick(0.);
+
+        game.tick(9.);
+        game.judge(&alone, 9.);
+        assert_gd!(game.running());
+        game.tick(1.);
+        game.judge(&alone, 1.);
+        assert_gd!(game.state() == MatchState::Ended);
+        assert_gd!(game.results().contains(&(Faction(1), MatchResult::Victory)));
+        assert_gd!(game.results().contains(&(Faction(0), MatchResult::Defeat)))
+    }
+}
@@ -0,0 +1,144 @@
+use std::f32::consts::PI;
+
+use gdnative::Vector2;
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModifierSource {
+    Terrain,
+    Slow,
+    /// Researched by the unit's faction
+    Upgrade,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SpeedModifier {
+    pub source: ModifierSource,
+    pub factor: f32,
+}
+
+/// How fast a unit can move and turn, and how fast it's currently going.
+#[derive(Debug, Clone)]
+pub struct Movement {
+    pub max_speed: f32,
+    pub acceleration: f32,
+    pub deceleration: f32,
+    /// Radians per second
+    pub turn_rate: f32,
+    pub velocity: Vector2,
+    /// Angle the unit is facing, in radians
+    pub facing: f32,
+    pub modifiers: Vec<SpeedModifier>,
+}
+
+impl Movement {
+    pub fn new(max_speed: f32, acceleration: f32, deceleration: f32, turn_rate: f32) -> Self {
+        Self {
+            max_speed,
+            acceleration,
+            deceleration,
+            turn_rate,
+            velocity: Vector2::zero(),
+            facing: 0.,
+            modifiers: Vec::new(),
+        }
+    }
+
+    /// Max speed after all modifiers are applied
+    pub fn speed(&self) -> f32 {
+        self.modifiers
+            .iter()
+            .fold(self.max_speed, |speed, modifier| speed * modifier.factor)
+    }
+
+    pub fn add_modifier(&mut self, source: ModifierSource, factor: f32) {
+        self.modifiers.push(SpeedModifier { source, factor });
+    }
+
+    /// Replace every modifier from `source` with a single one
+    pub fn set_modifier(&mut self, source: ModifierSource, factor: f32) {
+        self.clear_modifier(source);
+        self.add_modifier(source, factor);
+    }
+
+    pub fn clear_modifier(&mut self, source: ModifierSource) {
+        self.modifiers.retain(|modifier| modifier.source != source);
+    }
+
+    pub fn facing_direction(&self) -> Vector2 {
+        Vector2::new(self.facing.cos(), self.facing.sin())
+    }
+
+    /// Turn and accelerate towards `desired`, returning the new velocity
+    pub fn update(&mut self, desired: Vector2, delta: f32) -> Vector2 {
+        let target_speed = desired.length().min(self.speed());
+        if target_speed > 0. {
+            let target_angle = desired.y.atan2(desired.x);
+            self.facing = turn_towards(self.facing, target_angle, self.turn_rate * delta);
+        }
+
+        let current_speed = self.velocity.length();
+        let rate = if target_speed > current_speed {
+            self.acceleration
+        } else {
+            self.deceleration
+        };
+
+        let speed = approach(current_speed, target_speed, rate * delta);
+        self.velocity = self.facing_direction() * speed;
+        self.velocity
+    }
+}
+
+fn approach(from: f32, to: f32, max_step: f32) -> f32 {
+    if from < to {
+        (from + max_step).min(to)
+    } else {
+        (from - max_step).max(to)
+    }
+}
+
+fn turn_towards(from: f32, to: f32, max_step: f32) -> f32 {
+    let mut diff = (to - from) % (PI * 2.);
+    if diff > PI {
+        diff -= PI * 2.;
+    } else if diff < -PI {
+        diff += PI * 2.;
+    }
+
+    if diff.abs() <= max_step {
+        to
+    } else {
+        from + max_step * diff.signum()
+    }
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use super::*;
+
+    // Units should speed up over time and be slowed down by modifiers
+    pub fn test_movement() -> bool {
+        let mut movement = Movement::new(100., 200., 400., PI);
+
+        let velocity = movement.update(Vector2::new(100., 0.), 0.25);
+        assert_gd!(velocity.length() == 50.);
+
+        let velocity = movement.update(Vector2::new(100., 0.), 0.5);
+        assert_gd!(velocity.length() == 100.);
+
+        movement.add_modifier(ModifierSource::Slow, 0.5);
+        movement.add_modifier(ModifierSource::Terrain, 0.5);
+        assert_gd!(movement.speed() == 25.);
+
+        movement.set_modifier(ModifierSource::Terrain, 1.);
+        assert_gd!(movement.speed() == 50.);
+
+        // Turning is limited by the turn rate
+        movement.update(Vector2::new(0., 100.), 0.25);
+        assert_gd!((movement.facing - PI / 4.).abs() < 0.001)
+    }
+}
@@ -0,0 +1,99 @@
+use gdnative::Vector2;
+
+pub type Cell = (i32, i32);
+
+/// Movement cost of a cell that can't be entered
+pub const IMPASSABLE: f32 = std::f32::INFINITY;
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Grid of movement costs covering the world.
+/// Every change bumps the version so anything derived from the grid
+/// (like flow fields) knows to rebuild.
+#[derive(Clone)]
+pub struct NavGrid {
+    origin: Vector2,
+    cell_size: f32,
+    width: i32,
+    height: i32,
+    costs: Vec<f32>,
+    version: u32,
+}
+
+impl NavGrid {
+    pub fn new(origin: Vector2, cell_size: f32, width: i32, height: i32) -> Self {
+        Self {
+            origin,
+            cell_size,
+            width,
+            height,
+            costs: vec![1.; (width * height) as usize],
+            version: 0,
+        }
+    }
+
+    /// Top left corner of the grid
+    pub fn origin(&self) -> Vector2 {
+        self.origin
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn cell(&self, pos: Vector2) -> Option<Cell> {
+        let local = (pos - self.origin) / self.cell_size;
+        let cell = (local.x.floor() as i32, local.y.floor() as i32);
+        self.index(cell).map(|_| cell)
+    }
+
+    pub fn center(&self, cell: Cell) -> Vector2 {
+        self.origin
+            + Vector2::new(cell.0 as f32 + 0.5, cell.1 as f32 + 0.5) * self.cell_size
+    }
+
+    pub fn index(&self, cell: Cell) -> Option<usize> {
+        if cell.0 < 0 || cell.1 < 0 || cell.0 >= self.width || cell.1 >= self.height {
+            return None;
+        }
+        Some((cell.1 * self.width + cell.0) as usize)
+    }
+
+    pub fn cost(&self, cell: Cell) -> f32 {
+        self.index(cell).map(|i| self.costs[i]).unwrap_or(IMPASSABLE)
+    }
+
+    pub fn passable(&self, cell: Cell) -> bool {
+        self.cost(cell) < IMPASSABLE
+    }
+
+    pub fn set_cost(&mut self, cell: Cell, cost: f32) {
+        if let Some(i) = self.index(cell) {
+            if self.costs[i] != cost {
+                self.costs[i] = cost;
+                self.version += 1;
+            }
+        }
+    }
+
+    pub fn block(&mut self, cell: Cell) {
+        self.set_cost(cell, IMPASSABLE);
+    }
+
+    pub fn unblock(&mut self, cell: Cell) {
+        self.set_cost(cell, 1.);
+    }
+}
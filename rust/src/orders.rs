@@ -0,0 +1,365 @@
+use std::collections::VecDeque;
+
+use gdnative::{Line2D, Vector2};
+use legion::prelude::*;
+
+use crate::combat::{AttackMoving, Hitpoints, Target};
+use crate::economy::Harvest;
+use crate::gameworld::{Selected, WorldNode};
+use crate::healing::Repair;
+use crate::input::Actions;
+use crate::spawner;
+use crate::units::{clear_current_order, Destination, UnitPos, UnitRect};
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone)]
+pub enum Order {
+    Move(Destination),
+    Attack(Entity),
+    AttackMove(Destination),
+    Patrol(Vec<Vector2>),
+    Hold,
+    Stop,
+}
+
+pub struct Orders(pub VecDeque<Order>);
+
+/// Attack anything in range but never move
+pub struct Holding;
+
+impl Orders {
+    pub fn new() -> Self {
+        Self(VecDeque::new())
+    }
+
+    pub fn push(&mut self, order: Order) {
+        self.0.push_back(order);
+    }
+
+    /// Queue up a shift-clicked order. Another patrol extends the route at
+    /// the back of the queue rather than starting a new one.
+    pub fn queue(&mut self, order: Order) {
+        if let (Some(Order::Patrol(route)), Order::Patrol(points)) = (self.0.back_mut(), &order) {
+            route.extend(points.iter().skip(1));
+            return;
+        }
+
+        self.0.push_back(order);
+    }
+
+    pub fn replace(&mut self, order: Order) {
+        self.0.clear();
+        self.0.push_back(order);
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// What kind of order the next command click gives
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandMode {
+    Move,
+    AttackMove,
+    Patrol,
+}
+
+pub struct WaypointLine(pub Line2D);
+
+unsafe impl Send for WaypointLine {}
+unsafe impl Sync for WaypointLine {}
+
+impl Drop for WaypointLine {
+    fn drop(&mut self) {
+        unsafe { self.0.queue_free() };
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+pub fn follow_orders() -> Box<dyn Schedulable> {
+    SystemBuilder::new("follow orders")
+        .read_component::<Holding>()
+        .with_query(
+            <Write<Orders>>::query().filter(
+                !component::<Destination>()
+                    & !component::<Target>()
+                    & !component::<AttackMoving>(),
+            ),
+        )
+        .build(|cmd, world, _, query| {
+            for (entity, mut orders) in query.iter_entities_mut(world) {
+                let order = match orders.0.pop_front() {
+                    None => continue,
+                    Some(o) => o,
+                };
+
+                // Anything queued after a hold ends it
+                if world.get_component::<Holding>(entity).is_some() {
+                    cmd.remove_component::<Holding>(entity);
+                }
+
+                match order {
+                    Order::Move(dest) => {
+                        cmd.add_component(entity, dest);
+                    }
+                    Order::AttackMove(dest) => {
+                        cmd.add_component(entity, dest);
+                        cmd.add_component(entity, AttackMoving::new(dest));
+                    }
+                    Order::Attack(target) => {
+                        cmd.add_component(entity, Target(target));
+                    }
+                    Order::Patrol(mut points) => {
+                        if points.is_empty() {
+                            continue;
+                        }
+                        // Each leg of a patrol is an attack move
+                        let dest = Destination::new(points[0]);
+                        cmd.add_component(entity, dest);
+                        cmd.add_component(entity, AttackMoving::new(dest));
+
+                        // Keep patrolling by sending the first point to the back.
+                        // Its own route, never merged into a patrol queued after it.
+                        points.rotate_left(1);
+                        orders.0.push_back(Order::Patrol(points));
+                    }
+                    Order::Hold => cmd.add_component(entity, Holding),
+                    Order::Stop => orders.0.clear(),
+                }
+            }
+        })
+}
+
+pub fn stop_units() -> Box<dyn Schedulable> {
+    SystemBuilder::new("stop units")
+        .write_resource::<Actions>()
+        .write_component::<Orders>()
+        .read_component::<Destination>()
+        .read_component::<Target>()
+        .read_component::<AttackMoving>()
+        .read_component::<Holding>()
+        .read_component::<Harvest>()
+        .read_component::<Repair>()
+        .with_query(<Read<UnitRect>>::query().filter(tag::<Selected>()))
+        .build(|cmd, world, actions, query| {
+            let stop = actions.pressed("stop");
+            let hold = actions.pressed("hold_position");
+            if !stop && !hold {
+                return;
+            }
+
+            actions.consume("stop");
+            actions.consume("hold_position");
+
+            let selected = query
+                .iter_entities(world)
+                .map(|(ent, _)| ent)
+                .collect::<Vec<_>>();
+
+            for entity in selected {
+                clear_current_order(cmd, world, entity);
+                if let Some(mut orders) = world.get_component_mut::<Orders>(entity) {
+                    orders.0.clear();
+                }
+
+                if hold {
+                    cmd.add_component(entity, Holding);
+                }
+            }
+        })
+}
+
+pub fn choose_command_mode() -> Box<dyn Schedulable> {
+    SystemBuilder::new("choose command mode")
+        .write_resource::<Actions>()
+        .write_resource::<CommandMode>()
+        .build(|_, _, (actions, mode), _| {
+            let choices = [
+                ("attack_move", CommandMode::AttackMove),
+                ("patrol", CommandMode::Patrol),
+            ];
+
+            for (action, choice) in choices.iter() {
+                if actions.pressed(action) {
+                    actions.consume(action);
+                    **mode = *choice;
+                }
+            }
+        })
+}
+
+pub fn draw_waypoints() -> Box<dyn Runnable> {
+    SystemBuilder::new("draw waypoints")
+        .write_resource::<WorldNode>()
+        .read_component::<UnitPos>()
+        .read_component::<Hitpoints>()
+        .read_component::<Destination>()
+        .read_component::<Target>()
+        .with_query(<(Read<UnitPos>, Read<Orders>, Write<WaypointLine>)>::query()
+            .filter(tag::<Selected>()))
+        .with_query(<(Read<UnitPos>, Read<Orders>)>::query()
+            .filter(tag::<Selected>() & !component::<WaypointLine>()))
+        .with_query(<Read<WaypointLine>>::query().filter(!tag::<Selected>()))
+        .build_thread_local(|cmd, world, world_node, (lines, missing, deselected)| {
+            for (entity, _) in deselected.iter_entities(world) {
+                cmd.remove_component::<WaypointLine>(entity);
+            }
+
+            for (entity, _) in missing.iter_entities(world) {
+                let line = spawner::create_waypoint_line();
+                unsafe { world_node.add_child(line.to_node()) };
+                cmd.add_component(entity, WaypointLine(line));
+            }
+
+            let mut updates = Vec::new();
+            for (entity, (pos, orders, _)) in lines.iter_entities(world) {
+                let mut points = vec![pos.0];
+                if let Some(dest) = world.get_component::<Destination>(entity) {
+                    points.push(dest.pos);
+                }
+                if let Some(target) = world.get_component::<Target>(entity) {
+                    target_pos(world, target.0).map(|p| points.push(p));
+                }
+
+                for order in orders.0.iter() {
+                    match order {
+                        Order::Move(dest) | Order::AttackMove(dest) => points.push(dest.pos),
+                        Order::Patrol(patrol) => points.extend(patrol.iter()),
+                        Order::Attack(target) => {
+                            target_pos(world, *target).map(|p| points.push(p));
+                        }
+                        Order::Hold | Order::Stop => {}
+                    }
+                }
+
+                updates.push((entity, points));
+            }
+
+            for (entity, points) in updates {
+                if let Some(mut line) = world.get_component_mut::<WaypointLine>(entity) {
+                    unsafe {
+                        line.0.clear_points();
+                        for point in points {
+                            line.0.add_point(point, -1);
+                        }
+                    }
+                }
+            }
+        })
+}
+
+fn target_pos(world: &SubWorld, target: Entity) -> Option<Vector2> {
+    world.get_component::<Hitpoints>(target)?;
+    world.get_component::<UnitPos>(target).map(|pos| pos.0)
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use super::*;
+
+    // Units should advance to the next order once the current one is done
+    pub fn test_follow_orders() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+
+        let mut orders = Orders::new();
+        orders.push(Order::Move(Destination::new(Vector2::new(10., 10.))));
+        orders.push(Order::Move(Destination::new(Vector2::new(20., 20.))));
+
+        let entity = world.insert((), vec![(orders,)])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(follow_orders())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+
+        assert_gd!(world.get_component::<Orders>(entity).unwrap().0.len() == 1);
+        assert_gd!(world.get_component::<Destination>(entity).unwrap().pos == Vector2::new(10., 10.));
+
+        // Destination still active, the next order has to wait
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Orders>(entity).unwrap().0.len() == 1);
+
+        // Arrive and pick up the next order
+        let _ = world.remove_component::<Destination>(entity);
+        sched.execute(&mut world, &mut resources);
+
+        assert_gd!(world.get_component::<Destination>(entity).unwrap().pos == Vector2::new(20., 20.))
+    }
+
+    // A patrol going round again keeps its own route, even with another
+    // patrol queued behind it
+    pub fn test_patrol_route() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+
+        let p = vec![Vector2::new(0., 0.), Vector2::new(10., 0.)];
+        let q = vec![Vector2::new(50., 50.), Vector2::new(60., 50.)];
+
+        let mut orders = Orders::new();
+        orders.push(Order::Patrol(p.clone()));
+        orders.push(Order::Move(Destination::new(Vector2::new(20., 20.))));
+        orders.push(Order::Patrol(q.clone()));
+
+        let entity = world.insert((), vec![(orders,)])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(follow_orders())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+
+        let orders = world.get_component::<Orders>(entity).unwrap();
+        assert_gd!(orders.0.len() == 3);
+        match (&orders.0[1], &orders.0[2]) {
+            (Order::Patrol(second), Order::Patrol(third)) => {
+                assert_gd!(*second == q);
+                assert_gd!(*third == vec![p[1], p[0]])
+            }
+            _ => false,
+        }
+    }
+
+    // Stopping should clear the current and queued orders
+    pub fn test_stop_units() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        let mut actions = Actions::empty();
+        actions.press("stop");
+        resources.insert(actions);
+
+        let mut orders = Orders::new();
+        orders.push(Order::Move(Destination::new(Vector2::new(20., 20.))));
+
+        let entity = world.insert((Selected,), vec![(
+                UnitRect::new(Vector2::zero(), 10., 10.),
+                Destination::new(Vector2::new(10., 10.)),
+                orders,
+        ),])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(stop_units())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+
+        assert_gd!(world.get_component::<Destination>(entity).is_none());
+        assert_gd!(world.get_component::<Orders>(entity).unwrap().0.is_empty());
+        assert_gd!(world.get_component::<Holding>(entity).is_none());
+
+        // Hold position
+        resources.get_mut::<Actions>().map(|mut actions| actions.press("hold_position"));
+        sched.execute(&mut world, &mut resources);
+
+        assert_gd!(world.get_component::<Holding>(entity).is_some())
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashSet;
+
+use gdnative::{Color, Polygon2D, Rect2};
+use legion::prelude::*;
+
+use crate::buildings::{centered_tile, footprint, footprint_rect, insert_building, Blueprint, Blueprints, Footprint};
+use crate::economy::Stockpiles;
+use crate::gameworld::{Notices, WorldNode};
+use crate::input::{Actions, MouseButton, MousePos, Placement};
+use crate::navigation::Cell;
+use crate::spawner::create_ghost;
+use crate::terrain::Terrain;
+use crate::units::{Faction, UnitRect};
+
+/// Actions that start placing a building, and the blueprint they place
+pub const BUILD_ACTIONS: &[(&str, &str)] = &[
+    ("build_base", "base"),
+    ("build_barracks", "barracks"),
+    ("build_depot", "depot"),
+];
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Preview of the building being placed, and the blueprint it shows
+pub struct Ghost(Option<(String, Polygon2D)>);
+
+impl Ghost {
+    pub fn new() -> Self {
+        Self(None)
+    }
+}
+
+unsafe impl Send for Ghost {}
+unsafe impl Sync for Ghost {}
+
+/// A building fits if the ground is walkable and nothing is standing there
+pub fn can_place(
+    blueprint: &Blueprint,
+    terrain: &Terrain,
+    tile: Cell,
+    rects: &[Rect2],
+    occupied: &HashSet<Cell>,
+) -> bool {
+    let on_open_ground = footprint(blueprint, tile)
+        .iter()
+        .all(|cell| terrain.passable(*cell) && !occupied.contains(cell));
+
+    let rect = footprint_rect(blueprint, terrain, tile);
+    on_open_ground && !rects.iter().any(|other| other.intersects(&rect))
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+/// Pick a building, move it around with the mouse and left click (or the
+/// `confirm_placement` action) to build it there. Right click or
+/// `cancel_placement` puts it away.
+pub fn place_building() -> Box<dyn Schedulable> {
+    SystemBuilder::new("place building")
+        .write_resource::<Placement>()
+        .write_resource::<Actions>()
+        .write_resource::<MouseButton>()
+        .read_resource::<MousePos>()
+        .read_resource::<Terrain>()
+        .read_resource::<Blueprints>()
+        .write_resource::<Stockpiles>()
+        .write_resource::<Notices>()
+        .with_query(<Read<UnitRect>>::query())
+        .with_query(<Read<Footprint>>::query())
+        .build(|cmd, world, resources, (rects, footprints)| {
+            let (placement, actions, mouse_btn, mouse_pos, terrain, blueprints, stockpiles, notices) = resources;
+
+            for (action, blueprint) in BUILD_ACTIONS {
+                if actions.pressed(action) {
+                    placement.start(blueprint);
+                    actions.consume(action);
+                }
+            }
+
+            let blueprint = match placement.blueprint.as_ref().and_then(|name| blueprints.get(name)) {
+                Some(b) => b,
+                None => return,
+            };
+
+            if actions.pressed("cancel_placement") || mouse_btn.button_pressed(2) {
+                placement.cancel();
+                actions.consume("cancel_placement");
+                // Cancel shares its key with ui_cancel
+                actions.consume("ui_cancel");
+                mouse_btn.consume();
+                return;
+            }
+
+            let tile = match centered_tile(blueprint, terrain, mouse_pos.global()) {
+                Some(tile) => tile,
+                None => return,
+            };
+
+            let rects = rects.iter(world).map(|rect| rect.0).collect::<Vec<_>>();
+            let occupied = footprints
+                .iter(world)
+                .flat_map(|footprint| footprint.0.clone())
+                .collect::<HashSet<_>>();
+
+            placement.tile = tile;
+            placement.valid = can_place(blueprint, terrain, tile, &rects, &occupied);
+
+            if !actions.pressed("confirm_placement") && !mouse_btn.button_pressed(1) {
+                return;
+            }
+            actions.consume("confirm_placement");
+            mouse_btn.consume();
+
+            if !placement.valid {
+                notices.push(format!("Can't build a {} there", blueprint.name));
+            } else if stockpiles.spend(Faction::PLAYER, blueprint.cost) {
+                insert_building(cmd, blueprint, terrain, tile, Faction::PLAYER);
+                placement.cancel();
+            } else {
+                notices.push(format!("Not enough minerals for a {}", blueprint.name));
+            }
+        })
+}
+
+/// Show the building being placed under the mouse, green if it fits and red
+/// if it doesn't
+pub fn draw_placement() -> Box<dyn Runnable> {
+    SystemBuilder::new("draw placement")
+        .write_resource::<WorldNode>()
+        .write_resource::<Ghost>()
+        .read_resource::<Placement>()
+        .read_resource::<Terrain>()
+        .read_resource::<Blueprints>()
+        .build_thread_local(|_, _, (world_node, ghost, placement, terrain, blueprints), _| {
+            let blueprint = placement.blueprint.as_ref().and_then(|name| blueprints.get(name));
+
+            // Remove the ghost once done placing, or when placing something else
+            let stale = match (&ghost.0, blueprint) {
+                (Some((name, _)), Some(blueprint)) => *name != blueprint.name,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+
+            if stale {
+                if let Some((_, mut node)) = ghost.0.take() {
+                    unsafe { node.queue_free() };
+                }
+            }
+
+            let blueprint = match blueprint {
+                Some(b) => b,
+                None => return,
+            };
+
+            if ghost.0.is_none() {
+                let node = create_ghost(footprint_rect(blueprint, terrain, (0, 0)));
+                unsafe { world_node.add_child(node.to_node()) };
+                ghost.0 = Some((blueprint.name.clone(), node));
+            }
+
+            if let Some((_, node)) = ghost.0.as_mut() {
+                let color = if placement.valid {
+                    Color::rgba(0.2, 1., 0.2, 0.5)
+                } else {
+                    Color::rgba(1., 0.2, 0.2, 0.5)
+                };
+
+                let offset = terrain.tile_origin(placement.tile) - terrain.tile_origin((0, 0));
+                unsafe {
+                    node.set_position(offset);
+                    node.set_color(color);
+                }
+            }
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use crate::navigation::IMPASSABLE;
+    use crate::simulation::flat_terrain;
+    use crate::units::UnitPos;
+    use super::*;
+
+    // Footprints can't go on blocked ground or on top of anything
+    pub fn test_can_place() -> bool {
+        let mut terrain = flat_terrain();
+        terrain.set_cost((20, 20), IMPASSABLE);
+        let barracks = Blueprint::barracks();
+
+        let unit = UnitRect::new(terrain.tile_origin((31, 30)), 8., 8.);
+        let mut occupied = HashSet::new();
+        occupied.insert((41, 40));
+
+        assert_gd!(can_place(&barracks, &terrain, (10, 10), &[unit.0], &occupied));
+        assert_gd!(!can_place(&barracks, &terrain, (19, 19), &[unit.0], &occupied));
+        assert_gd!(!can_place(&barracks, &terrain, (30, 30), &[unit.0], &occupied));
+        assert_gd!(!can_place(&barracks, &terrain, (40, 40), &[unit.0], &occupied));
+        // Off the map
+        assert_gd!(!can_place(&barracks, &terrain, (62, 62), &[unit.0], &occupied))
+    }
+
+    // Confirming a valid spot builds the building and pays for it
+    pub fn test_place_building() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        let terrain = flat_terrain();
+        let pos = terrain.tile_origin((10, 10));
+        let mut mouse_pos = MousePos::zero();
+        mouse_pos.set_global(pos);
+
+        resources.insert(Placement::none());
+        resources.insert(Actions::empty());
+        resources.insert(MouseButton::Empty);
+        resources.insert(mouse_pos);
+        resources.insert(terrain);
+        resources.insert(Blueprints::new());
+        resources.insert(Stockpiles::new(1000));
+        resources.insert(Notices::new());
+
+        let mut sched = Schedule::builder()
+            .add_system(place_building())
+            .flush()
+            .build();
+
+        resources.get_mut::<Actions>().map(|mut actions| actions.press("build_barracks"));
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(resources.get::<Placement>().unwrap().valid);
+
+        resources.insert(MouseButton::Mouse { pressed: true, button_index: 1, shift: false });
+        sched.execute(&mut world, &mut resources);
+
+        let cost = Blueprint::barracks().cost;
+        let buildings = <Read<UnitPos>>::query().filter(component::<Footprint>()).iter(&world).count();
+        assert_gd!(buildings == 1);
+        assert_gd!(resources.get::<Stockpiles>().unwrap().amount(Faction::PLAYER) == 1000 - cost);
+        assert_gd!(resources.get::<Placement>().unwrap().blueprint.is_none());
+
+        // The same spot is taken now
+        resources.get_mut::<Actions>().map(|mut actions| actions.press("build_barracks"));
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(!resources.get::<Placement>().unwrap().valid)
+    }
+}
@@ -0,0 +1,119 @@
+//! The parts of the game that don't need Godot, so they can be stepped
+//! headless, e.g. to run AI against AI in tests.
+use gdnative::Vector2;
+use legion::prelude::*;
+
+use crate::abilities::{cast_abilities, Abilities};
+use crate::ai::{ai_attack, ai_form_squads, ai_retreat, ai_spawn_units};
+use crate::archetype::Archetypes;
+use crate::buildings::{block_footprints, produce_units, BlockedCells, Blueprints};
+use crate::economy::{harvest, Stockpiles, STARTING_MINERALS};
+use crate::behaviour::{run_behaviours, BehaviourTrees};
+use crate::combat::{attack_move, attack_targets, cooldown_units, discard_shots, hold_position};
+use crate::effects::{apply_effects, expire_effects, StatusEffects};
+use crate::flowfield::FlowFields;
+use crate::gameworld::{Delta, Notices};
+use crate::healing::{heal_allies, repair_buildings, HealEvents};
+use crate::input::Actions;
+use crate::match_state::{check_win_conditions, update_match, Match, MatchRules};
+use crate::orders::follow_orders;
+use crate::supply::{count_supply, Supply, STARTING_SUPPLY};
+use crate::tech::{apply_upgrades, research_techs, TechTree, Upgrades};
+use crate::terrain::Terrain;
+use crate::units::{integrate_positions, steer_units};
+use crate::veterancy::{award_experience, Ranks, Statistics};
+
+const CELL_SIZE: f32 = 16.;
+const GRID_SIZE: i32 = 64;
+
+/// Flat ground centered on the world origin
+pub fn flat_terrain() -> Terrain {
+    let half_size = GRID_SIZE as f32 * CELL_SIZE / 2.;
+    Terrain::new(
+        Vector2::new(-half_size, -half_size),
+        CELL_SIZE,
+        GRID_SIZE,
+        GRID_SIZE,
+    )
+}
+
+pub fn resources() -> Resources {
+    let mut resources = Resources::default();
+    let terrain = flat_terrain();
+
+    // No lobby or countdown when nobody is watching
+    let mut game = Match::new(MatchRules { countdown: 0., ..MatchRules::default() });
+    game.tick(0.);
+
+    resources.insert(Delta(0.));
+    resources.insert(Actions::empty());
+    resources.insert(game);
+    resources.insert(Archetypes::new());
+    resources.insert(BehaviourTrees::new());
+    resources.insert(Blueprints::new());
+    resources.insert(Stockpiles::new(STARTING_MINERALS));
+    resources.insert(Supply::new(STARTING_SUPPLY));
+    resources.insert(HealEvents::new());
+    resources.insert(Notices::new());
+    resources.insert(Ranks::new());
+    resources.insert(Statistics::new());
+    resources.insert(StatusEffects::new());
+    resources.insert(TechTree::new());
+    resources.insert(Upgrades::new());
+    resources.insert(Abilities::new());
+    resources.insert(terrain.nav_grid());
+    resources.insert(terrain);
+    resources.insert(FlowFields::new());
+    resources.insert(BlockedCells::default());
+    resources
+}
+
+/// The game world's schedules without input or drawing, keep the systems in
+/// the same order as in `gameworld`
+pub fn schedule() -> Schedule {
+    Schedule::builder()
+        .add_system(update_match())
+        .add_system(count_supply())
+        .add_system(ai_spawn_units())
+        .add_system(ai_form_squads())
+        .add_system(ai_retreat())
+        .add_system(ai_attack())
+        .add_system(run_behaviours())
+        .add_system(attack_move())
+        .add_system(hold_position())
+        .add_system(attack_targets())
+        .flush()
+        .add_system(apply_upgrades())
+        .add_system(follow_orders())
+        .add_system(harvest())
+        .add_system(repair_buildings())
+        .add_system(produce_units())
+        .add_system(research_techs())
+        .add_system(cooldown_units())
+        .add_system(award_experience())
+        .add_system(apply_effects())
+        .add_system(expire_effects())
+        .add_system(heal_allies())
+        .add_system(cast_abilities())
+        .add_system(check_win_conditions())
+        .add_system(discard_shots())
+        .flush()
+        // Physics
+        .add_system(block_footprints())
+        .add_system(steer_units())
+        .flush()
+        .add_system(integrate_positions())
+        .build()
+}
+
+pub fn step(world: &mut World, resources: &mut Resources, schedule: &mut Schedule, delta: f32) {
+    resources.get_mut::<Delta>().map(|mut d| d.0 = delta);
+
+    // Same as the game world, nothing moves once the match is over
+    if resources.get::<Match>().map(|game| game.running()).unwrap_or(false) {
+        schedule.execute(world, resources);
+    }
+
+    // Nothing to show them on headless
+    resources.get_mut::<HealEvents>().map(|mut events| events.drain());
+}
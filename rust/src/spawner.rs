@@ -1,18 +1,94 @@
-use gdnative::{KinematicBody2D, PackedScene, ResourceLoader, Sprite, TextureRect};
+use gdnative::{
+    Color, GodotString, KinematicBody2D, Label, Line2D, PackedScene, Polygon2D, Rect2, ResourceLoader, Sprite, TextureRect,
+    Vector2, Vector2Array,
+};
+use legion::prelude::*;
 
-use crate::units::Unit;
+use crate::abilities::AbilitySlots;
+use crate::archetype::Archetype;
+use crate::behaviour::{Behaviour, Value};
+use crate::fog::Vision;
+use crate::combat::{AttackRange, Flying, Hitpoints, MaxHitpoints, Weapons};
+use crate::effects::Effects;
+use crate::orders::Orders;
+use crate::tech::Upgraded;
+use crate::units::{Faction, Unit, UnitPos, UnitRect, UnitType};
+use crate::veterancy::{Attackers, Veterancy};
+
+/// Add the components for a unit of the given archetype.
+/// The Godot nodes are created later by `create_unit_nodes`, so this works
+/// without a scene tree as well.
+pub fn insert_unit(
+    cmd: &mut CommandBuffer,
+    archetype: &Archetype,
+    pos: Vector2,
+    faction: Faction,
+) -> Entity {
+    let weapons = Weapons(archetype.weapons.clone());
+    let entity = cmd.insert(
+        (),
+        vec![(
+            UnitType(archetype.name.clone()),
+            UnitPos(pos),
+            UnitRect::new(pos, archetype.width, archetype.height),
+            Hitpoints(archetype.hitpoints),
+            MaxHitpoints(archetype.hitpoints),
+            Orders::new(),
+            archetype.movement.clone(),
+            archetype.steering,
+            AttackRange(weapons.range()),
+            faction,
+        )],
+    )[0];
+
+    cmd.add_component(entity, archetype.targeting);
+    cmd.add_component(entity, weapons);
+    cmd.add_component(entity, Attackers::default());
+    cmd.add_component(entity, Veterancy::default());
+    cmd.add_component(entity, Upgraded::default());
+    cmd.add_component(entity, Effects::default());
+    cmd.add_component(entity, Vision(archetype.sight));
+
+    if !archetype.abilities.is_empty() {
+        cmd.add_component(entity, AbilitySlots(archetype.abilities.clone()));
+    }
+
+    if archetype.flying {
+        cmd.add_component(entity, Flying);
+    }
+
+    if let Some(healer) = archetype.healer {
+        cmd.add_component(entity, healer);
+    }
+
+    if let Some(repairer) = archetype.repairer {
+        cmd.add_component(entity, repairer);
+    }
+
+    if let Some(harvester) = archetype.harvester {
+        cmd.add_component(entity, harvester);
+    }
+
+    if let Some(tree) = &archetype.behaviour {
+        let mut behaviour = Behaviour::new(tree);
+        behaviour.blackboard.set("home", Value::Position(pos));
+        cmd.add_component(entity, behaviour);
+    }
+
+    entity
+}
 
 pub fn create_unit() -> Unit {
     let body = KinematicBody2D::new();
     Unit(body)
 }
 
-pub fn create_player_sprite() -> Sprite {
+pub fn create_sprite(path: &str) -> Sprite {
     let mut loader = ResourceLoader::godot_singleton();
 
     loader
         .load(
-            "res://PlayerSprite.tscn".into(),
+            path.into(),
             "PackedScene".into(),
             false,
         )
@@ -22,10 +98,9 @@ pub fn create_player_sprite() -> Sprite {
         .unwrap()
 }
 
-pub fn create_bullet(bullet_type: u32) -> TextureRect {
+pub fn create_bullet(path: &str) -> TextureRect {
     let mut loader = ResourceLoader::godot_singleton();
 
-    let path = format!("res://bullets/Ray{}.tscn", bullet_type);
     loader
         .load(
             path.into(),
@@ -37,3 +112,82 @@ pub fn create_bullet(bullet_type: u32) -> TextureRect {
         .and_then(|nod| unsafe { nod.cast::<TextureRect>() })
         .unwrap()
 }
+
+/// A plain rectangle for things that don't have a sprite
+pub fn create_marker(rect: Rect2, color: Color) -> Polygon2D {
+    let (min, max) = (rect.min(), rect.max());
+    let mut points = Vector2Array::new();
+    points.push(&Vector2::new(min.x, min.y));
+    points.push(&Vector2::new(max.x, min.y));
+    points.push(&Vector2::new(max.x, max.y));
+    points.push(&Vector2::new(min.x, max.y));
+
+    let mut marker = Polygon2D::new();
+    unsafe {
+        marker.set_polygon(points);
+        marker.set_color(color);
+    }
+    marker
+}
+
+/// Translucent preview of a building, drawn above everything else
+pub fn create_ghost(rect: Rect2) -> Polygon2D {
+    let mut ghost = create_marker(rect, Color::rgba(1., 1., 1., 0.5));
+    unsafe { ghost.set_z_index(10) };
+    ghost
+}
+
+/// Outline of a circle, e.g. the area an ability will hit
+pub fn create_circle(radius: f32, color: Color) -> Line2D {
+    let mut circle = Line2D::new();
+    let mut points = Vector2Array::new();
+    for i in 0..=32 {
+        let angle = i as f32 / 32. * std::f32::consts::PI * 2.;
+        points.push(&(Vector2::new(angle.cos(), angle.sin()) * radius));
+    }
+
+    unsafe {
+        circle.set_points(points);
+        circle.set_width(1.);
+        circle.set_default_color(color);
+        circle.set_z_index(10);
+    }
+    circle
+}
+
+/// Filled circle flashed where a splash goes off
+pub fn create_explosion(radius: f32) -> Polygon2D {
+    let mut points = Vector2Array::new();
+    for i in 0..32 {
+        let angle = i as f32 / 32. * std::f32::consts::PI * 2.;
+        points.push(&(Vector2::new(angle.cos(), angle.sin()) * radius));
+    }
+
+    let mut explosion = Polygon2D::new();
+    unsafe {
+        explosion.set_polygon(points);
+        explosion.set_color(Color::rgba(1., 0.6, 0.2, 0.6));
+        explosion.set_z_index(5);
+    }
+    explosion
+}
+
+/// Text above the units, e.g. floating heal numbers
+pub fn create_label(text: &str, color: Color) -> Label {
+    let mut label = Label::new();
+    unsafe {
+        label.set_text(GodotString::from_str(text));
+        label.add_color_override(GodotString::from_str("font_color"), color);
+        label.set_z_index(10);
+    }
+    label
+}
+
+pub fn create_waypoint_line() -> Line2D {
+    let mut line = Line2D::new();
+    unsafe {
+        line.set_width(1.);
+        line.set_default_color(Color::rgba(0.2, 1., 0.2, 0.6));
+    }
+    line
+}
@@ -0,0 +1,150 @@
+use gdnative::Vector2;
+
+/// Arrival radius for a single unit, grows with the size of the group
+pub const ARRIVAL_RADIUS: f32 = 4.;
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// Steering weights and radii, set per archetype
+#[derive(Debug, Clone, Copy)]
+pub struct Steering {
+    pub separation: f32,
+    pub separation_radius: f32,
+    pub avoidance: f32,
+    pub look_ahead: f32,
+    pub slowing_radius: f32,
+}
+
+impl Default for Steering {
+    fn default() -> Self {
+        Self {
+            separation: 1.5,
+            separation_radius: 16.,
+            avoidance: 2.,
+            look_ahead: 24.,
+            slowing_radius: 32.,
+        }
+    }
+}
+
+/// Something a moving unit should steer around
+pub struct Obstacle {
+    pub pos: Vector2,
+    pub radius: f32,
+}
+
+pub fn arrival_radius(group_size: usize) -> f32 {
+    ARRIVAL_RADIUS * (group_size.max(1) as f32).sqrt()
+}
+
+// -----------------------------------------------------------------------------
+//     - Behaviours -
+// -----------------------------------------------------------------------------
+/// Desired velocity towards `target`, slowing down inside the slowing radius
+pub fn arrive(pos: Vector2, target: Vector2, max_speed: f32, slowing_radius: f32) -> Vector2 {
+    let offset = target - pos;
+    let distance = offset.length();
+    if distance <= 0. {
+        return Vector2::zero();
+    }
+
+    let speed = if distance < slowing_radius {
+        max_speed * distance / slowing_radius
+    } else {
+        max_speed
+    };
+
+    offset / distance * speed
+}
+
+/// Push away from neighbours that are too close, stronger the closer they are
+pub fn separate(pos: Vector2, neighbours: &[Vector2], radius: f32) -> Vector2 {
+    neighbours
+        .iter()
+        .map(|other| pos - *other)
+        .filter(|offset| offset.length() > 0. && offset.length() < radius)
+        .fold(Vector2::zero(), |acc, offset| {
+            let distance = offset.length();
+            acc + offset / distance * (1. - distance / radius)
+        })
+}
+
+/// Steer sideways around obstacles in the look ahead distance
+pub fn avoid(pos: Vector2, heading: Vector2, obstacles: &[Obstacle], look_ahead: f32) -> Vector2 {
+    if heading.length() <= 0. {
+        return Vector2::zero();
+    }
+    let heading = heading.normalize();
+
+    let closest = obstacles
+        .iter()
+        .filter_map(|obstacle| {
+            let offset = obstacle.pos - pos;
+            let ahead = offset.dot(heading);
+            if ahead <= 0. || ahead > look_ahead + obstacle.radius {
+                return None;
+            }
+
+            let side = Vector2::new(-heading.y, heading.x);
+            let lateral = offset.dot(side);
+            if lateral.abs() > obstacle.radius {
+                return None;
+            }
+
+            Some((ahead, lateral, side))
+        })
+        .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    match closest {
+        None => Vector2::zero(),
+        Some((ahead, lateral, side)) => {
+            // Turn away from the side the obstacle is on
+            let away = if lateral > 0. { -side } else { side };
+            away * (1. - ahead / (look_ahead + 1.)).max(0.)
+        }
+    }
+}
+
+/// Combine the behaviours into a single velocity, capped at `max_speed`
+pub fn steer(
+    weights: &Steering,
+    pos: Vector2,
+    target: Vector2,
+    max_speed: f32,
+    neighbours: &[Vector2],
+    obstacles: &[Obstacle],
+) -> Vector2 {
+    let desired = arrive(pos, target, max_speed, weights.slowing_radius);
+    let separation = separate(pos, neighbours, weights.separation_radius) * weights.separation;
+    let avoidance = avoid(pos, desired, obstacles, weights.look_ahead) * weights.avoidance;
+
+    let velocity = desired + (separation + avoidance) * max_speed;
+    if velocity.length() > max_speed {
+        velocity.normalize() * max_speed
+    } else {
+        velocity
+    }
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use super::*;
+
+    // Units should be pushed apart and slow down when arriving
+    pub fn test_steering() -> bool {
+        let pos = Vector2::zero();
+
+        let push = separate(pos, &[Vector2::new(5., 0.)], 16.);
+        assert_gd!(push.x < 0.);
+
+        let far = arrive(pos, Vector2::new(100., 0.), 100., 32.);
+        let near = arrive(pos, Vector2::new(10., 0.), 100., 32.);
+        assert_gd!(far.length() > near.length());
+
+        let obstacle = Obstacle { pos: Vector2::new(10., 2.), radius: 8. };
+        let dodge = avoid(pos, Vector2::new(1., 0.), &[obstacle], 24.);
+        assert_gd!(dodge.y < 0.)
+    }
+}
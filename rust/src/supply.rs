@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use legion::prelude::*;
+
+use crate::archetype::Archetypes;
+use crate::buildings::{Blueprints, Building};
+use crate::units::{Faction, UnitType};
+
+/// Supply every faction has without any buildings
+pub const STARTING_SUPPLY: u32 = 5;
+
+/// No amount of buildings raises the cap past this
+pub const MAX_SUPPLY: u32 = 100;
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Default, Clone, Copy)]
+struct Count {
+    used: u32,
+    provided: u32,
+}
+
+/// Supply used by each faction's units and provided by its buildings.
+/// Recounted every frame by `count_supply`, so dead units free their supply.
+pub struct Supply {
+    starting: u32,
+    factions: HashMap<u32, Count>,
+}
+
+impl Supply {
+    pub fn new(starting: u32) -> Self {
+        Self { starting, factions: HashMap::new() }
+    }
+
+    fn count(&self, faction: Faction) -> Count {
+        self.factions.get(&faction.0).copied().unwrap_or_default()
+    }
+
+    pub fn used(&self, faction: Faction) -> u32 {
+        self.count(faction).used
+    }
+
+    pub fn cap(&self, faction: Faction) -> u32 {
+        (self.starting + self.count(faction).provided).min(MAX_SUPPLY)
+    }
+
+    pub fn available(&self, faction: Faction) -> u32 {
+        self.cap(faction).saturating_sub(self.used(faction))
+    }
+
+    /// Take up supply for a unit about to be spawned, if there is room
+    pub fn reserve(&mut self, faction: Faction, amount: u32) -> bool {
+        if self.available(faction) < amount {
+            return false;
+        }
+
+        self.factions.entry(faction.0).or_default().used += amount;
+        true
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+pub fn count_supply() -> Box<dyn Schedulable> {
+    SystemBuilder::new("count supply")
+        .read_resource::<Archetypes>()
+        .read_resource::<Blueprints>()
+        .write_resource::<Supply>()
+        .with_query(<(Read<UnitType>, Read<Faction>)>::query())
+        .with_query(<(Read<Building>, Read<Faction>)>::query())
+        .build(|_, world, (archetypes, blueprints, supply), (units, buildings)| {
+            supply.factions.clear();
+
+            for (unit_type, faction) in units.iter(world) {
+                if let Some(archetype) = archetypes.get(&unit_type.0) {
+                    supply.factions.entry(faction.0).or_default().used += archetype.supply;
+                }
+            }
+
+            for (building, faction) in buildings.iter(world) {
+                if let Some(blueprint) = blueprints.get(&building.0) {
+                    supply.factions.entry(faction.0).or_default().provided += blueprint.supply;
+                }
+            }
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use crate::buildings::{insert_building, produce_units, Blueprint, Production};
+    use crate::gameworld::Delta;
+    use crate::simulation::flat_terrain;
+    use super::*;
+
+    // Production waits at the cap and carries on once a unit dies
+    pub fn test_supply_cap() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(Delta(10.));
+        resources.insert(Archetypes::new());
+        resources.insert(Blueprints::new());
+        resources.insert(Supply::new(0));
+
+        let mut depot = Blueprint::barracks();
+        depot.supply = 2;
+
+        let mut cmd = CommandBuffer::new(&world);
+        let barracks = insert_building(&mut cmd, &depot, &flat_terrain(), (10, 10), Faction::PLAYER);
+        cmd.write(&mut world);
+        resources.get_mut::<Blueprints>().map(|mut blueprints| blueprints.insert(depot));
+
+        world.get_component_mut::<Production>(barracks).map(|mut production| {
+            (0..3).for_each(|_| production.queue.push_back("soldier".into()));
+        });
+
+        let mut sched = Schedule::builder()
+            .add_system(count_supply())
+            .add_system(produce_units())
+            .flush()
+            .build();
+
+        for _ in 0..4 {
+            sched.execute(&mut world, &mut resources);
+        }
+
+        let mut units = <Read<UnitType>>::query();
+        assert_gd!(units.iter(&world).count() == 2);
+        assert_gd!(world.get_component::<Production>(barracks).unwrap().queue.len() == 1);
+        assert_gd!(resources.get::<Supply>().unwrap().available(Faction::PLAYER) == 0);
+
+        let dead = units.iter_entities(&world).map(|(ent, _)| ent).next().unwrap();
+        world.delete(dead);
+        sched.execute(&mut world, &mut resources);
+
+        assert_gd!(units.iter(&world).count() == 2);
+        assert_gd!(world.get_component::<Production>(barracks).unwrap().queue.is_empty())
+    }
+}
@@ -0,0 +1,231 @@
+//! Utility scoring for picking which hostile to attack.
+//!
+//! Every hostile in range gets a score from a handful of factors, each in
+//! `0..=1`, weighted per archetype. The highest total wins.
+use gdnative::Vector2;
+use legion::prelude::*;
+use serde::Deserialize;
+
+use crate::combat::{Hitpoints, MaxHitpoints, Target, Weapons};
+use crate::units::Faction;
+
+// -----------------------------------------------------------------------------
+//     - Data -
+// -----------------------------------------------------------------------------
+/// A hostile that could be attacked
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+    pub entity: Entity,
+    pub pos: Vector2,
+    pub faction: Faction,
+    /// Fraction of max hitpoints left
+    pub health: f32,
+    /// Damage per second
+    pub threat: f32,
+    /// Faction of the unit the candidate is attacking
+    pub attacking: Option<Faction>,
+}
+
+impl Candidate {
+    /// Requires read access to `MaxHitpoints`, `Weapons`, `Target` and `Faction`
+    pub fn new(world: &SubWorld, entity: Entity, pos: Vector2, faction: Faction, hp: &Hitpoints) -> Self {
+        let health = world
+            .get_component::<MaxHitpoints>(entity)
+            .map(|max_hp| hp.0 as f32 / max_hp.0 as f32)
+            .unwrap_or(1.);
+
+        let threat = world
+            .get_component::<Weapons>(entity)
+            .map(|weapons| weapons.dps())
+            .unwrap_or(0.);
+
+        let attacking = world
+            .get_component::<Target>(entity)
+            .and_then(|target| world.get_component::<Faction>(target.0).map(|f| *f));
+
+        Self { entity, pos, faction, health, threat, attacking }
+    }
+}
+
+/// The factors behind a candidate's score, kept around for debugging
+#[derive(Debug, Clone, Copy)]
+pub struct TargetScore {
+    pub entity: Entity,
+    pub distance: f32,
+    pub health: f32,
+    pub threat: f32,
+    pub defend: f32,
+    pub total: f32,
+}
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// How much each factor counts when choosing a target
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct TargetWeights {
+    /// Prefer close targets
+    pub distance: f32,
+    /// Prefer wounded targets
+    pub health: f32,
+    /// Prefer targets that deal a lot of damage
+    pub threat: f32,
+    /// Prefer targets attacking an ally
+    pub defend: f32,
+}
+
+impl Default for TargetWeights {
+    fn default() -> Self {
+        Self {
+            distance: 1.,
+            health: 0.5,
+            threat: 0.5,
+            defend: 1.,
+        }
+    }
+}
+
+/// Scores from the last time the unit picked a target, best first
+#[derive(Debug, Clone, Default)]
+pub struct TargetScores(pub Vec<TargetScore>);
+
+// -----------------------------------------------------------------------------
+//     - Scoring -
+// -----------------------------------------------------------------------------
+/// Score every candidate within `range` that is hostile to `faction`, best first
+pub fn score_targets(
+    weights: &TargetWeights,
+    pos: Vector2,
+    faction: Faction,
+    range: f32,
+    candidates: &[Candidate],
+) -> Vec<TargetScore> {
+    let in_range = candidates
+        .iter()
+        .filter(|c| faction.hostile_to(&c.faction))
+        .map(|c| (c, (c.pos - pos).length()))
+        .filter(|(_, distance)| *distance <= range)
+        .collect::<Vec<_>>();
+
+    let max_threat = in_range
+        .iter()
+        .map(|(c, _)| c.threat)
+        .fold(0., f32::max);
+
+    let mut scores = in_range
+        .into_iter()
+        .map(|(c, distance)| {
+            let distance = if range > 0. { 1. - distance / range } else { 1. };
+            let health = 1. - c.health;
+            let threat = if max_threat > 0. { c.threat / max_threat } else { 0. };
+            let defend = match c.attacking {
+                Some(victim) if !faction.hostile_to(&victim) => 1.,
+                _ => 0.,
+            };
+
+            TargetScore {
+                entity: c.entity,
+                distance,
+                health,
+                threat,
+                defend,
+                total: distance * weights.distance
+                    + health * weights.health
+                    + threat * weights.threat
+                    + defend * weights.defend,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    scores.sort_by(|a, b| b.total.partial_cmp(&a.total).unwrap_or(std::cmp::Ordering::Equal));
+    scores
+}
+
+/// Best target for the unit, or `None` if nothing hostile is in range.
+/// The scores are added to the unit as `TargetScores`.
+pub fn choose_target(
+    cmd: &mut CommandBuffer,
+    world: &SubWorld,
+    entity: Entity,
+    pos: Vector2,
+    faction: Faction,
+    range: f32,
+    candidates: &[Candidate],
+) -> Option<Entity> {
+    let weights = world
+        .get_component::<TargetWeights>(entity)
+        .map(|w| *w)
+        .unwrap_or_default();
+
+    let scores = score_targets(&weights, pos, faction, range, candidates);
+    let best = scores.first().map(|score| score.entity);
+    if best.is_some() {
+        cmd.add_component(entity, TargetScores(scores));
+    }
+    best
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use crate::combat::{hold_position, AttackRange, Weapon};
+    use crate::orders::Holding;
+    use crate::units::UnitPos;
+    use super::*;
+
+    // A unit holding position at the origin picks from hostiles that are
+    // close, wounded, hard hitting or attacking its ally, in that order
+    fn choose(weights: TargetWeights, range: f32) -> (World, Vec<Entity>, Entity) {
+        let mut world = Universe::new().create_world();
+        let ally = world.insert((), vec![(UnitPos(Vector2::new(0., 50.)), Faction::PLAYER, Hitpoints(10))])[0];
+
+        let rifle = || Weapons(vec![Weapon::rifle()]);
+        let cannon = Weapons(vec![Weapon { damage: 10, ..Weapon::rifle() }]);
+        let mut hostiles = world.insert((), vec![
+            (UnitPos(Vector2::new(10., 0.)), Faction(1), Hitpoints(10), MaxHitpoints(10), rifle()),
+            (UnitPos(Vector2::new(50., 0.)), Faction(1), Hitpoints(2), MaxHitpoints(10), rifle()),
+            (UnitPos(Vector2::new(70., 0.)), Faction(1), Hitpoints(10), MaxHitpoints(10), cannon),
+        ]).to_vec();
+        hostiles.push(world.insert((), vec![
+            (UnitPos(Vector2::new(90., 0.)), Faction(1), Hitpoints(10), MaxHitpoints(10), rifle(), Target(ally)),
+        ])[0]);
+
+        let unit = world.insert((), vec![
+            (UnitPos(Vector2::zero()), Faction::PLAYER, AttackRange(range), Holding, weights),
+        ])[0];
+
+        let mut resources = Resources::default();
+        let mut sched = Schedule::builder().add_system(hold_position()).build();
+        sched.execute(&mut world, &mut resources);
+
+        (world, hostiles, unit)
+    }
+
+    pub fn test_score_targets() -> bool {
+        let nearest = TargetWeights { distance: 1., health: 0., threat: 0., defend: 0. };
+        let weakest = TargetWeights { distance: 0.1, health: 1., threat: 0., defend: 0. };
+        let deadliest = TargetWeights { distance: 0.1, health: 0., threat: 1., defend: 0. };
+        let defender = TargetWeights { distance: 0.1, health: 0., threat: 0., defend: 1. };
+
+        for (weights, best) in [(nearest, 0), (weakest, 1), (deadliest, 2), (defender, 3)].iter() {
+            let (world, hostiles, unit) = choose(*weights, 100.);
+            assert_gd!(world.get_component::<Target>(unit).map(|target| target.0) == Some(hostiles[*best]));
+
+            let scores = world.get_component::<TargetScores>(unit).unwrap();
+            assert_gd!(scores.0.len() == 4);
+            assert_gd!(scores.0[0].entity == hostiles[*best]);
+        }
+
+        // Threat is relative to the deadliest hostile in range
+        let (world, hostiles, unit) = choose(deadliest, 100.);
+        let scores = world.get_component::<TargetScores>(unit).unwrap();
+        let threat = |entity| scores.0.iter().find(|score| score.entity == entity).unwrap().threat;
+        assert_gd!(threat(hostiles[2]) == 1.);
+        assert_gd!(threat(hostiles[0]) == 0.1);
+
+        // Out of range
+        let (world, _, unit) = choose(nearest, 5.);
+        assert_gd!(world.get_component::<Target>(unit).is_none());
+        assert_gd!(world.get_component::<TargetScores>(unit).is_none())
+    }
+}
@@ -0,0 +1,476 @@
+//! Research and the upgrades it brings.
+use std::collections::{HashMap, HashSet};
+
+use legion::prelude::*;
+use serde::Deserialize;
+
+use crate::archetype::Archetypes;
+use crate::buildings::{Blueprints, Building};
+use crate::combat::{Hitpoints, MaxHitpoints, Weapons};
+use crate::economy::Stockpiles;
+use crate::gameworld::{Delta, Notices, Selected};
+use crate::input::Actions;
+use crate::movement::{ModifierSource, Movement};
+use crate::units::{Faction, UnitType};
+use crate::veterancy::{Ranks, Veterancy};
+
+/// Actions that research the first, second and third tech a building offers
+pub const RESEARCH_ACTIONS: &[&str] = &["research_1", "research_2", "research_3"];
+
+// -----------------------------------------------------------------------------
+//     - Data -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Stat {
+    Damage,
+    /// Seconds between attacks
+    Cooldown,
+    MaxHitpoints,
+    Speed,
+}
+
+fn one() -> f32 {
+    1.
+}
+
+/// Change to a stat, added to the base value before multiplying
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatModifier {
+    pub stat: Stat,
+    #[serde(default)]
+    pub add: f32,
+    #[serde(default = "one")]
+    pub multiply: f32,
+    /// Archetypes affected, every archetype if empty
+    #[serde(default)]
+    pub archetypes: Vec<String>,
+}
+
+impl StatModifier {
+    fn applies_to(&self, stat: Stat, archetype: &str) -> bool {
+        self.stat == stat && (self.archetypes.is_empty() || self.archetypes.iter().any(|a| a == archetype))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tech {
+    pub cost: u32,
+    /// Seconds it takes to research
+    pub research_time: f32,
+    /// Techs that have to be researched first
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Archetypes that can't be produced until this is researched
+    #[serde(default)]
+    pub unlocks: Vec<String>,
+    #[serde(default)]
+    pub modifiers: Vec<StatModifier>,
+}
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// Tech a building is researching
+#[derive(Debug, Clone, Default)]
+pub struct Research {
+    pub tech: Option<String>,
+    pub progress: f32,
+}
+
+/// The research and rank a unit's stats were last worked out for
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Upgraded {
+    version: u32,
+    rank: usize,
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Every tech, by name
+#[derive(Debug, Default, Deserialize)]
+pub struct TechTree(HashMap<String, Tech>);
+
+impl TechTree {
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tech> {
+        self.0.get(name)
+    }
+
+    /// Archetypes nothing unlocks are always available
+    pub fn unlocked(&self, upgrades: &Upgrades, faction: Faction, archetype: &str) -> bool {
+        let mut unlocked_by = self
+            .0
+            .iter()
+            .filter(|(_, tech)| tech.unlocks.iter().any(|a| a == archetype))
+            .peekable();
+        unlocked_by.peek().is_none() || unlocked_by.any(|(name, _)| upgrades.researched(faction, name))
+    }
+
+    /// Not researched yet, and everything it requires is
+    pub fn available(&self, upgrades: &Upgrades, faction: Faction, name: &str) -> bool {
+        match self.get(name) {
+            None => false,
+            Some(tech) => {
+                !upgrades.researched(faction, name)
+                    && tech.requires.iter().all(|required| upgrades.researched(faction, required))
+            }
+        }
+    }
+}
+
+/// What each faction has researched
+#[derive(Debug, Default)]
+pub struct Upgrades {
+    researched: HashMap<u32, HashSet<String>>,
+    modifiers: HashMap<u32, Vec<StatModifier>>,
+    /// Bumped every time the faction finishes research
+    versions: HashMap<u32, u32>,
+}
+
+impl Upgrades {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn researched(&self, faction: Faction, tech: &str) -> bool {
+        self.researched
+            .get(&faction.0)
+            .map(|techs| techs.contains(tech))
+            .unwrap_or(false)
+    }
+
+    pub fn complete(&mut self, faction: Faction, name: &str, tech: &Tech) {
+        if self.researched.entry(faction.0).or_default().insert(name.to_string()) {
+            self.modifiers
+                .entry(faction.0)
+                .or_default()
+                .extend(tech.modifiers.iter().cloned());
+            *self.versions.entry(faction.0).or_default() += 1;
+        }
+    }
+
+    pub fn version(&self, faction: Faction) -> u32 {
+        self.versions.get(&faction.0).copied().unwrap_or(0)
+    }
+
+    /// `base` with every modifier the faction researched applied
+    pub fn apply(&self, faction: Faction, archetype: &str, stat: Stat, base: f32) -> f32 {
+        match self.modifiers.get(&faction.0) {
+            Some(modifiers) => apply_modifiers(modifiers, archetype, stat, base),
+            None => base,
+        }
+    }
+}
+
+/// `base` with every matching modifier applied: additions first, then
+/// multipliers, so modifiers stack regardless of research order
+pub fn apply_modifiers(modifiers: &[StatModifier], archetype: &str, stat: Stat, base: f32) -> f32 {
+    let (add, multiply) = modifiers
+        .iter()
+        .filter(|modifier| modifier.applies_to(stat, archetype))
+        .fold((0., 1.), |(add, multiply), modifier| (add + modifier.add, multiply * modifier.multiply));
+
+    (base + add) * multiply
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+/// Start researching at the selected buildings, paying up front
+pub fn queue_research() -> Box<dyn Schedulable> {
+    SystemBuilder::new("queue research")
+        .write_resource::<Actions>()
+        .read_resource::<Blueprints>()
+        .read_resource::<TechTree>()
+        .read_resource::<Upgrades>()
+        .write_resource::<Stockpiles>()
+        .write_resource::<Notices>()
+        .with_query(<(Read<Building>, Read<Faction>, Write<Research>)>::query().filter(tag::<Selected>()))
+        .with_query(<(Read<Faction>, Read<Research>)>::query())
+        .build(|_, world, (actions, blueprints, tech_tree, upgrades, stockpiles, notices), (query, all_research)| {
+            let index = match RESEARCH_ACTIONS.iter().position(|action| actions.pressed(action)) {
+                Some(i) => i,
+                None => return,
+            };
+            RESEARCH_ACTIONS.iter().for_each(|action| actions.consume(action));
+
+            // A faction only researches each tech at one building at a time,
+            // so only the first selected building that offers it starts
+            let mut researching = all_research
+                .iter(world)
+                .filter_map(|(faction, research)| research.tech.clone().map(|tech| (faction.0, tech)))
+                .collect::<HashSet<_>>();
+
+            for (building, faction, mut research) in query.iter_mut(world) {
+                let name = match blueprints.get(&building.0).and_then(|bp| bp.researches.get(index)) {
+                    Some(name) => name,
+                    None => continue,
+                };
+
+                let tech = match tech_tree.get(name) {
+                    Some(tech) => tech,
+                    None => continue,
+                };
+
+                if research.tech.is_some() {
+                    continue;
+                }
+
+                if researching.contains(&(faction.0, name.clone())) {
+                    notices.push(format!("Already researching {}", name));
+                } else if !tech_tree.available(upgrades, *faction, name) {
+                    notices.push(format!("Can't research {} yet", name));
+                } else if stockpiles.spend(*faction, tech.cost) {
+                    research.tech = Some(name.clone());
+                    research.progress = 0.;
+                    researching.insert((faction.0, name.clone()));
+                } else {
+                    notices.push(format!("Not enough minerals for {}", name));
+                }
+            }
+        })
+}
+
+pub fn research_techs() -> Box<dyn Schedulable> {
+    SystemBuilder::new("research techs")
+        .read_resource::<Delta>()
+        .read_resource::<TechTree>()
+        .write_resource::<Upgrades>()
+        .with_query(<(Read<Faction>, Write<Research>)>::query())
+        .build(|_, world, (delta, tech_tree, upgrades), query| {
+            for (faction, mut research) in query.iter_mut(world) {
+                let name = match research.tech.clone() {
+                    Some(name) => name,
+                    None => continue,
+                };
+
+                let tech = match tech_tree.get(&name) {
+                    Some(tech) => tech,
+                    None => {
+                        *research = Research::default();
+                        continue;
+                    }
+                };
+
+                research.progress += delta.0;
+                if research.progress < tech.research_time {
+                    continue;
+                }
+
+                upgrades.complete(*faction, &name, tech);
+                *research = Research::default();
+            }
+        })
+}
+
+/// Work out a unit's stats from its archetype, its faction's upgrades and
+/// its rank, once each time research finishes or the unit is promoted. The
+/// archetype keeps the base stats. Units keep the hitpoints they gain when
+/// their max goes up.
+pub fn apply_upgrades() -> Box<dyn Schedulable> {
+    SystemBuilder::new("apply upgrades")
+        .read_resource::<Archetypes>()
+        .read_resource::<Upgrades>()
+        .read_resource::<Ranks>()
+        .read_component::<Veterancy>()
+        .with_query(<(
+            Read<UnitType>,
+            Read<Faction>,
+            Write<Upgraded>,
+            Write<Hitpoints>,
+            Write<MaxHitpoints>,
+            Write<Movement>,
+            Write<Weapons>,
+        )>::query())
+        .build(|_, world, (archetypes, upgrades, ranks), query| {
+            for (entity, (unit_type, faction, mut upgraded, mut hp, mut max_hp, mut movement, mut weapons)) in query.iter_entities_mut(world) {
+                let rank = world.get_component::<Veterancy>(entity).map(|v| v.rank).unwrap_or(0);
+                let current = Upgraded { version: upgrades.version(*faction), rank };
+                if *upgraded == current {
+                    continue;
+                }
+
+                let archetype = match archetypes.get(&unit_type.0) {
+                    Some(a) => a,
+                    None => continue,
+                };
+                *upgraded = current;
+
+                let apply = |stat: Stat, base: f32| {
+                    let upgraded = upgrades.apply(*faction, &archetype.name, stat, base);
+                    ranks.apply(rank, &archetype.name, stat, upgraded)
+                };
+
+                let max = apply(Stat::MaxHitpoints, archetype.hitpoints as f32).round().max(1.) as u32;
+                if max > max_hp.0 {
+                    hp.0 += max - max_hp.0;
+                }
+                hp.0 = hp.0.min(max);
+                max_hp.0 = max;
+
+                let base_speed = archetype.movement.max_speed;
+                movement.set_modifier(ModifierSource::Upgrade, apply(Stat::Speed, base_speed) / base_speed);
+
+                for (weapon, base) in weapons.0.iter_mut().zip(&archetype.weapons) {
+                    weapon.damage = apply(Stat::Damage, base.damage as f32).round().max(0.) as u32;
+                    weapon.cooldown = apply(Stat::Cooldown, base.cooldown).max(0.);
+                }
+            }
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use crate::archetype::Archetype;
+    use crate::data;
+    use crate::simulation::flat_terrain;
+    use crate::buildings::{insert_building, Blueprint};
+    use crate::spawner::insert_unit;
+    use gdnative::Vector2;
+    use super::*;
+
+    fn tech_tree() -> TechTree {
+        data::parse(r#"{
+            "weapons": (cost: 100, research_time: 2.0, modifiers: [
+                (stat: Damage, add: 1.0, archetypes: ["soldier"]),
+                (stat: Cooldown, multiply: 0.5),
+            ]),
+            "plating": (cost: 100, research_time: 2.0, requires: ["weapons"], modifiers: [
+                (stat: MaxHitpoints, add: 5.0),
+                (stat: Speed, multiply: 1.5),
+            ]),
+            "training": (cost: 100, research_time: 2.0, unlocks: ["sentry"]),
+        }"#).unwrap()
+    }
+
+    // Modifiers stack, add first and then multiply
+    pub fn test_stat_modifiers() -> bool {
+        let tree = tech_tree();
+        let mut upgrades = Upgrades::new();
+
+        assert_gd!(!tree.unlocked(&upgrades, Faction::PLAYER, "sentry"));
+        assert_gd!(tree.unlocked(&upgrades, Faction::PLAYER, "soldier"));
+        assert_gd!(!tree.available(&upgrades, Faction::PLAYER, "plating"));
+
+        upgrades.complete(Faction::PLAYER, "weapons", tree.get("weapons").unwrap());
+        upgrades.complete(Faction::PLAYER, "training", tree.get("training").unwrap());
+        assert_gd!(tree.available(&upgrades, Faction::PLAYER, "plating"));
+        assert_gd!(tree.unlocked(&upgrades, Faction::PLAYER, "sentry"));
+
+        assert_gd!(upgrades.apply(Faction::PLAYER, "soldier", Stat::Damage, 1.) == 2.);
+        assert_gd!(upgrades.apply(Faction::PLAYER, "worker", Stat::Damage, 1.) == 1.);
+        assert_gd!(upgrades.apply(Faction::PLAYER, "worker", Stat::Cooldown, 1.) == 0.5);
+        // Researching twice changes nothing
+        upgrades.complete(Faction::PLAYER, "weapons", tree.get("weapons").unwrap());
+        assert_gd!(upgrades.apply(Faction::PLAYER, "soldier", Stat::Damage, 1.) == 2.);
+        // Other factions don't get the upgrades
+        assert_gd!(upgrades.apply(Faction(1), "soldier", Stat::Damage, 1.) == 1.)
+    }
+
+    // Research at a building upgrades the units already out there
+    pub fn test_research() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(Delta(1.));
+        resources.insert(Actions::empty());
+        resources.insert(Archetypes::new());
+        resources.insert(tech_tree());
+        resources.insert(Upgrades::new());
+        resources.insert(Ranks::new());
+        resources.insert(Stockpiles::new(500));
+        resources.insert(Notices::new());
+
+        let mut blueprints = Blueprints::new();
+        let mut lab = Blueprint::barracks();
+        lab.researches = vec!["weapons".into(), "plating".into()];
+        blueprints.insert(lab.clone());
+        resources.insert(blueprints);
+
+        let mut cmd = CommandBuffer::new(&world);
+        let building = insert_building(&mut cmd, &lab, &flat_terrain(), (10, 10), Faction::PLAYER);
+        cmd.add_tag(building, Selected);
+        let archetype = Archetype::soldier();
+        let soldier = insert_unit(&mut cmd, &archetype, Vector2::zero(), Faction::PLAYER);
+        cmd.write(&mut world);
+
+        let mut sched = Schedule::builder()
+            .add_system(queue_research())
+            .add_system(research_techs())
+            .add_system(apply_upgrades())
+            .build();
+
+        // Plating needs weapons first
+        resources.get_mut::<Actions>().map(|mut actions| actions.press("research_2"));
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Research>(building).unwrap().tech.is_none());
+
+        resources.get_mut::<Actions>().map(|mut actions| actions.press("research_1"));
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(resources.get::<Stockpiles>().unwrap().amount(Faction::PLAYER) == 400);
+        let damage = archetype.weapons[0].damage;
+        assert_gd!(world.get_component::<Weapons>(soldier).unwrap().0[0].damage == damage);
+
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(resources.get::<Upgrades>().unwrap().researched(Faction::PLAYER, "weapons"));
+        assert_gd!(world.get_component::<Weapons>(soldier).unwrap().0[0].damage == damage + 1);
+
+        resources.get_mut::<Actions>().map(|mut actions| actions.press("research_2"));
+        for _ in 0..3 {
+            sched.execute(&mut world, &mut resources);
+        }
+
+        let max = archetype.hitpoints + 5;
+        assert_gd!(world.get_component::<MaxHitpoints>(soldier).unwrap().0 == max);
+        assert_gd!(world.get_component::<Hitpoints>(soldier).unwrap().0 == max);
+        assert_gd!(world.get_component::<Movement>(soldier).unwrap().speed() == 150.);
+
+        // Nothing is worked out again until something changes
+        world.get_component_mut::<Weapons>(soldier).unwrap().0[0].damage = 0;
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Weapons>(soldier).unwrap().0[0].damage == 0)
+    }
+
+    // A tech one building is researching can't be paid for again at another
+    pub fn test_research_once() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(Actions::empty());
+        resources.insert(tech_tree());
+        resources.insert(Upgrades::new());
+        resources.insert(Stockpiles::new(500));
+        resources.insert(Notices::new());
+
+        let mut blueprints = Blueprints::new();
+        let mut lab = Blueprint::barracks();
+        lab.researches = vec!["weapons".into()];
+        blueprints.insert(lab.clone());
+        resources.insert(blueprints);
+
+        let terrain = flat_terrain();
+        let mut cmd = CommandBuffer::new(&world);
+        let first = insert_building(&mut cmd, &lab, &terrain, (10, 10), Faction::PLAYER);
+        let second = insert_building(&mut cmd, &lab, &terrain, (20, 10), Faction::PLAYER);
+        cmd.add_tag(first, Selected);
+        cmd.write(&mut world);
+
+        let mut sched = Schedule::builder().add_system(queue_research()).build();
+
+        resources.get_mut::<Actions>().map(|mut actions| actions.press("research_1"));
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Research>(first).unwrap().tech.is_some());
+
+        let mut cmd = CommandBuffer::new(&world);
+        cmd.add_tag(second, Selected);
+        cmd.write(&mut world);
+
+        resources.get_mut::<Actions>().map(|mut actions| actions.press("research_1"));
+        sched.execute(&mut world, &mut resources);
+        assert_gd!(world.get_component::<Research>(second).unwrap().tech.is_none());
+        assert_gd!(resources.get::<Stockpiles>().unwrap().amount(Faction::PLAYER) == 400)
+    }
+}
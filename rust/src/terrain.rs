@@ -0,0 +1,160 @@
+use gdnative::{GodotString, TileMap, Variant, Vector2};
+
+use crate::fog::FogOfWar;
+use crate::navigation::{Cell, NavGrid, IMPASSABLE};
+
+/// TileMap meta: dictionary of tile name -> movement cost
+const COST_META: &str = "terrain_cost";
+/// TileMap meta: array of tile names units can't walk on
+const BLOCKED_META: &str = "terrain_blocked";
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Movement cost per tile. A cost of 2 means units move at half speed.
+/// The costs live in a navigation grid with one cell per tile.
+#[derive(Clone)]
+pub struct Terrain {
+    grid: NavGrid,
+}
+
+impl Terrain {
+    pub fn new(origin: Vector2, tile_size: f32, width: i32, height: i32) -> Self {
+        Self {
+            grid: NavGrid::new(origin, tile_size, width, height),
+        }
+    }
+
+    /// Build the terrain from the tiles used in a tile map, using the
+    /// `terrain_cost` and `terrain_blocked` meta on the tile map to look up
+    /// the cost by tile name.
+    pub fn from_tilemap(tilemap: &TileMap) -> Option<Self> {
+        unsafe {
+            let tileset = tilemap.get_tileset()?;
+            let used = tilemap.get_used_rect();
+            if used.size.width <= 0. || used.size.height <= 0. {
+                return None;
+            }
+
+            let tile_size = tilemap.get_cell_size().x;
+            let origin = tilemap.get_global_position()
+                + tilemap.map_to_world(used.origin.to_vector(), false);
+
+            let costs = meta(tilemap, COST_META).and_then(|v| v.try_to_dictionary());
+            let blocked = meta(tilemap, BLOCKED_META).and_then(|v| v.try_to_array());
+
+            let mut terrain = Self::new(
+                origin,
+                tile_size,
+                used.size.width as i32,
+                used.size.height as i32,
+            );
+
+            for y in 0..terrain.grid.height() {
+                for x in 0..terrain.grid.width() {
+                    let tile = tilemap.get_cell(
+                        used.origin.x as i64 + x as i64,
+                        used.origin.y as i64 + y as i64,
+                    );
+                    if tile < 0 {
+                        continue;
+                    }
+
+                    let name = Variant::from_godot_string(&tileset.tile_get_name(tile));
+
+                    let is_blocked = blocked.as_ref().map(|b| b.contains(&name)).unwrap_or(false);
+                    let cost = if is_blocked {
+                        IMPASSABLE
+                    } else {
+                        costs
+                            .as_ref()
+                            .and_then(|c| c.get(&name).try_to_f64())
+                            .map(|c| c as f32)
+                            .unwrap_or(1.)
+                    };
+
+                    terrain.set_cost((x, y), cost);
+                }
+            }
+
+            Some(terrain)
+        }
+    }
+
+    pub fn tile_size(&self) -> f32 {
+        self.grid.cell_size()
+    }
+
+    /// The tile at `pos`, if it's on the map
+    pub fn tile(&self, pos: Vector2) -> Option<Cell> {
+        self.grid.cell(pos)
+    }
+
+    /// Top left corner of the tile
+    pub fn tile_origin(&self, tile: Cell) -> Vector2 {
+        self.grid.origin() + Vector2::new(tile.0 as f32, tile.1 as f32) * self.tile_size()
+    }
+
+    /// On the map and not blocked
+    pub fn passable(&self, tile: Cell) -> bool {
+        self.grid.passable(tile)
+    }
+
+    pub fn set_cost(&mut self, tile: Cell, cost: f32) {
+        self.grid.set_cost(tile, cost);
+    }
+
+    /// Cost of the tile at `pos`, anywhere off the map is plain ground
+    pub fn cost_at(&self, pos: Vector2) -> f32 {
+        self.grid.cell(pos).map(|tile| self.grid.cost(tile)).unwrap_or(1.)
+    }
+
+    /// Speed multiplier for a unit standing at `pos`
+    pub fn speed_factor(&self, pos: Vector2) -> f32 {
+        let cost = self.cost_at(pos);
+        if cost >= IMPASSABLE || cost <= 0. {
+            1.
+        } else {
+            1. / cost
+        }
+    }
+
+    /// Navigation grid with one cell per tile
+    pub fn nav_grid(&self) -> NavGrid {
+        self.grid.clone()
+    }
+
+    /// Fog of war with one cell per tile
+    pub fn fog_of_war(&self) -> FogOfWar {
+        FogOfWar::new(self.grid.origin(), self.tile_size(), self.grid.width(), self.grid.height())
+    }
+}
+
+unsafe fn meta(tilemap: &TileMap, name: &str) -> Option<Variant> {
+    if tilemap.has_meta(GodotString::from_str(name)) {
+        Some(tilemap.get_meta(GodotString::from_str(name)))
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use crate::assert_gd;
+    use super::*;
+
+    // Terrain cost should slow units down and block the navigation grid
+    pub fn test_terrain() -> bool {
+        let mut terrain = Terrain::new(Vector2::zero(), 10., 4, 4);
+        terrain.set_cost((1, 0), 2.);
+        terrain.set_cost((2, 0), IMPASSABLE);
+
+        assert_gd!(terrain.speed_factor(Vector2::new(5., 5.)) == 1.);
+        assert_gd!(terrain.speed_factor(Vector2::new(15., 5.)) == 0.5);
+        assert_gd!(terrain.speed_factor(Vector2::new(-15., 5.)) == 1.);
+
+        let grid = terrain.nav_grid();
+        assert_gd!(grid.cost((1, 0)) == 2.);
+        assert_gd!(!grid.passable((2, 0)))
+    }
+}
@@ -1,12 +1,24 @@
 use gdextras::movement::Move2D;
-use gdnative::{KinematicBody2D, Rect2, Vector2};
+use gdnative::{Color, KinematicBody2D, Rect2, Sprite, Vector2};
 use legion::prelude::*;
 
-use crate::gameworld::{Selected, WorldNode};
-use crate::input::{MouseButton, MousePos};
-use crate::spawner::{create_player_sprite, create_unit};
+use crate::gameworld::{Delta, Selected, WorldNode};
+use crate::input::{Actions, MouseButton, MousePos};
+use crate::spawner::{create_sprite, create_unit};
 use crate::Size2;
-use crate::combat::Hitpoints;
+use crate::combat::{AttackMoving, Target};
+use crate::buildings::Building;
+use crate::economy::{Harvest, ResourceNode};
+use crate::effects::Stunned;
+use crate::healing::Repair;
+use crate::orders::{CommandMode, Holding, Order, Orders};
+use crate::formation::{assign_slots, Formation};
+use crate::archetype::Archetypes;
+use crate::flowfield::FlowFields;
+use crate::navigation::NavGrid;
+use crate::movement::{ModifierSource, Movement};
+use crate::terrain::Terrain;
+use crate::steering::{arrival_radius, steer, Obstacle, Steering, ARRIVAL_RADIUS};
 
 pub struct Unit(pub KinematicBody2D);
 
@@ -21,6 +33,30 @@ unsafe impl Sync for Unit {}
 
 pub struct UnitPos(pub Vector2);
 
+/// Name of the archetype a unit was spawned from
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnitType(pub String);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Faction(pub u32);
+
+impl Faction {
+    pub const PLAYER: Faction = Faction(0);
+
+    pub fn hostile_to(&self, other: &Faction) -> bool {
+        self != other
+    }
+}
+
+/// Every entity in `positions` within `radius` of `center`, with its distance
+pub fn within_radius(positions: &[(Entity, Vector2)], center: Vector2, radius: f32) -> Vec<(Entity, f32)> {
+    positions
+        .iter()
+        .map(|(ent, pos)| (*ent, (*pos - center).length()))
+        .filter(|(_, distance)| *distance <= radius)
+        .collect()
+}
+
 pub struct UnitRect(pub Rect2);
 
 impl UnitRect {
@@ -39,33 +75,79 @@ impl UnitRect {
     }
 }
 
-pub struct Destination(pub Vector2);
+/// Where a unit is headed. When moving as part of a group `pos` is the
+/// unit's own slot in the formation, `goal` is the point the group was sent
+/// to, and `speed` is the speed of the slowest unit in the group.
+#[derive(Debug, Clone, Copy)]
+pub struct Destination {
+    pub pos: Vector2,
+    pub goal: Vector2,
+    pub speed: Option<f32>,
+    pub radius: f32,
+}
 
-pub fn spawn_unit() -> Box<dyn Runnable> {
-    SystemBuilder::new("spaw unit")
-        .write_resource::<WorldNode>()
-        .write_resource::<MouseButton>()
-        .read_resource::<MousePos>()
-        .build_thread_local(|cmd, _world, (world_node, mouse_btn, mouse_pos), _query| {
-            if !mouse_btn.button_pressed(2) {
-                return;
-            }
+impl Destination {
+    pub fn new(pos: Vector2) -> Self {
+        Self { pos, goal: pos, speed: None, radius: ARRIVAL_RADIUS }
+    }
 
-            mouse_btn.consume();
+    pub fn in_group(pos: Vector2, goal: Vector2, speed: f32, group_size: usize) -> Self {
+        Self { pos, goal, speed: Some(speed), radius: arrival_radius(group_size) }
+    }
+}
 
-            let mut unit = create_unit();
-            let sprite = create_player_sprite();
+pub struct UnitSprite {
+    pub sprite: Sprite,
+    pub rotate: bool,
+}
 
-            unsafe {
-                unit.0.add_child(Some(sprite.to_node()), false);
-                world_node.add_child(unit.0.to_node());
-                unit.0.set_global_position(mouse_pos.global());
+unsafe impl Send for UnitSprite {}
+unsafe impl Sync for UnitSprite {}
+
+impl UnitSprite {
+    /// Turn the sprite to face `facing` (in radians)
+    pub fn face(&mut self, facing: f32) {
+        unsafe {
+            if self.rotate {
+                self.sprite.set_rotation(facing as f64);
+            } else {
+                self.sprite.set_flip_h(facing.cos() < 0.);
+            }
+        }
+    }
+}
 
-                let unit_pos = UnitPos(unit.0.get_global_position());
-                let unit_rect = UnitRect::new(unit_pos.0, 7., 29.);
-                let hitpoints = Hitpoints(10);
-                cmd.insert((), vec![(unit, unit_pos, unit_rect, hitpoints)]);
-            };
+/// Create the Godot nodes for units that don't have any yet
+pub fn create_unit_nodes() -> Box<dyn Runnable> {
+    SystemBuilder::new("create unit nodes")
+        .write_resource::<WorldNode>()
+        .read_resource::<Archetypes>()
+        .with_query(<(Read<UnitPos>, Read<UnitType>, Read<Faction>)>::query()
+            .filter(!component::<Unit>()))
+        .build_thread_local(|cmd, world, (world_node, archetypes), query| {
+            for (entity, (pos, unit_type, faction)) in query.iter_entities(world) {
+                let archetype = match archetypes.get(&unit_type.0) {
+                    Some(a) => a,
+                    None => continue,
+                };
+
+                let mut unit = create_unit();
+                let mut sprite = create_sprite(&archetype.sprite);
+
+                unsafe {
+                    if *faction != Faction::PLAYER {
+                        sprite.set_modulate(Color::rgb(1., 0.5, 0.5));
+                    }
+
+                    unit.0.add_child(Some(sprite.to_node()), false);
+                    world_node.add_child(unit.0.to_node());
+                    unit.0.set_global_position(pos.0);
+                }
+
+                let sprite = UnitSprite { sprite, rotate: archetype.rotate_sprite };
+                cmd.add_component(entity, unit);
+                cmd.add_component(entity, sprite);
+            }
         })
 }
 
@@ -73,25 +155,69 @@ pub fn select_unit() -> Box<dyn Schedulable> {
     SystemBuilder::new("select unit")
         .write_resource::<MouseButton>()
         .read_resource::<MousePos>()
-        .with_query(<Read<UnitRect>>::query())
+        .read_component::<Faction>()
+        .with_query(<Read<UnitRect>>::query().filter(!component::<ResourceNode>()))
         .with_query(<Read<UnitRect>>::query().filter(tag::<Selected>()))
-        .build(|cmd, world, (mouse_btn, mouse_pos), (deselected_query, selected_query)| {
-            // Only one selected unit at a time
-            if selected_query.iter(world).count() > 0 {
+        .build(|cmd, world, (mouse_btn, mouse_pos), (units_query, selected_query)| {
+            if !mouse_btn.button_pressed(1) {
                 return;
             }
 
-            if !mouse_btn.button_pressed(1) {
-                return;
+            // Only the player's own units can be selected
+            let clicked = units_query
+                .iter_entities(world)
+                .filter(|(ent, _)| {
+                    world
+                        .get_component::<Faction>(*ent)
+                        .map(|faction| *faction == Faction::PLAYER)
+                        .unwrap_or(true)
+                })
+                .find(|(_, rect)| rect.0.contains(mouse_pos.global().to_point()))
+                .map(|(ent, _)| ent);
+
+            let selected = selected_query
+                .iter_entities(world)
+                .map(|(ent, _)| ent)
+                .collect::<Vec<_>>();
+
+            // Shift click adds to the selection, anything else replaces it.
+            // Clicking on empty ground clears the selection.
+            let add_to_selection = mouse_btn.shift() && clicked.is_some();
+            if !add_to_selection {
+                for entity in selected.iter().filter(|ent| Some(**ent) != clicked) {
+                    cmd.remove_tag::<Selected>(*entity);
+                }
             }
 
-            for (entity, rect) in deselected_query.iter_entities(world) {
-                if rect.0.contains(mouse_pos.global().to_point()) {
+            if let Some(entity) = clicked {
+                if !selected.contains(&entity) {
                     cmd.add_tag(entity, Selected);
-                    mouse_btn.consume();
-                    return;
                 }
             }
+
+            mouse_btn.consume();
+        })
+}
+
+pub fn deselect_units() -> Box<dyn Schedulable> {
+    SystemBuilder::new("deselect units")
+        .write_resource::<Actions>()
+        .with_query(<Read<UnitRect>>::query().filter(tag::<Selected>()))
+        .build(|cmd, world, actions, query| {
+            if !actions.pressed("ui_cancel") {
+                return;
+            }
+
+            let mut deselected = false;
+            for (entity, _) in query.iter_entities(world) {
+                cmd.remove_tag::<Selected>(entity);
+                deselected = true;
+            }
+
+            // Nothing to deselect, leave the action for someone else
+            if deselected {
+                actions.consume("ui_cancel");
+            }
         })
 }
 
@@ -99,10 +225,20 @@ pub fn set_unit_destination() -> Box<dyn Schedulable> {
     SystemBuilder::new("give units a destination")
         .write_resource::<MouseButton>()
         .read_resource::<MousePos>()
+        .read_resource::<Formation>()
+        .write_resource::<CommandMode>()
+        .write_component::<Orders>()
+        .read_component::<Destination>()
+        .read_component::<Target>()
+        .read_component::<AttackMoving>()
+        .read_component::<Holding>()
+        .read_component::<Harvest>()
+        .read_component::<Repair>()
+        .read_component::<Movement>()
         .with_query(<Read<UnitRect>>::query())
-        .with_query(<Read<UnitRect>>::query().filter(tag::<Selected>()))
-        .build(|cmd, world, (mouse_btn, mouse_pos), (all_query, query)| {
-            if !mouse_btn.button_pressed(1) {
+        .with_query(<Read<UnitRect>>::query().filter(tag::<Selected>() & !component::<Building>()))
+        .build(|cmd, world, (mouse_btn, mouse_pos, formation, mode), (all_query, query)| {
+            if !mouse_btn.button_pressed(2) {
                 return;
             }
 
@@ -112,38 +248,205 @@ pub fn set_unit_destination() -> Box<dyn Schedulable> {
                 }
             }
 
-            for (entity, _) in query.iter_entities(world) {
-                cmd.add_component(entity, Destination(mouse_pos.global()));
-                cmd.remove_tag::<Selected>(entity);
+            let queue = mouse_btn.shift();
+            let selected = query
+                .iter_entities(world)
+                .map(|(ent, rect)| (ent, rect.0.center().to_vector()))
+                .collect::<Vec<_>>();
+
+            if selected.is_empty() {
+                return;
+            }
+
+            // The group moves at the speed of its slowest unit
+            let group_speed = selected
+                .iter()
+                .filter_map(|(ent, _)| world.get_component::<Movement>(*ent).map(|m| m.speed()))
+                .fold(std::f32::MAX, f32::min);
+
+            let slots = assign_slots(**formation, &selected, mouse_pos.global());
+
+            for (entity, slot) in slots {
+                // Replacing orders cancels whatever the unit is busy with
+                if !queue {
+                    clear_current_order(cmd, world, entity);
+                }
+
+                let dest = if selected.len() > 1 {
+                    Destination::in_group(slot, mouse_pos.global(), group_speed, selected.len())
+                } else {
+                    Destination::new(slot)
+                };
+
+                let order = match **mode {
+                    CommandMode::Move => Order::Move(dest),
+                    CommandMode::AttackMove => Order::AttackMove(dest),
+                    CommandMode::Patrol => {
+                        let start = selected
+                            .iter()
+                            .find(|(ent, _)| *ent == entity)
+                            .map(|(_, pos)| *pos)
+                            .unwrap_or(slot);
+                        Order::Patrol(vec![start, slot])
+                    }
+                };
+
+                match world.get_component_mut::<Orders>(entity) {
+                    Some(mut orders) if queue => orders.queue(order),
+                    Some(mut orders) => orders.replace(order),
+                    None => {
+                        let mut orders = Orders::new();
+                        orders.push(order);
+                        cmd.add_component(entity, orders);
+                    }
+                }
+            }
+
+            // Shift keeps the mode so more points can be added
+            if !queue {
+                **mode = CommandMode::Move;
             }
 
             mouse_btn.consume();
         })
 }
 
+pub fn clear_current_order(cmd: &mut CommandBuffer, world: &SubWorld, entity: Entity) {
+    if world.get_component::<Destination>(entity).is_some() {
+        cmd.remove_component::<Destination>(entity);
+    }
+
+    if world.get_component::<Target>(entity).is_some() {
+        cmd.remove_component::<Target>(entity);
+    }
+
+    if world.get_component::<AttackMoving>(entity).is_some() {
+        cmd.remove_component::<AttackMoving>(entity);
+    }
+
+    if world.get_component::<Holding>(entity).is_some() {
+        cmd.remove_component::<Holding>(entity);
+    }
+
+    if world.get_component::<Harvest>(entity).is_some() {
+        cmd.remove_component::<Harvest>(entity);
+    }
+
+    if world.get_component::<Repair>(entity).is_some() {
+        cmd.remove_component::<Repair>(entity);
+    }
+}
+
+pub fn steer_units() -> Box<dyn Schedulable> {
+    SystemBuilder::new("steer units")
+        .read_resource::<Delta>()
+        .read_resource::<NavGrid>()
+        .read_resource::<Terrain>()
+        .write_resource::<FlowFields>()
+        .with_query(<Read<UnitPos>>::query().filter(component::<Movement>()))
+        .with_query(<(Read<UnitPos>, Read<UnitRect>)>::query().filter(!component::<Destination>()))
+        .with_query(<(
+            Read<UnitPos>,
+            Read<Destination>,
+            Write<Movement>,
+            Read<Steering>,
+        )>::query().filter(!component::<Holding>()))
+        .with_query(<Write<Movement>>::query()
+            .filter(!component::<Destination>() | component::<Holding>()))
+        .build(|cmd, world, (delta, nav_grid, terrain, flow_fields), (units_query, idle_query, query, stopping_query)| {
+            let positions = units_query
+                .iter_entities(world)
+                .map(|(ent, pos)| (ent, pos.0))
+                .collect::<Vec<_>>();
+
+            // Units that are standing still are steered around
+            let obstacles = idle_query
+                .iter(world)
+                .map(|(pos, rect)| Obstacle {
+                    pos: pos.0,
+                    radius: rect.0.size.width.max(rect.0.size.height) / 2.,
+                })
+                .collect::<Vec<_>>();
+
+            for (entity, (unit_pos, dest, mut movement, steering)) in query.iter_entities_mut(world) {
+                if (dest.pos - unit_pos.0).length() < dest.radius {
+                    cmd.remove_component::<Destination>(entity);
+                    continue;
+                }
+
+                movement.set_modifier(ModifierSource::Terrain, terrain.speed_factor(unit_pos.0));
+                let speed = dest.speed.map(|s| s.min(movement.speed())).unwrap_or(movement.speed());
+                let neighbours = positions
+                    .iter()
+                    .filter(|(ent, _)| *ent != entity)
+                    .map(|(_, pos)| *pos)
+                    .collect::<Vec<_>>();
+
+                // Follow the group's flow field until close enough to head
+                // straight for our own slot
+                let slot_distance = (dest.goal - dest.pos).length() + nav_grid.cell_size() * 2.;
+                let heading = if (dest.goal - unit_pos.0).length() > slot_distance {
+                    flow_fields
+                        .direction(&nav_grid, dest.goal, unit_pos.0)
+                        .map(|dir| unit_pos.0 + dir * steering.slowing_radius * 2.)
+                } else {
+                    None
+                };
+
+                let desired = steer(
+                    &steering,
+                    unit_pos.0,
+                    heading.unwrap_or(dest.pos),
+                    speed,
+                    &neighbours,
+                    &obstacles,
+                );
+
+                movement.update(desired, delta.0);
+            }
+
+            // Units without a destination come to a stop
+            for mut movement in stopping_query.iter_mut(world) {
+                if movement.velocity != Vector2::zero() {
+                    movement.update(Vector2::zero(), delta.0);
+                }
+            }
+        })
+}
+
 pub fn move_units() -> Box<dyn Runnable> {
     SystemBuilder::new("move units")
         .with_query(<(
             Write<Unit>,
             Write<UnitPos>,
             Write<UnitRect>,
-            Read<Destination>,
-        )>::query())
-        .build_thread_local(|cmd, world, _, query| {
-            for (entity, (mut unit, mut unit_pos, mut unit_rect, dest)) in
-                query.iter_entities_mut(world)
-            {
-                let direction = (dest.0 - unit_pos.0).normalize();
-                let speed = 100f32;
-                let velocity = direction * speed;
-
-                unit.0.move_and_slide_default(velocity, Vector2::zero());
+            Read<Movement>,
+            Write<UnitSprite>,
+        )>::query().filter(!component::<Stunned>()))
+        .build_thread_local(|_, world, _, query| {
+            for (mut unit, mut unit_pos, mut unit_rect, movement, mut sprite) in query.iter_mut(world) {
+                if movement.velocity == Vector2::zero() {
+                    continue;
+                }
+
+                unit.0.move_and_slide_default(movement.velocity, Vector2::zero());
                 unsafe { unit_pos.0 = unit.0.get_global_position() };
                 unit_rect.update(unit_pos.0);
+                sprite.face(movement.facing);
+            }
+        })
+}
 
-                if (dest.0 - unit_pos.0).length() < 4. {
-                    cmd.remove_component::<Destination>(entity);
-                }
+/// Move units that have no physics body, used when simulating without Godot
+pub fn integrate_positions() -> Box<dyn Schedulable> {
+    SystemBuilder::new("integrate positions")
+        .read_resource::<Delta>()
+        .with_query(<(Write<UnitPos>, Write<UnitRect>, Read<Movement>)>::query()
+            .filter(!component::<Unit>() & !component::<Stunned>()))
+        .build(|_, world, delta, query| {
+            for (mut unit_pos, mut unit_rect, movement) in query.iter_mut(world) {
+                unit_pos.0 += movement.velocity * delta.0;
+                unit_rect.update(unit_pos.0);
             }
         })
 }
@@ -158,7 +461,7 @@ pub mod tests {
         let mut world = Universe::new().create_world();
         let mut resources = Resources::default();
         resources.insert(MousePos::zero());
-        resources.insert(MouseButton::Mouse { pressed: true, button_index: 1 });
+        resources.insert(MouseButton::Mouse { pressed: true, button_index: 1, shift: false });
 
         let entity = world.insert((), vec![(
                 UnitRect(Rect2::new(Vector2::zero().to_point(), Size2::new(10., 10.,))),
@@ -174,4 +477,76 @@ pub mod tests {
 
         assert_gd!(world.get_tag::<Selected>(entity).is_some())
     }
+
+    // Unit should stay selected across several commands, and only be
+    // deselected by clicking empty ground
+    pub fn test_select_units_multiple_commands() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(MousePos::zero());
+        resources.insert(MouseButton::Mouse { pressed: true, button_index: 1, shift: false });
+        resources.insert(Formation::Line);
+        resources.insert(CommandMode::Move);
+
+        let entity = world.insert((), vec![(
+                UnitRect(Rect2::new(Vector2::zero().to_point(), Size2::new(10., 10.,))),
+                Orders::new(),
+        ),])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(select_unit())
+            .add_system(set_unit_destination())
+            .flush()
+            .build();
+
+        let mut click = |world: &mut World, pos: Vector2, button_index: i64, shift: bool| {
+            resources.get_mut::<MousePos>().map(|mut mouse| mouse.set_global(pos));
+            resources.insert(MouseButton::Mouse { pressed: true, button_index, shift });
+            sched.execute(world, &mut resources);
+        };
+
+        click(&mut world, Vector2::zero(), 1, false);
+        assert_gd!(world.get_tag::<Selected>(entity).is_some());
+
+        // Move order
+        click(&mut world, Vector2::new(100., 100.), 2, false);
+        assert_gd!(world.get_tag::<Selected>(entity).is_some());
+        assert_gd!(world.get_component::<Orders>(entity).unwrap().0.len() == 1);
+
+        // Queue a second order on the same selection
+        click(&mut world, Vector2::new(200., 100.), 2, true);
+        assert_gd!(world.get_tag::<Selected>(entity).is_some());
+        assert_gd!(world.get_component::<Orders>(entity).unwrap().0.len() == 2);
+
+        // Replace the orders
+        click(&mut world, Vector2::new(50., 50.), 2, false);
+        assert_gd!(world.get_tag::<Selected>(entity).is_some());
+        assert_gd!(world.get_component::<Orders>(entity).unwrap().0.len() == 1);
+
+        // Click on empty ground
+        click(&mut world, Vector2::new(300., 300.), 1, false);
+        assert_gd!(world.get_tag::<Selected>(entity).is_none())
+    }
+
+    // Escape should clear the selection
+    pub fn test_deselect_units() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        let mut actions = Actions::empty();
+        actions.press("ui_cancel");
+        resources.insert(actions);
+
+        let entity = world.insert((Selected,), vec![(
+                UnitRect(Rect2::new(Vector2::zero().to_point(), Size2::new(10., 10.,))),
+        ),])[0];
+
+        let mut sched = Schedule::builder()
+            .add_system(deselect_units())
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+
+        assert_gd!(world.get_tag::<Selected>(entity).is_none());
+        assert_gd!(!resources.get::<Actions>().unwrap().pressed("ui_cancel"))
+    }
 }
@@ -0,0 +1,335 @@
+//! Experience, ranks and who killed whom.
+use std::collections::HashMap;
+
+use gdnative::{Dictionary, Variant};
+use legion::prelude::*;
+use serde::Deserialize;
+
+use crate::buildings::Building;
+use crate::combat::MaxHitpoints;
+use crate::gameworld::Notices;
+use crate::tech::{apply_modifiers, Stat, StatModifier};
+use crate::units::{Faction, UnitType};
+
+/// Part of the bounty that goes to whoever landed the killing blow, the
+/// rest is split by damage dealt
+const KILLER_SHARE: f32 = 0.5;
+
+// -----------------------------------------------------------------------------
+//     - Data -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rank {
+    pub name: String,
+    /// Experience needed to reach it
+    pub xp: u32,
+    #[serde(default)]
+    pub modifiers: Vec<StatModifier>,
+}
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// Damage dealt to a unit or building, by attacker
+#[derive(Debug, Clone, Default)]
+pub struct Attackers(pub Vec<(Entity, u32)>);
+
+impl Attackers {
+    pub fn record(&mut self, attacker: Entity, damage: u32) {
+        match self.0.iter_mut().find(|(ent, _)| *ent == attacker) {
+            Some((_, total)) => *total += damage,
+            None => self.0.push((attacker, damage)),
+        }
+    }
+}
+
+/// Experience earned and the rank it bought. Rank 0 is a recruit, rank `n`
+/// is the `n`th rank in `Ranks`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Veterancy {
+    pub xp: u32,
+    pub rank: usize,
+}
+
+/// Something died, waiting for `award_experience`
+#[derive(Debug, Clone)]
+pub struct Death {
+    pub faction: Option<Faction>,
+    pub building: bool,
+    /// Experience shared among the contributors
+    pub bounty: u32,
+    pub killer: Option<Entity>,
+    pub killer_faction: Option<Faction>,
+    pub contributors: Vec<(Entity, u32)>,
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Every rank, lowest first
+#[derive(Debug, Default, Deserialize)]
+pub struct Ranks(Vec<Rank>);
+
+impl Ranks {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn get(&self, rank: usize) -> Option<&Rank> {
+        rank.checked_sub(1).and_then(|i| self.0.get(i))
+    }
+
+    /// Highest rank `xp` is enough for
+    pub fn rank_for(&self, xp: u32) -> usize {
+        self.0.iter().take_while(|rank| xp >= rank.xp).count()
+    }
+
+    pub fn apply(&self, rank: usize, archetype: &str, stat: Stat, base: f32) -> f32 {
+        match self.get(rank) {
+            Some(rank) => apply_modifiers(&rank.modifiers, archetype, stat, base),
+            None => base,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FactionStats {
+    pub units_killed: u32,
+    pub buildings_destroyed: u32,
+    pub units_lost: u32,
+    pub buildings_lost: u32,
+}
+
+impl FactionStats {
+    pub fn to_dictionary(&self) -> Dictionary {
+        let mut dict = Dictionary::new();
+        dict.set(&Variant::from_str("units_killed"), &Variant::from_u64(self.units_killed as u64));
+        dict.set(&Variant::from_str("buildings_destroyed"), &Variant::from_u64(self.buildings_destroyed as u64));
+        dict.set(&Variant::from_str("units_lost"), &Variant::from_u64(self.units_lost as u64));
+        dict.set(&Variant::from_str("buildings_lost"), &Variant::from_u64(self.buildings_lost as u64));
+        dict
+    }
+}
+
+/// Kills and losses per faction, for the end of match screen
+#[derive(Debug, Default)]
+pub struct Statistics(HashMap<u32, FactionStats>);
+
+impl Statistics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, faction: Faction) -> FactionStats {
+        self.0.get(&faction.0).copied().unwrap_or_default()
+    }
+
+    pub fn record(&mut self, death: &Death) {
+        if let Some(faction) = death.faction {
+            let lost = self.0.entry(faction.0).or_default();
+            if death.building {
+                lost.buildings_lost += 1;
+            } else {
+                lost.units_lost += 1;
+            }
+        }
+
+        // Killing your own doesn't count
+        let killer = match (death.killer_faction, death.faction) {
+            (Some(killer), Some(victim)) if killer.hostile_to(&victim) => killer,
+            (Some(killer), None) => killer,
+            _ => return,
+        };
+
+        let killed = self.0.entry(killer.0).or_default();
+        if death.building {
+            killed.buildings_destroyed += 1;
+        } else {
+            killed.units_killed += 1;
+        }
+    }
+}
+
+/// Delete `entity` and leave a `Death` behind for `award_experience`.
+/// Requires read access to `Attackers`, `Faction`, `MaxHitpoints` and
+/// `Building`.
+pub fn record_death(cmd: &mut CommandBuffer, world: &SubWorld, entity: Entity, killer: Option<Entity>) {
+    let death = Death {
+        faction: world.get_component::<Faction>(entity).map(|f| *f),
+        building: world.get_component::<Building>(entity).is_some(),
+        bounty: world.get_component::<MaxHitpoints>(entity).map(|hp| hp.0).unwrap_or(0),
+        killer,
+        killer_faction: killer.and_then(|killer| world.get_component::<Faction>(killer).map(|f| *f)),
+        contributors: world
+            .get_component::<Attackers>(entity)
+            .map(|attackers| attackers.0.clone())
+            .unwrap_or_default(),
+    };
+
+    cmd.delete(entity);
+    cmd.insert((), vec![(death,)]);
+}
+
+/// Experience for everyone who helped with a kill
+pub fn split_experience(death: &Death) -> Vec<(Entity, u32)> {
+    let bounty = death.bounty as f32;
+    let total_damage = death.contributors.iter().map(|(_, damage)| *damage).sum::<u32>();
+
+    let (killer_share, shared) = match death.killer {
+        Some(_) => (bounty * KILLER_SHARE, bounty * (1. - KILLER_SHARE)),
+        None => (0., bounty),
+    };
+
+    let mut split = death
+        .contributors
+        .iter()
+        .filter(|_| total_damage > 0)
+        .map(|(ent, damage)| (*ent, shared * *damage as f32 / total_damage as f32))
+        .collect::<Vec<_>>();
+
+    if let Some(killer) = death.killer {
+        match split.iter_mut().find(|(ent, _)| *ent == killer) {
+            Some((_, xp)) => *xp += killer_share,
+            None => split.push((killer, killer_share)),
+        }
+    }
+
+    split.into_iter().map(|(ent, xp)| (ent, xp.round() as u32)).collect()
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+/// Share out experience for everything that died, promote whoever earned
+/// it and keep count of kills and losses
+pub fn award_experience() -> Box<dyn Schedulable> {
+    SystemBuilder::new("award experience")
+        .read_resource::<Ranks>()
+        .write_resource::<Statistics>()
+        .write_resource::<Notices>()
+        .write_component::<Veterancy>()
+        .read_component::<Faction>()
+        .read_component::<UnitType>()
+        .with_query(<Read<Death>>::query())
+        .build(|cmd, world, (ranks, statistics, notices), deaths| {
+            for (entity, death) in deaths.iter_entities(world) {
+                cmd.delete(entity);
+                statistics.record(&death);
+
+                for (contributor, xp) in split_experience(&death) {
+                    // Friendly fire earns nothing
+                    let faction = world.get_component::<Faction>(contributor).map(|f| *f);
+                    if faction.is_some() && faction == death.faction {
+                        continue;
+                    }
+
+                    let mut veterancy = match world.get_component_mut::<Veterancy>(contributor) {
+                        Some(veterancy) => veterancy,
+                        None => continue,
+                    };
+
+                    veterancy.xp += xp;
+                    let rank = ranks.rank_for(veterancy.xp);
+                    if rank <= veterancy.rank {
+                        continue;
+                    }
+                    veterancy.rank = rank;
+
+                    let unit_type = world.get_component::<UnitType>(contributor).map(|t| t.0.clone());
+                    if let (Some(rank), Some(unit_type), Some(Faction::PLAYER)) = (ranks.get(rank), unit_type, faction) {
+                        notices.push(format!("A {} was promoted to {}", unit_type, rank.name));
+                    }
+                }
+            }
+        })
+}
+
+#[cfg(feature = "godot_test")]
+pub mod tests {
+    use gdnative::Vector2;
+
+    use crate::assert_gd;
+    use crate::archetype::Archetypes;
+    use crate::combat::{attack_targets, Hitpoints, Target, Weapon, Weapons};
+    use crate::data;
+    use crate::effects::StatusEffects;
+    use crate::tech::{apply_upgrades, Upgrades};
+    use crate::spawner::insert_unit;
+    use crate::units::UnitPos;
+    use super::*;
+
+    // The killer gets half, everyone splits the rest by damage dealt
+    pub fn test_split_experience() -> bool {
+        let mut world = Universe::new().create_world();
+        let ents = world
+            .insert((), vec![(Faction(0), Veterancy::default()), (Faction(0), Veterancy::default())])
+            .to_vec();
+
+        let death = Death {
+            faction: Some(Faction(1)),
+            building: false,
+            bounty: 20,
+            killer: Some(ents[0]),
+            killer_faction: Some(Faction(0)),
+            contributors: vec![(ents[0], 2), (ents[1], 8)],
+        };
+
+        let split = split_experience(&death);
+        assert_gd!(split.contains(&(ents[0], 12)));
+        assert_gd!(split.contains(&(ents[1], 8)));
+
+        let mut statistics = Statistics::new();
+        statistics.record(&death);
+        statistics.record(&Death { building: true, ..death.clone() });
+        assert_gd!(statistics.get(Faction(0)).units_killed == 1);
+        assert_gd!(statistics.get(Faction(0)).buildings_destroyed == 1);
+        assert_gd!(statistics.get(Faction(1)).units_lost == 1);
+        assert_gd!(statistics.get(Faction(1)).buildings_lost == 1)
+    }
+
+    // Kills earn experience, and ranks make units hit harder
+    pub fn test_veterancy() -> bool {
+        let mut world = Universe::new().create_world();
+        let mut resources = Resources::default();
+        resources.insert(Archetypes::new());
+        resources.insert(Upgrades::new());
+        resources.insert(Statistics::new());
+        resources.insert(Notices::new());
+        resources.insert(StatusEffects::new());
+        resources.insert(data::parse::<Ranks>(r#"[
+            (name: "veteran", xp: 10, modifiers: [(stat: Damage, add: 1.0)]),
+            (name: "elite", xp: 50),
+        ]"#).unwrap());
+
+        let victim = world.insert((), vec![
+            (UnitPos(Vector2::new(50., 0.)), Faction(1), Hitpoints(1), MaxHitpoints(10), Attackers::default()),
+        ])[0];
+
+        let archetypes = Archetypes::new();
+        let mut cmd = CommandBuffer::new(&world);
+        let soldier = insert_unit(&mut cmd, archetypes.get("soldier").unwrap(), Vector2::zero(), Faction::PLAYER);
+        cmd.add_component(soldier, Target(victim));
+        cmd.write(&mut world);
+
+        let mut sched = Schedule::builder()
+            .add_system(attack_targets())
+            .flush()
+            .add_system(award_experience())
+            .add_system(apply_upgrades())
+            .flush()
+            .build();
+
+        sched.execute(&mut world, &mut resources);
+
+        assert_gd!(world.get_component::<Hitpoints>(victim).is_none());
+        let veterancy = *world.get_component::<Veterancy>(soldier).unwrap();
+        assert_gd!(veterancy.xp == 10);
+        assert_gd!(veterancy.rank == 1);
+        assert_gd!(resources.get::<Statistics>().unwrap().get(Faction::PLAYER).units_killed == 1);
+        assert_gd!(resources.get::<Statistics>().unwrap().get(Faction(1)).units_lost == 1);
+
+        let rifle = Weapon::rifle().damage;
+        let weapons = world.get_component::<Weapons>(soldier).unwrap();
+        assert_gd!(weapons.0[0].damage == rifle + 1)
+    }
+}